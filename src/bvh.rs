@@ -0,0 +1,600 @@
+use crate::scene::{Intersection, Ray, Shape, Vector};
+use crate::traits::AlmostEqual;
+use std::f32;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Aabb {
+    pub min: Vector,
+    pub max: Vector,
+}
+
+impl AlmostEqual for Aabb {
+    fn almost_equal(&self, other: &Aabb) -> bool {
+        self.min.almost_equal(&other.min) && self.max.almost_equal(&other.max)
+    }
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vector {
+                x: f32::INFINITY,
+                y: f32::INFINITY,
+                z: f32::INFINITY,
+            },
+            max: Vector {
+                x: f32::NEG_INFINITY,
+                y: f32::NEG_INFINITY,
+                z: f32::NEG_INFINITY,
+            },
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Vector {
+                x: self.min.x.min(other.min.x),
+                y: self.min.y.min(other.min.y),
+                z: self.min.z.min(other.min.z),
+            },
+            max: Vector {
+                x: self.max.x.max(other.max.x),
+                y: self.max.y.max(other.max.y),
+                z: self.max.z.max(other.max.z),
+            },
+        }
+    }
+
+    pub fn including(&self, point: &Vector) -> Aabb {
+        self.union(&Aabb {
+            min: *point,
+            max: *point,
+        })
+    }
+
+    pub fn center(&self) -> Vector {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let size = self.max - self.min;
+        if size.x < 0.0 || size.y < 0.0 || size.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    // Returns the distance along the ray at which it enters the box (t_min if the ray starts
+    // inside) or None if the box is missed or outside of the ray's [t_min, t_max] interval.
+    // inverse_dir is the componentwise inverse of the ray direction, computed once per ray by the
+    // caller.
+    pub fn intersect_ray(&self, ray: &Ray, inverse_dir: &Vector) -> Option<f32> {
+        // The slab method: intersect the ray with the pair of planes bounding the box along every
+        // axis and narrow the [tmin, tmax] range down with each pair.
+        let mut tmin = ray.t_min;
+        let mut tmax = ray.t_max;
+        for &(pos, inverse, min, max) in &[
+            (ray.pos.x, inverse_dir.x, self.min.x, self.max.x),
+            (ray.pos.y, inverse_dir.y, self.min.y, self.max.y),
+            (ray.pos.z, inverse_dir.z, self.min.z, self.max.z),
+        ] {
+            if inverse.is_infinite() {
+                // The ray is parallel to the slab, it either runs between its planes or misses
+                // the box altogether.
+                if pos < min || pos > max {
+                    return None;
+                }
+                continue;
+            }
+            let t1 = (min - pos) * inverse;
+            let t2 = (max - pos) * inverse;
+            tmin = tmin.max(t1.min(t2));
+            tmax = tmax.min(t1.max(t2));
+        }
+        if tmin <= tmax {
+            Some(tmin)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Node {
+    // The primitives are the range [first, first + count) of Bvh::primitives.
+    Leaf {
+        bounds: Aabb,
+        first: usize,
+        count: usize,
+    },
+    // The left child is always stored directly after its parent.
+    Interior {
+        bounds: Aabb,
+        right: usize,
+    },
+}
+
+impl Node {
+    fn bounds(&self) -> &Aabb {
+        match self {
+            Node::Leaf { bounds, .. } | Node::Interior { bounds, .. } => bounds,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BvhStats {
+    pub primitives: usize,
+    // Shapes without a bounding box, they're not part of the hierarchy.
+    pub unbounded: usize,
+    pub nodes: usize,
+    pub leaves: usize,
+    pub depth: usize,
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "BVH: {} primitives, {} nodes ({} leaves), depth {}, {} unbounded shape(s)",
+            self.primitives, self.nodes, self.leaves, self.depth, self.unbounded
+        )
+    }
+}
+
+// A bounding volume hierarchy over a slice of shapes, it only stores indices so the same slice
+// has to be passed to closest_intersection. Unbounded shapes are kept on a separate list and
+// checked for every ray.
+#[derive(Clone, Debug)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    primitives: Vec<usize>,
+    unbounded: Vec<usize>,
+    depth: usize,
+}
+
+// The relative costs of visiting a node and intersecting a primitive used by the surface area
+// heuristic.
+const TRAVERSAL_COST: f32 = 0.125;
+const INTERSECTION_COST: f32 = 1.0;
+const BUCKETS: usize = 16;
+const MAX_LEAF_SIZE: usize = 4;
+
+struct BuildPrimitive {
+    index: usize,
+    bounds: Aabb,
+    center: Vector,
+}
+
+impl Bvh {
+    pub fn build<S: Shape>(shapes: &[S]) -> Bvh {
+        Bvh::from_bounds(shapes.iter().map(|shape| shape.bounding_box()))
+    }
+
+    // Builds a hierarchy over primitives that aren't shapes themselves, like the faces of a mesh.
+    // The indices passed to closest_hit's callback are positions in the bounds sequence.
+    pub fn from_bounds<I: IntoIterator<Item = Option<Aabb>>>(bounds: I) -> Bvh {
+        let mut build_primitives = Vec::new();
+        let mut unbounded = Vec::new();
+        for (index, bounds) in bounds.into_iter().enumerate() {
+            match bounds {
+                Some(bounds) => build_primitives.push(BuildPrimitive {
+                    index,
+                    bounds,
+                    center: bounds.center(),
+                }),
+                None => unbounded.push(index),
+            }
+        }
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            primitives: Vec::with_capacity(build_primitives.len()),
+            unbounded,
+            depth: 0,
+        };
+        if !build_primitives.is_empty() {
+            bvh.build_node(&mut build_primitives, 1);
+        }
+        bvh
+    }
+
+    fn build_node(&mut self, primitives: &mut [BuildPrimitive], depth: usize) {
+        self.depth = self.depth.max(depth);
+        let bounds = primitives
+            .iter()
+            .fold(Aabb::empty(), |bounds, p| bounds.union(&p.bounds));
+        let node_index = self.nodes.len();
+        match self.find_split(primitives, &bounds) {
+            None => {
+                self.nodes.push(Node::Leaf {
+                    bounds,
+                    first: self.primitives.len(),
+                    count: primitives.len(),
+                });
+                self.primitives.extend(primitives.iter().map(|p| p.index));
+            }
+            Some(mid) => {
+                self.nodes.push(Node::Interior { bounds, right: 0 });
+                let (left, right) = primitives.split_at_mut(mid);
+                self.build_node(left, depth + 1);
+                let right_index = self.nodes.len();
+                self.build_node(right, depth + 1);
+                self.nodes[node_index] = Node::Interior {
+                    bounds,
+                    right: right_index,
+                };
+            }
+        }
+    }
+
+    // Partitions the primitives using the surface area heuristic and returns the index of the
+    // first primitive of the right half, or None if the primitives are better off in a leaf.
+    fn find_split(&self, primitives: &mut [BuildPrimitive], bounds: &Aabb) -> Option<usize> {
+        if primitives.len() == 1 {
+            return None;
+        }
+        let centers = primitives
+            .iter()
+            .fold(Aabb::empty(), |centers, p| centers.including(&p.center));
+        let extent = centers.max - centers.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        let coordinate = |v: &Vector| match axis {
+            0 => v.x,
+            1 => v.y,
+            _ => v.z,
+        };
+        let (axis_min, axis_extent) = (coordinate(&centers.min), coordinate(&extent));
+        if axis_extent <= 0.0 {
+            // All the centers are in the same spot, there's no way to separate them.
+            return None;
+        }
+
+        // Instead of evaluating every possible split position the centers are distributed into
+        // equally sized buckets and only the bucket boundaries are considered.
+        let bucket = |p: &BuildPrimitive| {
+            let relative = (coordinate(&p.center) - axis_min) / axis_extent;
+            ((relative * BUCKETS as f32) as usize).min(BUCKETS - 1)
+        };
+        let mut counts = [0usize; BUCKETS];
+        let mut bucket_bounds = [Aabb::empty(); BUCKETS];
+        for p in primitives.iter() {
+            let b = bucket(p);
+            counts[b] += 1;
+            bucket_bounds[b] = bucket_bounds[b].union(&p.bounds);
+        }
+
+        // Sweep from both ends to get the bounds and primitive counts on each side of every
+        // bucket boundary.
+        let mut right_sides = [(Aabb::empty(), 0); BUCKETS];
+        let mut right_side = (Aabb::empty(), 0);
+        for b in (1..BUCKETS).rev() {
+            right_side = (
+                right_side.0.union(&bucket_bounds[b]),
+                right_side.1 + counts[b],
+            );
+            right_sides[b] = right_side;
+        }
+        let mut best_cost = f32::INFINITY;
+        let mut best_split = 0;
+        let mut left_side = (Aabb::empty(), 0);
+        for split in 1..BUCKETS {
+            left_side = (
+                left_side.0.union(&bucket_bounds[split - 1]),
+                left_side.1 + counts[split - 1],
+            );
+            let (left_bounds, left_count) = left_side;
+            let (right_bounds, right_count) = right_sides[split];
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+            let cost = TRAVERSAL_COST
+                + INTERSECTION_COST
+                    * (left_bounds.surface_area() * left_count as f32
+                        + right_bounds.surface_area() * right_count as f32)
+                    / bounds.surface_area();
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+
+        let leaf_cost = INTERSECTION_COST * primitives.len() as f32;
+        if primitives.len() <= MAX_LEAF_SIZE && leaf_cost <= best_cost {
+            return None;
+        }
+        // Sorting keeps the partition deterministic, it also keeps primitives with the same
+        // bucket in their original order.
+        primitives.sort_by_key(|p| bucket(p) >= best_split);
+        Some(
+            primitives
+                .iter()
+                .position(|p| bucket(p) >= best_split)
+                .unwrap(),
+        )
+    }
+
+    pub fn stats(&self) -> BvhStats {
+        BvhStats {
+            primitives: self.primitives.len(),
+            unbounded: self.unbounded.len(),
+            nodes: self.nodes.len(),
+            leaves: self
+                .nodes
+                .iter()
+                .filter(|n| matches!(n, Node::Leaf { .. }))
+                .count(),
+            depth: self.depth,
+        }
+    }
+
+    // Returns the same result as scene::closest_intersection called on the shapes the BVH was
+    // built from, ties are broken in favor of the shape that comes first in the slice.
+    pub fn closest_intersection<'a, S: Shape>(
+        &self,
+        shapes: &'a [S],
+        ray: &Ray,
+    ) -> Option<Intersection<'a>> {
+        self.closest_hit(ray, |index, ray| shapes[index].intersect_ray(ray))
+    }
+
+    // Finds the closest of the intersections returned by intersect, which is called with the
+    // indices of the primitives the ray may hit. The ray passed to it is shortened to end at the
+    // closest hit found so far.
+    pub fn closest_hit<'a, F>(&self, ray: &Ray, mut intersect: F) -> Option<Intersection<'a>>
+    where
+        F: FnMut(usize, &Ray) -> Option<Intersection<'a>>,
+    {
+        let mut closest = ClosestHit {
+            hit: None,
+            index: usize::MAX,
+            ray: *ray,
+        };
+        for &index in &self.unbounded {
+            closest.update(index, &mut intersect);
+        }
+        if self.nodes.is_empty() {
+            return closest.hit;
+        }
+
+        let inverse_dir = Vector {
+            x: 1.0 / ray.dir.0.x,
+            y: 1.0 / ray.dir.0.y,
+            z: 1.0 / ray.dir.0.z,
+        };
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node
+                .bounds()
+                .intersect_ray(&closest.ray, &inverse_dir)
+                .is_none()
+            {
+                continue;
+            }
+            match *node {
+                Node::Leaf { first, count, .. } => {
+                    for &index in &self.primitives[first..first + count] {
+                        closest.update(index, &mut intersect);
+                    }
+                }
+                Node::Interior { right, .. } => {
+                    // Visit the closer child first, it's likely to produce a hit that lets us skip
+                    // the other one.
+                    let left = node_index + 1;
+                    let left_distance = self.nodes[left]
+                        .bounds()
+                        .intersect_ray(&closest.ray, &inverse_dir);
+                    let right_distance = self.nodes[right]
+                        .bounds()
+                        .intersect_ray(&closest.ray, &inverse_dir);
+                    match (left_distance, right_distance) {
+                        (Some(l), Some(r)) if r < l => {
+                            stack.push(left);
+                            stack.push(right);
+                        }
+                        (Some(_), Some(_)) => {
+                            stack.push(right);
+                            stack.push(left);
+                        }
+                        (Some(_), None) => stack.push(left),
+                        (None, Some(_)) => stack.push(right),
+                        (None, None) => (),
+                    }
+                }
+            }
+        }
+        closest.hit
+    }
+}
+
+// The ray ends at the closest hit so far. Hits at exactly the same distance are still reported by
+// shapes and replace the closest one if they come first in the shape order, that way the result
+// doesn't depend on the order the BVH is traversed in.
+struct ClosestHit<'a> {
+    hit: Option<Intersection<'a>>,
+    index: usize,
+    ray: Ray,
+}
+
+impl<'a> ClosestHit<'a> {
+    fn update<F>(&mut self, index: usize, intersect: &mut F)
+    where
+        F: FnMut(usize, &Ray) -> Option<Intersection<'a>>,
+    {
+        if let Some(intersection) = intersect(index, &self.ray) {
+            let t = intersection.t;
+            if t < self.ray.t_max || index < self.index {
+                self.ray.t_max = t;
+                self.index = index;
+                self.hit = Some(intersection);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_almost_eq;
+    use crate::bvh::{Aabb, Bvh};
+    use crate::material::Material;
+    use crate::scene::{closest_intersection, Motion, Plane, Ray, Shape, Sphere, Vector};
+    use crate::traits::AlmostEqual;
+
+    // A tiny xorshift generator, good enough to scatter spheres around.
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as f32 / u32::MAX as f32
+        }
+
+        fn vector(&mut self, scale: f32) -> Vector {
+            Vector {
+                x: (self.next() - 0.5) * scale,
+                y: (self.next() - 0.5) * scale,
+                z: (self.next() - 0.5) * scale,
+            }
+        }
+    }
+
+    #[test]
+    fn test_aabb_ray_intersection() {
+        let aabb = Aabb {
+            min: Vector {
+                x: -1.0,
+                y: -1.0,
+                z: -1.0,
+            },
+            max: Vector {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        };
+        let ray = Ray::new(
+            Vector {
+                x: -5.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector::unitx(),
+        );
+        let inverse_dir = Vector {
+            x: 1.0,
+            y: f32::INFINITY,
+            z: f32::INFINITY,
+        };
+        assert_almost_eq!(aabb.intersect_ray(&ray, &inverse_dir), Some(4.0));
+        let short = Ray { t_max: 3.0, ..ray };
+        assert_almost_eq!(aabb.intersect_ray(&short, &inverse_dir), None);
+        let starting_later = Ray { t_min: 5.0, ..ray };
+        assert_almost_eq!(aabb.intersect_ray(&starting_later, &inverse_dir), Some(5.0));
+        let starting_past = Ray { t_min: 7.0, ..ray };
+        assert_almost_eq!(aabb.intersect_ray(&starting_past, &inverse_dir), None);
+        let inside = Ray {
+            pos: Vector::zero(),
+            ..ray
+        };
+        assert_almost_eq!(aabb.intersect_ray(&inside, &inverse_dir), Some(0.0));
+        let behind = Ray {
+            pos: Vector {
+                x: 5.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            ..ray
+        };
+        assert_almost_eq!(aabb.intersect_ray(&behind, &inverse_dir), None);
+        let grazing = Ray {
+            pos: Vector {
+                x: -5.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            ..ray
+        };
+        assert_almost_eq!(aabb.intersect_ray(&grazing, &inverse_dir), Some(4.0));
+        let above = Ray {
+            pos: Vector {
+                x: -5.0,
+                y: 1.5,
+                z: 0.0,
+            },
+            ..ray
+        };
+        assert_almost_eq!(aabb.intersect_ray(&above, &inverse_dir), None);
+        assert_almost_eq!(aabb.surface_area(), 24.0);
+    }
+
+    #[test]
+    fn test_bvh_matches_brute_force() {
+        let mut random = Random(2463534242);
+        for &count in &[0, 1, 2, 5, 50, 1000] {
+            let mut shapes: Vec<Box<dyn Shape>> = Vec::new();
+            for i in 0..count {
+                if i % 100 == 99 {
+                    shapes.push(Box::new(Plane {
+                        point: random.vector(200.0),
+                        normal: random.vector(1.0).normalized(),
+                        material: Material::dummy(),
+                    }));
+                } else {
+                    shapes.push(Box::new(Sphere {
+                        center: random.vector(100.0),
+                        radius: 0.1 + random.next() * 5.0,
+                        material: Material::dummy(),
+                        motion: Motion::default(),
+                    }));
+                }
+            }
+            let bvh = Bvh::build(&shapes);
+            let stats = bvh.stats();
+            assert_eq!(stats.primitives + stats.unbounded, count);
+            assert_eq!(stats.unbounded, count / 100);
+            for i in 0..500 {
+                let mut ray = Ray::new(random.vector(150.0), random.vector(1.0).normalized());
+                // Some of the rays only cover part of the scene.
+                if i % 2 == 1 {
+                    ray.t_min = random.next() * 100.0;
+                    ray.t_max = ray.t_min + random.next() * 200.0;
+                }
+                assert_almost_eq!(
+                    bvh.closest_intersection(&shapes, &ray),
+                    closest_intersection(&shapes, &ray)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_bvh_stats() {
+        let spheres: Vec<Sphere> = (0..64)
+            .map(|i| Sphere {
+                center: Vector {
+                    x: i as f32 * 3.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                radius: 1.0,
+                material: Material::dummy(),
+                motion: Motion::default(),
+            })
+            .collect();
+        let stats = Bvh::build(&spheres).stats();
+        assert_eq!(stats.primitives, 64);
+        assert_eq!(stats.nodes, 2 * stats.leaves - 1);
+        assert!(stats.leaves >= 64 / 4);
+        // Evenly spaced spheres should result in a balanced tree.
+        assert!(stats.depth <= 8, "{}", stats);
+    }
+}
@@ -1,26 +1,166 @@
 use crate::material::Color;
-use std::io::Write;
+use crate::png::{read_png, write_png};
+use std::io::{Read, Write};
+use std::path::Path;
 
-pub fn image_to_file(image: &Image, w: &mut Write) {
-    write!(w, "P3\n{} {}\n255\n", image.width(), image.height()).expect("Cannot write");
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    // ASCII PPM
+    P3,
+    // Binary PPM
+    P6,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name.to_lowercase().as_ref() {
+            "p3" => Some(ImageFormat::P3),
+            "p6" => Some(ImageFormat::P6),
+            "png" => Some(ImageFormat::Png),
+            _ => None,
+        }
+    }
 
-    for y in 0..image.height() {
-        for x in 0..image.width() {
-            let color = image.get_color(x, y);
-            write!(
-                w,
-                "{} {} {} ",
-                (color.r * 255.0) as u8,
-                (color.g * 255.0) as u8,
-                (color.b * 255.0) as u8
-            )
-            .expect("Cannot write");
+    // Guesses the format from the file extension, PPM files are written in the binary variant.
+    pub fn from_path(path: &Path) -> Option<ImageFormat> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_ref() {
+            "ppm" | "pnm" => Some(ImageFormat::P6),
+            "png" => Some(ImageFormat::Png),
+            _ => None,
         }
-        write!(w, "\n").expect("Cannot write");
+    }
+}
+
+pub fn image_to_file(image: &Image, format: ImageFormat, w: &mut dyn Write) {
+    match format {
+        ImageFormat::P3 => write_p3(image, w),
+        ImageFormat::P6 => write_p6(image, w),
+        ImageFormat::Png => write_png(w, image.width(), image.height(), &image.to_rgb_bytes()),
     }
     w.flush().expect("Cannot flush");
 }
 
+fn write_p3(image: &Image, w: &mut dyn Write) {
+    write!(w, "P3\n{} {}\n255\n", image.width(), image.height()).expect("Cannot write");
+    for row in image.to_rgb_bytes().chunks(image.width() * 3) {
+        for rgb in row.chunks(3) {
+            write!(w, "{} {} {} ", rgb[0], rgb[1], rgb[2]).expect("Cannot write");
+        }
+        writeln!(w).expect("Cannot write");
+    }
+}
+
+fn write_p6(image: &Image, w: &mut dyn Write) {
+    write!(w, "P6\n{} {}\n255\n", image.width(), image.height()).expect("Cannot write");
+    w.write_all(&image.to_rgb_bytes()).expect("Cannot write");
+}
+
+// Reads a P3, P6 or PNG image, telling them apart by their first bytes. Values are scaled to
+// [0.0, 1.0] as they are, without any color space conversion, so that writing the image with
+// image_to_file gives back the same file.
+pub fn image_from_file(r: &mut dyn Read) -> Result<Image, String> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)
+        .map_err(|error| error.to_string())?;
+    let (width, height, pixels) = if data.starts_with(b"P3") || data.starts_with(b"P6") {
+        read_ppm(&data)?
+    } else if data.starts_with(b"\x89PNG") {
+        read_png(&data)?
+    } else {
+        return Err("expected a PPM or PNG image".to_string());
+    };
+    let mut image = Image::new(width, height);
+    for (color, rgb) in image.buffer.iter_mut().zip(pixels.chunks(3)) {
+        *color = Color::new(rgb[0], rgb[1], rgb[2]);
+    }
+    Ok(image)
+}
+
+// Splits the text parts of a PPM file into numbers, skipping whitespace and comments.
+struct PpmReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PpmReader<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some(&byte) = self.data.get(self.position) {
+            if byte == b'#' {
+                while self.data.get(self.position).is_some_and(|&b| b != b'\n') {
+                    self.position += 1;
+                }
+            } else if byte.is_ascii_whitespace() {
+                self.position += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn number(&mut self, what: &str) -> Result<usize, String> {
+        self.skip_whitespace();
+        let start = self.position;
+        while self.data.get(self.position).is_some_and(u8::is_ascii_digit) {
+            self.position += 1;
+        }
+        std::str::from_utf8(&self.data[start..self.position])
+            .unwrap()
+            .parse()
+            .map_err(|_| format!("expected the {}", what))
+    }
+}
+
+fn read_ppm(data: &[u8]) -> Result<(usize, usize, Vec<f32>), String> {
+    let mut reader = PpmReader { data, position: 2 };
+    let width = reader.number("width")?;
+    let height = reader.number("height")?;
+    let max = reader.number("maximum value")?;
+    if width == 0 || height == 0 {
+        return Err("the image has no pixels".to_string());
+    }
+    if max == 0 || max > 65535 {
+        return Err("the maximum value must be between 1 and 65535".to_string());
+    }
+    // The header can claim any size, so it's checked against the data that's actually there
+    // before anything gets allocated.
+    let count = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(3))
+        .ok_or("the image is too large")?;
+    let mut values = Vec::new();
+    if data[1] == b'3' {
+        for _ in 0..count {
+            values.push(reader.number("pixel value")?);
+        }
+    } else {
+        // A single whitespace character separates the header from the binary samples, which take
+        // two bytes (most significant first) when they don't fit in one.
+        let start = reader.position + 1;
+        let size = if max > 255 { 2 } else { 1 };
+        let samples = count
+            .checked_mul(size)
+            .filter(|&length| length <= data.len().saturating_sub(start))
+            .map(|length| &data[start..start + length])
+            .ok_or("not enough pixel data")?;
+        values.extend(samples.chunks(size).map(|sample| {
+            sample
+                .iter()
+                .fold(0, |value, &byte| value << 8 | byte as usize)
+        }));
+    }
+    if values.iter().any(|&value| value > max) {
+        return Err("pixel value above the maximum".to_string());
+    }
+    let pixels = values
+        .into_iter()
+        .map(|value| value as f32 / max as f32)
+        .collect();
+    Ok((width, height, pixels))
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Image {
     buffer: Vec<Color>,
     w: usize,
@@ -51,21 +191,43 @@ impl Image {
     pub fn height(&self) -> usize {
         self.h
     }
+
+    // Returns the pixels row by row as 8 bit r, g, b triples. Colors outside of the [0.0, 1.0]
+    // range are clamped.
+    pub fn to_rgb_bytes(&self) -> Vec<u8> {
+        self.buffer
+            .iter()
+            .flat_map(|color| {
+                let color = color.clamped();
+                vec![
+                    (color.r * 255.0) as u8,
+                    (color.g * 255.0) as u8,
+                    (color.b * 255.0) as u8,
+                ]
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::image::{image_to_file, Image};
+    use crate::image::{image_from_file, image_to_file, Image, ImageFormat};
     use crate::material::Color;
+    use std::path::Path;
     use std::str;
 
-    #[test]
-    fn test_image_to_file() {
+    fn test_image() -> Image {
         let mut image = Image::new(3, 2);
         image.set_color(0, 0, Color::new_red());
         image.set_color(2, 1, Color::new_white());
+        image
+    }
+
+    #[test]
+    fn test_image_to_file() {
+        let image = test_image();
         let mut buffer = Vec::new();
-        image_to_file(&image, &mut buffer);
+        image_to_file(&image, ImageFormat::P3, &mut buffer);
         let got = str::from_utf8(&buffer).unwrap();
         let expected = "P3
 3 2
@@ -75,4 +237,74 @@ mod tests {
 ";
         assert_eq!(got, expected);
     }
+
+    #[test]
+    fn test_image_clamps_colors() {
+        let mut image = Image::new(2, 1);
+        image.set_color(0, 0, Color::new(4.0, 0.5, -1.0));
+        image.set_color(1, 0, Color::new_white() * 2.0);
+        assert_eq!(image.to_rgb_bytes(), vec![255, 127, 0, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_image_to_binary_ppm() {
+        let mut buffer = Vec::new();
+        image_to_file(&test_image(), ImageFormat::P6, &mut buffer);
+        let mut expected = b"P6\n3 2\n255\n".to_vec();
+        expected.extend(&[255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255]);
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn test_image_format() {
+        assert_eq!(ImageFormat::from_name("P6"), Some(ImageFormat::P6));
+        assert_eq!(ImageFormat::from_name("png"), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::from_name("jpeg"), None);
+        assert_eq!(
+            ImageFormat::from_path(Path::new("out/image.PNG")),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            ImageFormat::from_path(Path::new("image.ppm")),
+            Some(ImageFormat::P6)
+        );
+        assert_eq!(ImageFormat::from_path(Path::new("image")), None);
+        assert_eq!(ImageFormat::from_path(Path::new("-")), None);
+    }
+
+    #[test]
+    fn test_image_from_file() {
+        for &format in &[ImageFormat::P3, ImageFormat::P6, ImageFormat::Png] {
+            let mut buffer = Vec::new();
+            image_to_file(&test_image(), format, &mut buffer);
+            let image = image_from_file(&mut &buffer[..]).unwrap();
+            assert_eq!(image, test_image());
+        }
+
+        let image = image_from_file(&mut &b"P3 # comment\n2 1\n# another\n4\n0 1 2 3 4 4\n"[..]);
+        assert_eq!(
+            image.map(|image| (image.get_color(0, 0), image.get_color(1, 0))),
+            Ok((Color::new(0.0, 0.25, 0.5), Color::new(0.75, 1.0, 1.0)))
+        );
+        let image = image_from_file(&mut &b"P6 1 1 1000\n\x03\xe8\x01\xf4\x00\x00"[..]);
+        assert_eq!(
+            image.map(|image| image.get_color(0, 0)),
+            Ok(Color::new(1.0, 0.5, 0.0))
+        );
+
+        assert!(image_from_file(&mut &b"GIF89a"[..]).is_err());
+        assert!(image_from_file(&mut &b"P3\n1 1\n255\n0 0"[..]).is_err());
+        assert!(image_from_file(&mut &b"P3\n1 1\n255\n0 0 256"[..]).is_err());
+        assert!(image_from_file(&mut &b"P6\n2 1\n255\n\x00\x00\x00"[..]).is_err());
+        assert!(image_from_file(&mut &b"P6\n0 1\n255\n"[..]).is_err());
+        assert_eq!(
+            image_from_file(&mut &b"P6 4000000000 4000000000 255\n\x00"[..]),
+            Err("the image is too large".to_string())
+        );
+        assert_eq!(
+            image_from_file(&mut &b"P6 100000 100000 65535\n\x00"[..]),
+            Err("not enough pixel data".to_string())
+        );
+        assert!(image_from_file(&mut &b"P3 100000 100000 255\n0 0 0"[..]).is_err());
+    }
 }
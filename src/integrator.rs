@@ -0,0 +1,334 @@
+use crate::material::{fresnel, Color, Surface};
+use crate::sampler::{cosine_hemisphere, Sampler};
+use crate::scene::{Ray, Scene};
+use std::f32;
+
+// Computes the light arriving at the camera along a ray (Li in the rendering literature), this is
+// where the different ways of shading a scene plug into the renderer. Integrators that don't need
+// randomness can ignore the sampler, the others must only draw their random numbers from it to
+// keep renders reproducible.
+pub trait Integrator: Sync {
+    fn li(&self, scene: &Scene, ray: &Ray, sampler: &mut Sampler) -> Color;
+}
+
+// Lights surfaces with the scene's lights and adds reflections off glossy surfaces, traced in a
+// single direction picked by the surface's BSDF. Dielectrics both reflect and refract, weighted by
+// the Fresnel equations. Every bounce spends one of the given number of bounces.
+#[derive(Copy, Clone, Debug)]
+pub struct Whitted {
+    pub bounces: usize,
+}
+
+impl Whitted {
+    fn trace(&self, scene: &Scene, ray: &Ray, bounces: usize, sampler: &mut Sampler) -> Color {
+        let intersection = match scene.closest_intersection(ray) {
+            Some(intersection) => intersection,
+            None => return Color::new_black(),
+        };
+        let material = intersection.object.material();
+        let wo = -ray.dir;
+        let mut color = material.emission;
+        match material.surface {
+            Surface::Dielectric { ior, .. } => {
+                if bounces > 0 {
+                    let normal = &intersection.normal;
+                    let eta = if intersection.front_face {
+                        1.0 / ior
+                    } else {
+                        ior
+                    };
+                    let reflected =
+                        self.trace(scene, &ray.reflected(&intersection), bounces - 1, sampler);
+                    color += material.color_at(&intersection)
+                        * match ray.dir.refracted(normal, eta) {
+                            Some(direction) => {
+                                let reflectance = fresnel(wo.0.dot(&normal.0), eta);
+                                let refracted = self.trace(
+                                    scene,
+                                    &intersection.spawn_ray(direction),
+                                    bounces - 1,
+                                    sampler,
+                                );
+                                reflected * reflectance + refracted * (1.0 - reflectance)
+                            }
+                            None => reflected,
+                        };
+                }
+            }
+            _ => {
+                color += scene.direct_lighting(&intersection, &wo);
+                if bounces > 0 {
+                    if let Some(sample) = material.sample_glossy(&intersection, &wo, sampler) {
+                        let ray = intersection.spawn_ray(sample.direction);
+                        color += sample.weight * self.trace(scene, &ray, bounces - 1, sampler);
+                    }
+                }
+            }
+        }
+        // Light reaching the back of a surface has travelled through the medium behind it.
+        if intersection.front_face {
+            color
+        } else {
+            color * material.transmittance(intersection.t)
+        }
+    }
+}
+
+impl Integrator for Whitted {
+    fn li(&self, scene: &Scene, ray: &Ray, sampler: &mut Sampler) -> Color {
+        self.trace(scene, ray, self.bounces, sampler)
+    }
+}
+
+// White where the surface hit is open to the surroundings and black where nearby geometry
+// blocks it, ignoring lights and materials. Every sample checks a single random direction, the
+// ones closer to the normal being picked more often, and only geometry closer than distance
+// counts as blocking.
+#[derive(Copy, Clone, Debug)]
+pub struct AmbientOcclusion {
+    pub distance: f32,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn li(&self, scene: &Scene, ray: &Ray, sampler: &mut Sampler) -> Color {
+        let intersection = match scene.closest_intersection(ray) {
+            Some(intersection) => intersection,
+            None => return Color::new_black(),
+        };
+        let direction =
+            cosine_hemisphere(&intersection.normal, sampler.next_f32(), sampler.next_f32());
+        let probe = Ray {
+            t_max: self.distance,
+            ..intersection.spawn_ray(direction)
+        };
+        match scene.closest_intersection(&probe) {
+            Some(_) => Color::new_black(),
+            None => Color::new_white(),
+        }
+    }
+}
+
+// Shows the shading normals of the surfaces hit, their coordinates mapped from [-1, 1] to the
+// [0, 1] color range, for debugging shapes.
+#[derive(Copy, Clone, Debug)]
+pub struct Normals;
+
+impl Integrator for Normals {
+    fn li(&self, scene: &Scene, ray: &Ray, _sampler: &mut Sampler) -> Color {
+        match scene.closest_intersection(ray) {
+            Some(intersection) => {
+                let n = intersection.normal.0;
+                Color::new(n.x + 1.0, n.y + 1.0, n.z + 1.0) * 0.5
+            }
+            None => Color::new_black(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_almost_eq;
+    use crate::integrator::{AmbientOcclusion, Integrator, Normals, Whitted};
+    use crate::light::{DirectionalLight, Light};
+    use crate::material::{Color, Material, Surface};
+    use crate::sampler::Sampler;
+    use crate::scene::{Motion, Plane, Ray, Scene, Sphere, Vector};
+    use crate::testing::{scene_with, vector};
+    use crate::traits::AlmostEqual;
+    use std::f32;
+
+    fn whitted(scene: &Scene, ray: &Ray, bounces: usize) -> Color {
+        Whitted { bounces }.li(scene, ray, &mut Sampler::new(0, 0))
+    }
+
+    #[test]
+    fn test_whitted() {
+        let sphere = Sphere {
+            center: vector(0.0, 0.0, -5.0),
+            radius: 1.0,
+            material: Material::diffuse(Color::new_red()),
+            motion: Motion::default(),
+        };
+        // Lambertian surfaces reflect color / pi of the light arriving at them, the factor pi in the
+        // intensity cancels that out.
+        let light = |direction: Vector| {
+            Box::new(DirectionalLight {
+                direction: direction.normalized(),
+                color: Color::new_white(),
+                intensity: 2.0 * f32::consts::PI,
+            }) as Box<dyn Light>
+        };
+        let ray = Ray::new(Vector::zero(), -Vector::unitz());
+
+        // Lights brighter than white aren't clamped.
+        let scene = scene_with(
+            vec![Box::new(sphere.clone())],
+            vec![light(-Vector::unitz().0)],
+        );
+        assert_almost_eq!(whitted(&scene, &ray, 0), Color::new(2.0, 0.0, 0.0));
+        // Nothing to reflect.
+        assert_almost_eq!(whitted(&scene, &ray, 1), Color::new(2.0, 0.0, 0.0));
+
+        let scene = scene_with(
+            vec![Box::new(sphere)],
+            vec![light(vector(0.0, -1.0, -1.0)), light(Vector::unitz().0)],
+        );
+        assert_almost_eq!(
+            whitted(&scene, &ray, 0),
+            2.0 * 45.0f32.to_radians().cos() * Color::new_red()
+        );
+
+        // A gray metal mirror reflecting a red wall, the light is to the side so the reflected
+        // wall is lit. The mirror itself only reflects the light in one direction, away from the
+        // camera.
+        let wall = Plane {
+            point: vector(4.0, 0.0, 0.0),
+            normal: -Vector::unitx(),
+            material: Material::diffuse(Color::new_red()),
+        };
+        let mirror = Plane {
+            point: vector(0.0, 0.0, -10.0),
+            normal: Vector::unitz(),
+            material: Material::microfacet(Color::new(0.5, 0.5, 0.5), 0.0, 1.0),
+        };
+        let scene = scene_with(
+            vec![Box::new(wall), Box::new(mirror)],
+            vec![light(vector(1.0, 0.0, -0.5))],
+        );
+        let ray = Ray::new(vector(-1.0, 0.0, -7.0), vector(1.0, 0.0, -1.0).normalized());
+        let wall_cosine = 1.0 / 1.25f32.sqrt();
+        // Metals reflect more at grazing angles (Schlick's approximation of the Fresnel terms).
+        let reflectance = 0.5 + 0.5 * (1.0 - 45.0f32.to_radians().cos()).powi(5);
+        assert!(whitted(&scene, &ray, 0).almost_equal_with_epsilon(&Color::new_black(), 1e-4));
+        assert!(whitted(&scene, &ray, 1).almost_equal_with_epsilon(
+            &(Color::new_red() * 2.0 * wall_cosine * reflectance),
+            1e-3
+        ));
+    }
+
+    #[test]
+    fn test_whitted_dielectric() {
+        // Looking through the middle of a glass ball at a glowing wall. Head on 4% of the light
+        // is reflected off glass when it enters and again when it leaves, the light reflected
+        // inside the ball takes more bounces to get out than are available.
+        let glass = |absorption: Color| Sphere {
+            center: vector(0.0, 0.0, -5.0),
+            radius: 1.0,
+            material: Material {
+                surface: Surface::Dielectric {
+                    ior: 1.5,
+                    absorption,
+                },
+                ..Material::dielectric(1.5)
+            },
+            motion: Motion::default(),
+        };
+        let wall = Plane {
+            point: vector(0.0, 0.0, -10.0),
+            normal: Vector::unitz(),
+            material: Material {
+                emission: Color::new_white(),
+                ..Material::dummy()
+            },
+        };
+        let ray = Ray::new(Vector::zero(), -Vector::unitz());
+        let scene = scene_with(
+            vec![Box::new(glass(Color::new_black())), Box::new(wall.clone())],
+            vec![],
+        );
+        let transmitted = 0.96 * 0.96;
+        assert!(whitted(&scene, &ray, 2)
+            .almost_equal_with_epsilon(&Color::new(transmitted, transmitted, transmitted), 1e-5));
+        // Every interaction with the glass takes a bounce.
+        assert_almost_eq!(whitted(&scene, &ray, 1), Color::new_black());
+
+        // Absorption dims the light going through 2 units of glass.
+        let absorption = Color::new(0.0, 0.5, 1.0);
+        let scene = scene_with(vec![Box::new(glass(absorption)), Box::new(wall)], vec![]);
+        let expected = Color::new(1.0, (-1.0f32).exp(), (-2.0f32).exp()) * transmitted;
+        assert!(whitted(&scene, &ray, 2).almost_equal_with_epsilon(&expected, 1e-5));
+
+        // A ray inside the ball meeting its surface past the critical angle keeps meeting it at
+        // the same angle, the light around never gets in along it.
+        let sky = Sphere {
+            center: Vector::zero(),
+            radius: 100.0,
+            material: Material {
+                emission: Color::new_white(),
+                ..Material::dummy()
+            },
+            motion: Motion::default(),
+        };
+        let scene = scene_with(
+            vec![Box::new(glass(Color::new_black())), Box::new(sky)],
+            vec![],
+        );
+        let trapped = Ray::new(vector(0.0, 0.9, -5.0), Vector::unitx());
+        assert_almost_eq!(whitted(&scene, &trapped, 10), Color::new_black());
+        let escaping = Ray::new(vector(0.0, 0.3, -5.0), Vector::unitx());
+        assert!(whitted(&scene, &escaping, 10).r > 0.9);
+    }
+
+    #[test]
+    fn test_ambient_occlusion() {
+        // A floor with a wall standing on it, the closer to the wall the more of the floor's
+        // surroundings it blocks.
+        let floor = Plane {
+            point: Vector::zero(),
+            normal: Vector::unity(),
+            material: Material::diffuse(Color::new_white()),
+        };
+        let wall = Plane {
+            point: vector(0.0, 0.0, -10.0),
+            normal: Vector::unitz(),
+            material: Material::diffuse(Color::new_white()),
+        };
+        let scene = scene_with(vec![Box::new(floor), Box::new(wall)], vec![]);
+        let occlusion = |z: f32, distance: f32| {
+            let integrator = AmbientOcclusion { distance };
+            let ray = Ray::new(vector(0.0, 1.0, z), -Vector::unity());
+            let mut sampler = Sampler::new(1, 0);
+            let count = 2000;
+            let mut sum = Color::new_black();
+            for _ in 0..count {
+                sum += integrator.li(&scene, &ray, &mut sampler);
+            }
+            sum.r / count as f32
+        };
+        let far = occlusion(-5.0, 1.0);
+        assert_eq!(far, 1.0);
+        let near = occlusion(-9.9, 1.0);
+        assert!(near > 0.3 && near < 0.9, "{}", near);
+        // The wall is infinitely high, so without a limit it blocks half of the directions.
+        let unlimited = occlusion(-5.0, f32::INFINITY);
+        assert!((unlimited - 0.5).abs() < 0.05, "{}", unlimited);
+
+        let miss = Ray::new(vector(0.0, 1.0, 0.0), Vector::unity());
+        assert_almost_eq!(
+            AmbientOcclusion { distance: 1.0 }.li(&scene, &miss, &mut Sampler::new(0, 0)),
+            Color::new_black()
+        );
+    }
+
+    #[test]
+    fn test_normals() {
+        let sphere = Sphere {
+            center: vector(0.0, 0.0, -5.0),
+            radius: 1.0,
+            material: Material::diffuse(Color::new_red()),
+            motion: Motion::default(),
+        };
+        let scene = scene_with(vec![Box::new(sphere)], vec![]);
+        let mut sampler = Sampler::new(0, 0);
+        let ray = Ray::new(Vector::zero(), -Vector::unitz());
+        assert_almost_eq!(
+            Normals.li(&scene, &ray, &mut sampler),
+            Color::new(0.5, 0.5, 1.0)
+        );
+        let ray = Ray::new(vector(0.0, 5.0, -5.0), -Vector::unity());
+        assert_almost_eq!(
+            Normals.li(&scene, &ray, &mut sampler),
+            Color::new(0.5, 1.0, 0.5)
+        );
+    }
+}
@@ -1,11 +1,39 @@
+pub mod bvh;
 pub mod image;
+pub mod integrator;
+pub mod light;
 pub mod material;
+pub mod mesh;
+pub mod microfacet;
+pub mod obj;
+pub mod path;
+pub mod png;
+pub mod primitives;
 pub mod render;
+pub mod sampler;
 pub mod scene;
+#[cfg(test)]
+mod testing;
+pub mod texture;
 pub mod traits;
+pub mod transform;
 
-pub use crate::image::{image_to_file, Image};
-pub use crate::material::{Color, Material};
-pub use crate::render::render;
-pub use crate::scene::{Camera, Radians, Ray, Sphere, Vector};
+pub use crate::image::{image_from_file, image_to_file, Image, ImageFormat};
+pub use crate::integrator::{AmbientOcclusion, Integrator, Normals, Whitted};
+pub use crate::light::{DirectionalLight, Light, PointLight};
+pub use crate::material::{BsdfSample, Color, Material, Surface};
+pub use crate::mesh::{Mesh, Triangle};
+pub use crate::obj::load_obj;
+pub use crate::path::PathTracer;
+pub use crate::primitives::{Cone, Cuboid, Cylinder, Disk, Torus};
+pub use crate::render::{render, render_with, RenderMode, RenderSettings};
+pub use crate::scene::{
+    load_scene, Camera, CameraError, Motion, ParseError, Plane, Projection, Radians, Ray, Scene,
+    Shape, Sphere, Vector,
+};
+pub use crate::texture::{
+    Checkerboard, Filter, Gradient, ImageTexture, Marble, Noise, Stripes, Texture, Turbulence,
+    Wood, Wrap,
+};
 pub use crate::traits::AlmostEqual;
+pub use crate::transform::{Instance, Transform};
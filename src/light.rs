@@ -0,0 +1,117 @@
+use crate::material::Color;
+use crate::scene::{UnitVector, Vector};
+use std::f32;
+use std::fmt;
+
+// The light arriving at a point from a light source.
+#[derive(Copy, Clone, Debug)]
+pub struct LightSample {
+    // Points from the illuminated point towards the light.
+    pub direction: UnitVector,
+    // How far away the light is, f32::INFINITY for lights without a position.
+    pub distance: f32,
+    pub radiance: Color,
+}
+
+// A source of light that shapes are shaded against.
+pub trait Light: fmt::Debug + Send + Sync {
+    fn illuminate(&self, point: &Vector) -> LightSample;
+}
+
+// Shines in all directions from a single point, the light falls off with the square of the
+// distance.
+#[derive(Copy, Clone, Debug)]
+pub struct PointLight {
+    pub position: Vector,
+    pub color: Color,
+    pub intensity: f32,
+}
+
+impl Light for PointLight {
+    fn illuminate(&self, point: &Vector) -> LightSample {
+        let to_light = self.position - *point;
+        let distance = to_light.len();
+        LightSample {
+            direction: to_light.normalized(),
+            distance,
+            radiance: self.color * (self.intensity / (distance * distance)),
+        }
+    }
+}
+
+// A light infinitely far away (like the sun), all its rays travel in the same direction and its
+// intensity doesn't depend on the distance.
+#[derive(Copy, Clone, Debug)]
+pub struct DirectionalLight {
+    pub direction: UnitVector,
+    pub color: Color,
+    pub intensity: f32,
+}
+
+impl Light for DirectionalLight {
+    fn illuminate(&self, _point: &Vector) -> LightSample {
+        LightSample {
+            direction: -self.direction,
+            distance: f32::INFINITY,
+            radiance: self.color * self.intensity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_almost_eq;
+    use crate::light::{DirectionalLight, Light, PointLight};
+    use crate::material::Color;
+    use crate::scene::Vector;
+    use crate::traits::AlmostEqual;
+    use std::f32;
+
+    #[test]
+    fn test_point_light() {
+        let light = PointLight {
+            position: Vector {
+                x: 0.0,
+                y: 2.0,
+                z: 0.0,
+            },
+            color: Color::new_white(),
+            intensity: 8.0,
+        };
+        let sample = light.illuminate(&Vector::zero());
+        assert_almost_eq!(sample.direction, Vector::unity());
+        assert_almost_eq!(sample.distance, 2.0);
+        assert_almost_eq!(sample.radiance, Color::new(2.0, 2.0, 2.0));
+
+        // Twice as far away means a quarter of the light.
+        let sample = light.illuminate(&Vector {
+            x: 0.0,
+            y: -2.0,
+            z: 0.0,
+        });
+        assert_almost_eq!(sample.distance, 4.0);
+        assert_almost_eq!(sample.radiance, Color::new(0.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn test_directional_light() {
+        let light = DirectionalLight {
+            direction: -Vector::unity(),
+            color: Color::new_red(),
+            intensity: 3.0,
+        };
+        for point in &[
+            Vector::zero(),
+            Vector {
+                x: 100.0,
+                y: -50.0,
+                z: 3.0,
+            },
+        ] {
+            let sample = light.illuminate(point);
+            assert_almost_eq!(sample.direction, Vector::unity());
+            assert_eq!(sample.distance, f32::INFINITY);
+            assert_almost_eq!(sample.radiance, Color::new(3.0, 0.0, 0.0));
+        }
+    }
+}
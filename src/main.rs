@@ -1,134 +1,79 @@
-use ray::{image_to_file, render, Camera, Color, Material, Radians, Sphere, Vector};
+use ray::{image_to_file, load_scene, render, ImageFormat};
 use std::env;
-use std::fs::File;
-use std::io::{self, Write};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 use std::process;
 
+fn usage(program: &str) -> ! {
+    println!(
+        "Usage: {} [--threads <count>] [--format p3|p6|png] <scene filename> <output filename> \
+         (<output filename> may be - for stdout, <count> defaults to the number of CPU cores, the \
+         format defaults to the one matching the output file extension or to p3)",
+        program,
+    );
+    process::exit(1);
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!(
-            "Usage: {} <output filename> (<output filename> may be - for stdout)",
-            args[0],
-        );
-        process::exit(1);
+    let mut threads = None;
+    let mut format = None;
+    let mut positional = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_ref() {
+            "--threads" => match rest.next().map(|count| count.parse::<usize>()) {
+                Some(Ok(count)) if count > 0 => threads = Some(count),
+                _ => usage(&args[0]),
+            },
+            "--format" => match rest.next().and_then(|name| ImageFormat::from_name(name)) {
+                Some(name) => format = Some(name),
+                None => usage(&args[0]),
+            },
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 2 {
+        usage(&args[0]);
     }
 
-    let filename = &args[1];
-    let mut file: Box<Write> = match filename.as_ref() {
-        "-" => Box::new(io::stdout()),
-        _ => Box::new(File::create(filename).expect("Cannot open file for writing")),
+    let scene_filename = positional[0];
+    let source = match fs::read_to_string(scene_filename) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", scene_filename, e);
+            process::exit(1);
+        }
     };
+    let directory = Path::new(scene_filename)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let mut scene = match load_scene(&source, directory) {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("{}: {}", scene_filename, e);
+            process::exit(1);
+        }
+    };
+    if let Some(threads) = threads {
+        scene.settings.threads = threads;
+    }
 
-    let spheres = [
-        Sphere {
-            center: Vector {
-                x: 0.0,
-                y: 0.0,
-                z: -5.0,
-            },
-            radius: 1.0,
-            material: Material {
-                color: Color::new_red(),
-            },
-        },
-        Sphere {
-            center: Vector {
-                x: -3.0,
-                y: 1.0,
-                z: -5.0,
-            },
-            radius: 1.0,
-            material: Material {
-                color: Color::new_green(),
-            },
-        },
-        Sphere {
-            center: Vector {
-                x: 5.0,
-                y: 1.0,
-                z: -10.0,
-            },
-            radius: 1.0,
-            material: Material {
-                color: Color::new_blue(),
-            },
-        },
-        // Let's simulate walls, floor and ceiling with spheres
-        Sphere {
-            center: Vector {
-                x: 0.0,
-                y: -10005.0,
-                z: 0.0,
-            },
-            radius: 10000.0,
-            material: Material {
-                color: Color::new_white(),
-            },
-        },
-        Sphere {
-            center: Vector {
-                x: 0.0,
-                y: 10005.0,
-                z: 0.0,
-            },
-            radius: 10000.0,
-            material: Material {
-                color: Color::new_white(),
-            },
-        },
-        Sphere {
-            center: Vector {
-                x: -10010.0,
-                y: 0.0,
-                z: 0.0,
-            },
-            radius: 10000.0,
-            material: Material {
-                color: Color::new_white(),
-            },
-        },
-        Sphere {
-            center: Vector {
-                x: 10010.0,
-                y: 0.0,
-                z: 0.0,
-            },
-            radius: 10000.0,
-            material: Material {
-                color: Color::new_white(),
-            },
-        },
-        Sphere {
-            center: Vector {
-                x: 0.0,
-                y: 0.0,
-                z: -10015.0,
-            },
-            radius: 10000.0,
-            material: Material {
-                color: Color::new_white(),
-            },
-        },
-        Sphere {
-            center: Vector {
-                x: 0.0,
-                y: 0.0,
-                z: 10005.0,
-            },
-            radius: 10000.0,
-            material: Material {
-                color: Color::new_white(),
-            },
-        },
-    ];
-    let camera = Camera {
-        position: Vector::zero(),
-        forward: -Vector::unitz(),
-        up: Vector::unity(),
-        aspect_ratio: 4.0 / 3.0,
-        fovx: Radians(90.0f32.to_radians()),
+    let filename = positional[1];
+    // Without a known extension (or when writing to stdout) the output stays ASCII PPM, as it's
+    // always been, so it can still be piped into text tools.
+    let format = format
+        .or_else(|| ImageFormat::from_path(Path::new(filename)))
+        .unwrap_or(ImageFormat::P3);
+    let mut file: Box<dyn Write> = match filename.as_ref() {
+        "-" => Box::new(BufWriter::new(io::stdout())),
+        _ => Box::new(BufWriter::new(
+            File::create(filename).expect("Cannot open file for writing"),
+        )),
     };
-    let image = render(&spheres, &camera, 800, 600, 3);
-    image_to_file(&image, &mut file);
+
+    eprintln!("{}", scene.bvh_stats());
+    let image = render(&scene);
+    image_to_file(&image, format, &mut file);
 }
@@ -1,21 +1,321 @@
+use crate::microfacet::Ggx;
+use crate::sampler::{cosine_hemisphere, Sampler};
+use crate::scene::{Intersection, UnitVector};
+use crate::texture::Texture;
 use crate::traits::AlmostEqual;
 use std::f32;
-use std::ops::{Add, Mul};
+use std::ops::{Add, AddAssign, Div, Mul, Sub};
+use std::sync::Arc;
 
-#[derive(Copy, Clone, Debug)]
+// How light interacts with a surface. Emissive surfaces also give off light of their own.
+#[derive(Clone, Debug)]
 pub struct Material {
-    pub color: Color,
+    // The fraction of light reflected by diffuse surfaces (the albedo), the color of metals and
+    // the tint of dielectrics. It can vary across the surface, see color_at.
+    pub color: Arc<dyn Texture>,
+    pub emission: Color,
+    pub surface: Surface,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Surface {
+    // Reflects light equally in all directions (Lambertian).
+    Diffuse,
+    // The smooth boundary of a transparent medium like glass or water, light is either reflected
+    // or refracted into (or out of) it, see fresnel. ior is the medium's index of refraction and
+    // absorption how much of each color it absorbs per unit of distance travelled through it.
+    Dielectric { ior: f32, absorption: Color },
+    // A surface made of tiny mirror-like facets, see Ggx. Roughness goes from polished (0) to
+    // matte (1). Metals (metallic = 1) reflect light tinted by their color, other surfaces (0)
+    // have a diffuse base of their color under a clear coating reflecting 4% of the light head on,
+    // like plastic. Values in between blend the two.
+    Microfacet { roughness: f32, metallic: f32 },
+}
+
+// A direction picked by Material::sample for light to arrive from, weight is the BSDF times the
+// cosine term divided by the probability density of the direction. The density is None for
+// specular surfaces, which scatter light arriving from a single direction only.
+#[derive(Copy, Clone, Debug)]
+pub struct BsdfSample {
+    pub direction: UnitVector,
+    pub weight: Color,
+    pub pdf: Option<f32>,
 }
 
 impl Material {
-    pub fn dummy() -> Material {
+    pub fn diffuse(color: impl Texture + 'static) -> Material {
+        Material {
+            color: Arc::new(color),
+            emission: Color::new_black(),
+            surface: Surface::Diffuse,
+        }
+    }
+
+    // A clear, colorless medium.
+    pub fn dielectric(ior: f32) -> Material {
+        Material {
+            color: Arc::new(Color::new_white()),
+            emission: Color::new_black(),
+            surface: Surface::Dielectric {
+                ior,
+                absorption: Color::new_black(),
+            },
+        }
+    }
+
+    pub fn microfacet(color: impl Texture + 'static, roughness: f32, metallic: f32) -> Material {
         Material {
-            color: Color::new_black(),
+            color: Arc::new(color),
+            emission: Color::new_black(),
+            surface: Surface::Microfacet {
+                roughness,
+                metallic,
+            },
+        }
+    }
+
+    pub fn dummy() -> Material {
+        Material::diffuse(Color::new_black())
+    }
+
+    // The color of the surface at the intersection.
+    pub fn color_at(&self, intersection: &Intersection) -> Color {
+        self.color.value(&intersection.position, intersection.uv)
+    }
+
+    // Specular surfaces can't be lit by light arriving from a given direction, like that of a
+    // light, they can only pass on the light coming from the directions they sample.
+    pub fn is_specular(&self) -> bool {
+        matches!(self.surface, Surface::Dielectric { .. })
+    }
+
+    // The BSDF, how much of the light arriving from wi is scattered towards wo at the
+    // intersection. Both directions point away from the surface.
+    pub fn eval(&self, intersection: &Intersection, wo: &UnitVector, wi: &UnitVector) -> Color {
+        let normal = &intersection.normal;
+        match self.surface {
+            Surface::Diffuse if wi.0.dot(&normal.0) > 0.0 => {
+                self.color_at(intersection) * f32::consts::FRAC_1_PI
+            }
+            Surface::Microfacet {
+                roughness,
+                metallic,
+            } => {
+                let (wo, wi) = (normal.to_local(&wo.0), normal.to_local(&wi.0));
+                if wo.z <= 0.0 || wi.z <= 0.0 {
+                    return Color::new_black();
+                }
+                let ggx = Ggx::from_roughness(roughness);
+                let h = (wo + wi).normalized().0;
+                let color = self.color_at(intersection);
+                let specular_color = specular_color(&color, metallic);
+                let fresnel = schlick(specular_color, wi.dot(&h));
+                let specular = fresnel * (ggx.d(&h) * ggx.g2(&wo, &wi) / (4.0 * wo.z * wi.z));
+                // The light that gets through the coating both on the way in and on the way out
+                // makes it to the diffuse base and back.
+                let diffuse = (Color::new_white() - schlick(specular_color, wi.z))
+                    * (Color::new_white() - schlick(specular_color, wo.z))
+                    * color
+                    * ((1.0 - metallic) * f32::consts::FRAC_1_PI);
+                specular + diffuse
+            }
+            _ => Color::new_black(),
+        }
+    }
+
+    // The probability density of sample picking wi, 0 for specular surfaces.
+    pub fn pdf(&self, intersection: &Intersection, wo: &UnitVector, wi: &UnitVector) -> f32 {
+        let normal = &intersection.normal;
+        let cosine = wi.0.dot(&normal.0);
+        match self.surface {
+            Surface::Diffuse => cosine.max(0.0) * f32::consts::FRAC_1_PI,
+            Surface::Microfacet {
+                roughness,
+                metallic,
+            } => {
+                let (wo, wi) = (normal.to_local(&wo.0), normal.to_local(&wi.0));
+                if wo.z <= 0.0 || wi.z <= 0.0 {
+                    return 0.0;
+                }
+                let specular = specular_probability(&self.color_at(intersection), wo.z, metallic);
+                specular * Ggx::from_roughness(roughness).reflection_pdf(&wo, &wi)
+                    + (1.0 - specular) * wi.z * f32::consts::FRAC_1_PI
+            }
+            Surface::Dielectric { .. } => 0.0,
+        }
+    }
+
+    // Picks a direction for light scattered towards wo to arrive from, roughly proportionally to
+    // how much of it is scattered. None when the light is absorbed.
+    pub fn sample(
+        &self,
+        intersection: &Intersection,
+        wo: &UnitVector,
+        sampler: &mut Sampler,
+    ) -> Option<BsdfSample> {
+        let normal = &intersection.normal;
+        match self.surface {
+            Surface::Diffuse => {
+                let direction = cosine_hemisphere(normal, sampler.next_f32(), sampler.next_f32());
+                // The Lambertian BRDF (color / pi) times the cosine over the cosine / pi density.
+                Some(BsdfSample {
+                    direction,
+                    weight: self.color_at(intersection),
+                    pdf: Some(direction.0.dot(&normal.0) * f32::consts::FRAC_1_PI),
+                })
+            }
+            Surface::Dielectric { ior, .. } => {
+                let eta = if intersection.front_face {
+                    1.0 / ior
+                } else {
+                    ior
+                };
+                // Reflecting with the probability of the Fresnel reflectance and refracting
+                // otherwise cancels the reflectance out.
+                let incoming = -*wo;
+                let reflectance = fresnel(wo.0.dot(&normal.0), eta);
+                let direction = match incoming.refracted(normal, eta) {
+                    Some(refracted) if sampler.next_f32() >= reflectance => refracted,
+                    _ => incoming.reflected(normal),
+                };
+                Some(BsdfSample {
+                    direction,
+                    weight: self.color_at(intersection),
+                    pdf: None,
+                })
+            }
+            Surface::Microfacet {
+                roughness,
+                metallic,
+            } => {
+                let local_wo = normal.to_local(&wo.0);
+                if local_wo.z <= 0.0 {
+                    return None;
+                }
+                // The glossy reflection and the diffuse base are sampled separately, the density
+                // of the result is that of both combined.
+                let specular =
+                    specular_probability(&self.color_at(intersection), local_wo.z, metallic);
+                let direction = if sampler.next_f32() < specular {
+                    let ggx = Ggx::from_roughness(roughness);
+                    let h = ggx.sample_visible_normal(
+                        &local_wo,
+                        sampler.next_f32(),
+                        sampler.next_f32(),
+                    );
+                    UnitVector(normal.from_local(&(h * (2.0 * local_wo.dot(&h)) - local_wo)))
+                } else {
+                    cosine_hemisphere(normal, sampler.next_f32(), sampler.next_f32())
+                };
+                let pdf = self.pdf(intersection, wo, &direction);
+                if pdf == 0.0 {
+                    return None;
+                }
+                Some(BsdfSample {
+                    direction,
+                    weight: self.eval(intersection, wo, &direction)
+                        * (direction.0.dot(&normal.0) / pdf),
+                    pdf: Some(pdf),
+                })
+            }
+        }
+    }
+
+    // Samples only the glossy reflection of microfacet surfaces, for integrators that handle the
+    // diffuse part on their own.
+    pub fn sample_glossy(
+        &self,
+        intersection: &Intersection,
+        wo: &UnitVector,
+        sampler: &mut Sampler,
+    ) -> Option<BsdfSample> {
+        let normal = &intersection.normal;
+        let (roughness, metallic) = match self.surface {
+            Surface::Microfacet {
+                roughness,
+                metallic,
+            } => (roughness, metallic),
+            _ => return None,
+        };
+        let local_wo = normal.to_local(&wo.0);
+        if local_wo.z <= 0.0 {
+            return None;
+        }
+        let ggx = Ggx::from_roughness(roughness);
+        let h = ggx.sample_visible_normal(&local_wo, sampler.next_f32(), sampler.next_f32());
+        let local_wi = h * (2.0 * local_wo.dot(&h)) - local_wo;
+        if local_wi.z <= 0.0 {
+            return None;
+        }
+        // The specular BRDF F * D * G2 / (4 * cos_o * cos_i) times cos_i over the density
+        // G1(wo) * D / (4 * cos_o) leaves just this.
+        let specular_color = specular_color(&self.color_at(intersection), metallic);
+        let fresnel = schlick(specular_color, local_wi.dot(&h));
+        Some(BsdfSample {
+            direction: UnitVector(normal.from_local(&local_wi)),
+            weight: fresnel * (ggx.g2(&local_wo, &local_wi) / ggx.g1(&local_wo)),
+            pdf: Some(ggx.reflection_pdf(&local_wo, &local_wi)),
+        })
+    }
+
+    // The fraction of light left after travelling the given distance through the medium behind
+    // the surface, following the Beer-Lambert law.
+    pub fn transmittance(&self, distance: f32) -> Color {
+        match self.surface {
+            Surface::Dielectric { absorption, .. } => Color {
+                r: (-absorption.r * distance).exp(),
+                g: (-absorption.g * distance).exp(),
+                b: (-absorption.b * distance).exp(),
+            },
+            _ => Color::new_white(),
         }
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+// Schlick's approximation of the Fresnel reflectance of a surface reflecting specular_color of
+// the light head on.
+fn schlick(specular_color: Color, cosine: f32) -> Color {
+    let m = 1.0 - cosine.clamp(0.0, 1.0);
+    specular_color + (Color::new_white() - specular_color) * (m * m * m * m * m)
+}
+
+// The fraction of light reflected off a smooth dielectric boundary, the rest is refracted
+// through it. cos_incident is the cosine of the angle between the incoming light and the normal,
+// eta the ratio of the index of refraction on the incoming side to the one on the other side.
+// Uses the exact Fresnel equations for unpolarized light, past the critical angle everything is
+// reflected (total internal reflection).
+pub fn fresnel(cos_incident: f32, eta: f32) -> f32 {
+    let cos_incident = cos_incident.clamp(0.0, 1.0);
+    let sin_transmitted_squared = eta * eta * (1.0 - cos_incident * cos_incident);
+    if sin_transmitted_squared >= 1.0 {
+        return 1.0;
+    }
+    let cos_transmitted = (1.0 - sin_transmitted_squared).sqrt();
+    let perpendicular =
+        (eta * cos_incident - cos_transmitted) / (eta * cos_incident + cos_transmitted);
+    let parallel = (cos_incident - eta * cos_transmitted) / (cos_incident + eta * cos_transmitted);
+    (perpendicular * perpendicular + parallel * parallel) / 2.0
+}
+
+// The fraction of light reflected head on by the surface of microfacet materials of the given
+// color.
+fn specular_color(color: &Color, metallic: f32) -> Color {
+    Color::new(0.04, 0.04, 0.04) * (1.0 - metallic) + *color * metallic
+}
+
+// How likely the glossy reflection of a microfacet material is to be sampled instead of the
+// diffuse base, following roughly how much light each of them reflects.
+fn specular_probability(color: &Color, cos_o: f32, metallic: f32) -> f32 {
+    let specular = schlick(specular_color(color, metallic), cos_o).max_component();
+    let diffuse = (1.0 - metallic) * color.max_component() * (1.0 - specular);
+    if specular + diffuse > 0.0 {
+        specular / (specular + diffuse)
+    } else {
+        1.0
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Color {
     pub r: f32,
     pub g: f32,
@@ -23,6 +323,12 @@ pub struct Color {
 }
 
 impl Color {
+    pub fn almost_equal_with_epsilon(&self, other: &Color, epsilon: f32) -> bool {
+        (self.r - other.r).abs() < epsilon
+            && (self.g - other.g).abs() < epsilon
+            && (self.b - other.b).abs() < epsilon
+    }
+
     pub fn new(r: f32, g: f32, b: f32) -> Color {
         Color { r, g, b }
     }
@@ -50,6 +356,41 @@ impl Color {
             b: 1.0,
         }
     }
+
+    pub fn max_component(&self) -> f32 {
+        self.r.max(self.g).max(self.b)
+    }
+
+    pub fn is_black(&self) -> bool {
+        self.r == 0.0 && self.g == 0.0 && self.b == 0.0
+    }
+
+    // Colors hold linear radiance which isn't limited to [0.0, 1.0], this brings it back into the
+    // displayable range.
+    pub fn clamped(&self) -> Color {
+        Color {
+            r: self.r.clamp(0.0, 1.0),
+            g: self.g.clamp(0.0, 1.0),
+            b: self.b.clamp(0.0, 1.0),
+        }
+    }
+
+    // Image files usually store sRGB encoded values, which spend more of their precision on dark
+    // colors than linear ones would. This undoes the encoding.
+    pub fn srgb_to_linear(&self) -> Color {
+        let decode = |value: f32| {
+            if value <= 0.04045 {
+                value / 12.92
+            } else {
+                ((value + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        Color {
+            r: decode(self.r),
+            g: decode(self.g),
+            b: decode(self.b),
+        }
+    }
 }
 
 impl AlmostEqual for Color {
@@ -64,22 +405,42 @@ impl Add for Color {
     type Output = Color;
 
     fn add(self, other: Color) -> Color {
-        // Adding two Colors together clamps the r, g and b values to [0.0, 1.0] range
         Color {
-            r: clamp(0.0, 1.0, self.r + other.r),
-            g: clamp(0.0, 1.0, self.g + other.g),
-            b: clamp(0.0, 1.0, self.b + other.b),
+            r: self.r + other.r,
+            g: self.g + other.g,
+            b: self.b + other.b,
         }
     }
 }
 
-fn clamp(min: f32, max: f32, value: f32) -> f32 {
-    if value < min {
-        min
-    } else if value > max {
-        max
-    } else {
-        value
+impl AddAssign for Color {
+    fn add_assign(&mut self, other: Color) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Color {
+    type Output = Color;
+
+    fn sub(self, other: Color) -> Color {
+        Color {
+            r: self.r - other.r,
+            g: self.g - other.g,
+            b: self.b - other.b,
+        }
+    }
+}
+
+// Componentwise multiplication, used to filter light by the color of the surface it hits.
+impl Mul for Color {
+    type Output = Color;
+
+    fn mul(self, other: Color) -> Color {
+        Color {
+            r: self.r * other.r,
+            g: self.g * other.g,
+            b: self.b * other.b,
+        }
     }
 }
 
@@ -87,10 +448,6 @@ impl Mul<f32> for Color {
     type Output = Color;
 
     fn mul(self, other: f32) -> Color {
-        // The floating point operand needs to be strictly within (0.0, 1.0) range, this is for
-        // simple scaling. May revisit later to do multiplication by values larger than 1.0 and
-        // clamping afterwards.
-        assert!(0.0 <= other && other <= 1.0);
         Color {
             r: self.r * other,
             g: self.g * other,
@@ -103,16 +460,26 @@ impl Mul<Color> for f32 {
     type Output = Color;
 
     fn mul(self, other: Color) -> Color {
-        // See impl Mul<f32> for Color comment.
-        assert!(0.0 <= self && self <= 1.0);
         other * self
     }
 }
 
+impl Div<f32> for Color {
+    type Output = Color;
+
+    fn div(self, other: f32) -> Color {
+        Color {
+            r: self.r / other,
+            g: self.g / other,
+            b: self.b / other,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::assert_almost_eq;
-    use crate::material::Color;
+    use crate::material::{fresnel, Color, Material, Surface};
     use crate::traits::AlmostEqual;
 
     #[test]
@@ -149,6 +516,85 @@ mod tests {
             Color::new_red() + Color::new_green() + Color::new_blue(),
             Color::new_white()
         );
-        assert_almost_eq!(Color::new_red() + Color::new_red(), Color::new_red());
+        assert_almost_eq!(
+            Color::new_red() + Color::new_red(),
+            Color::new(2.0, 0.0, 0.0)
+        );
+        let mut color = Color::new(0.5, 0.25, 0.0);
+        color += Color::new(1.0, 1.0, 1.0);
+        assert_almost_eq!(color, Color::new(1.5, 1.25, 1.0));
+        assert_almost_eq!(
+            Color::new(1.5, 1.0, 0.0) - Color::new_white(),
+            Color::new(0.5, 0.0, -1.0)
+        );
+    }
+
+    #[test]
+    fn test_color_hdr_operations() {
+        assert_almost_eq!(Color::new_white() * 4.0, Color::new(4.0, 4.0, 4.0));
+        assert_almost_eq!(Color::new(3.0, 1.5, 0.0) / 3.0, Color::new(1.0, 0.5, 0.0));
+        assert_almost_eq!(
+            Color::new(2.0, 1.0, 0.5) * Color::new(0.5, 0.0, 1.0),
+            Color::new(1.0, 0.0, 0.5)
+        );
+        assert_almost_eq!(
+            Color::new(2.0, -1.0, 0.5).clamped(),
+            Color::new(1.0, 0.0, 0.5)
+        );
+    }
+
+    #[test]
+    fn test_srgb_to_linear() {
+        assert_almost_eq!(Color::new_black().srgb_to_linear(), Color::new_black());
+        assert_almost_eq!(Color::new_white().srgb_to_linear(), Color::new_white());
+        // Mid gray in sRGB is about a fifth as bright, dark values are scaled linearly.
+        let color = Color::new(0.5, 0.02, 1.0).srgb_to_linear();
+        assert!((color.r - 0.214).abs() < 1e-3, "{}", color.r);
+        assert!((color.g - 0.02 / 12.92).abs() < 1e-6, "{}", color.g);
+    }
+
+    #[test]
+    fn test_fresnel() {
+        // Head on 4% of the light is reflected off glass, coming from either side.
+        assert!((fresnel(1.0, 1.0 / 1.5) - 0.04).abs() < 1e-6);
+        assert!((fresnel(1.0, 1.5) - 0.04).abs() < 1e-6);
+        // Everything is reflected at grazing angles.
+        assert!((fresnel(0.0, 1.0 / 1.5) - 1.0).abs() < 1e-6);
+        // Past the critical angle of about 41.8 degrees light can't leave the glass.
+        assert!(fresnel(40.0f32.to_radians().cos(), 1.5) < 1.0);
+        assert_eq!(fresnel(43.0f32.to_radians().cos(), 1.5), 1.0);
+        // Nothing is reflected between two media with the same index of refraction.
+        assert!(fresnel(0.5, 1.0) < 1e-7);
+        // Brewster's angle, no light polarized in the plane of incidence is reflected.
+        let brewster = 1.5f32.atan();
+        let sin_transmitted = brewster.sin() / 1.5;
+        let cos_transmitted = (1.0 - sin_transmitted * sin_transmitted).sqrt();
+        let perpendicular =
+            (brewster.cos() / 1.5 - cos_transmitted) / (brewster.cos() / 1.5 + cos_transmitted);
+        assert!(
+            (fresnel(brewster.cos(), 1.0 / 1.5) - perpendicular * perpendicular / 2.0).abs() < 1e-6
+        );
+    }
+
+    #[test]
+    fn test_transmittance() {
+        assert_almost_eq!(
+            Material::dielectric(1.5).transmittance(10.0),
+            Color::new_white()
+        );
+        assert_almost_eq!(
+            Material::diffuse(Color::new_red()).transmittance(10.0),
+            Color::new_white()
+        );
+        let colored = Material {
+            surface: Surface::Dielectric {
+                ior: 1.5,
+                absorption: Color::new(0.0, 1.0, 2.0),
+            },
+            ..Material::dielectric(1.5)
+        };
+        assert!(colored
+            .transmittance(0.5)
+            .almost_equal_with_epsilon(&Color::new(1.0, (-0.5f32).exp(), (-1.0f32).exp()), 1e-6));
     }
 }
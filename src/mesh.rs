@@ -0,0 +1,452 @@
+use crate::bvh::{Aabb, Bvh};
+use crate::material::Material;
+use crate::scene::{rounding_error, Intersection, Ray, Shape, UnitVector, Vector};
+
+// Intersects a ray with the triangle abc using the Möller–Trumbore algorithm, see
+// https://en.wikipedia.org/wiki/M%C3%B6ller%E2%80%93Trumbore_intersection_algorithm
+//
+// Returns the distance to the hit (within the ray's interval) and the barycentric coordinates u
+// and v of the hit point, which are the weights of b and c respectively (the weight of a is
+// 1 - u - v).
+fn intersect_triangle(ray: &Ray, a: &Vector, b: &Vector, c: &Vector) -> Option<(f32, f32, f32)> {
+    let edge1 = *b - *a;
+    let edge2 = *c - *a;
+    let p = ray.dir.0.cross(&edge2);
+    let determinant = edge1.dot(&p);
+    // The ray is parallel to the triangle (or the triangle is degenerate).
+    if determinant == 0.0 {
+        return None;
+    }
+    let inverse_determinant = 1.0 / determinant;
+    let s = ray.pos - *a;
+    let u = s.dot(&p) * inverse_determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(&edge1);
+    let v = ray.dir.0.dot(&q) * inverse_determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = edge2.dot(&q) * inverse_determinant;
+    if !ray.contains(t) {
+        return None;
+    }
+    Some((t, u, v))
+}
+
+// Computes the hit point from the barycentric coordinates rather than the distance along the ray,
+// that way the rounding error only depends on the triangle's vertices.
+fn triangle_point(a: &Vector, b: &Vector, c: &Vector, u: f32, v: f32) -> (Vector, f32) {
+    let position = (1.0 - u - v) * *a + u * *b + v * *c;
+    let error =
+        rounding_error(a.max_abs_component() + b.max_abs_component() + c.max_abs_component());
+    (position, error)
+}
+
+fn triangle_bounds(a: &Vector, b: &Vector, c: &Vector) -> Aabb {
+    Aabb::empty().including(a).including(b).including(c)
+}
+
+// Triangles can be hit from both sides, the normal we report always faces the side the ray came
+// from. The texture coordinates are the barycentric coordinates of the point hit, (0, 0) at a,
+// (1, 0) at b and (0, 1) at c.
+#[derive(Clone, Debug)]
+pub struct Triangle {
+    pub a: Vector,
+    pub b: Vector,
+    pub c: Vector,
+    pub material: Material,
+}
+
+impl Shape for Triangle {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let (t, u, v) = intersect_triangle(ray, &self.a, &self.b, &self.c)?;
+        let (position, position_error) = triangle_point(&self.a, &self.b, &self.c, u, v);
+        // The front face is the one the vertices appear counter-clockwise from.
+        let normal = (self.b - self.a).cross(&(self.c - self.a)).normalized();
+        let front_face = normal.0.dot(&ray.dir.0) <= 0.0;
+        let normal = if front_face { normal } else { -normal };
+        Some(Intersection {
+            position,
+            t,
+            normal,
+            geometric_normal: normal,
+            front_face,
+            position_error,
+            uv: (u, v),
+            time: ray.time,
+            object: self,
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(triangle_bounds(&self.a, &self.b, &self.c))
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+// A corner of a mesh face, referring to the mesh's vertex attributes by index.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MeshVertex {
+    pub position: usize,
+    pub normal: Option<usize>,
+    pub uv: Option<usize>,
+}
+
+// A triangle mesh with shared vertex attributes. Faces whose corners all have normals are smooth
+// shaded by interpolating them, the remaining ones are flat.
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    positions: Vec<Vector>,
+    normals: Vec<UnitVector>,
+    uvs: Vec<(f32, f32)>,
+    faces: Vec<[MeshVertex; 3]>,
+    material: Material,
+    bounds: Aabb,
+    bvh: Bvh,
+}
+
+impl Mesh {
+    // Panics if there are no faces or if a face refers to a nonexistent vertex attribute.
+    pub fn new(
+        positions: Vec<Vector>,
+        normals: Vec<UnitVector>,
+        uvs: Vec<(f32, f32)>,
+        faces: Vec<[MeshVertex; 3]>,
+        material: Material,
+    ) -> Mesh {
+        assert!(!faces.is_empty(), "A mesh needs at least one face");
+        for vertex in faces.iter().flatten() {
+            assert!(vertex.position < positions.len());
+            assert!(vertex.normal.is_none_or(|normal| normal < normals.len()));
+            assert!(vertex.uv.is_none_or(|uv| uv < uvs.len()));
+        }
+        let face_bounds: Vec<Aabb> = faces
+            .iter()
+            .map(|[a, b, c]| {
+                triangle_bounds(
+                    &positions[a.position],
+                    &positions[b.position],
+                    &positions[c.position],
+                )
+            })
+            .collect();
+        let bounds = face_bounds
+            .iter()
+            .fold(Aabb::empty(), |bounds, face| bounds.union(face));
+        let bvh = Bvh::from_bounds(face_bounds.into_iter().map(Some));
+        Mesh {
+            positions,
+            normals,
+            uvs,
+            faces,
+            material,
+            bounds,
+            bvh,
+        }
+    }
+
+    pub fn positions(&self) -> &[Vector] {
+        &self.positions
+    }
+
+    pub fn normals(&self) -> &[UnitVector] {
+        &self.normals
+    }
+
+    pub fn uvs(&self) -> &[(f32, f32)] {
+        &self.uvs
+    }
+
+    pub fn faces(&self) -> &[[MeshVertex; 3]] {
+        &self.faces
+    }
+
+    fn intersect_face(&self, index: usize, ray: &Ray) -> Option<Intersection<'_>> {
+        let [a, b, c] = &self.faces[index];
+        let (pa, pb, pc) = (
+            &self.positions[a.position],
+            &self.positions[b.position],
+            &self.positions[c.position],
+        );
+        let (t, u, v) = intersect_triangle(ray, pa, pb, pc)?;
+        let (position, position_error) = triangle_point(pa, pb, pc, u, v);
+        let mut geometric_normal = (*pb - *pa).cross(&(*pc - *pa));
+        let normal = match (a.normal, b.normal, c.normal) {
+            (Some(na), Some(nb), Some(nc)) => {
+                let normal = ((1.0 - u - v) * self.normals[na].0
+                    + u * self.normals[nb].0
+                    + v * self.normals[nc].0)
+                    .normalized();
+                // Vertex normals, if present, decide which side of the face is the outside, no
+                // matter the winding order.
+                if normal.0.dot(&geometric_normal) < 0.0 {
+                    geometric_normal = -geometric_normal;
+                }
+                normal
+            }
+            _ => geometric_normal.normalized(),
+        };
+        // The geometric normal tells us which side of the face got hit, the (possibly
+        // interpolated) normal is flipped accordingly.
+        let front_face = geometric_normal.dot(&ray.dir.0) <= 0.0;
+        // Faces without texture coordinates get the barycentric ones, like triangles.
+        let uv = match (a.uv, b.uv, c.uv) {
+            (Some(ta), Some(tb), Some(tc)) => {
+                let (ta, tb, tc) = (self.uvs[ta], self.uvs[tb], self.uvs[tc]);
+                (
+                    (1.0 - u - v) * ta.0 + u * tb.0 + v * tc.0,
+                    (1.0 - u - v) * ta.1 + u * tb.1 + v * tc.1,
+                )
+            }
+            _ => (u, v),
+        };
+        let geometric_normal = geometric_normal.normalized();
+        Some(Intersection {
+            position,
+            t,
+            normal: if front_face { normal } else { -normal },
+            geometric_normal: if front_face {
+                geometric_normal
+            } else {
+                -geometric_normal
+            },
+            front_face,
+            position_error,
+            uv,
+            time: ray.time,
+            object: self,
+        })
+    }
+}
+
+impl Shape for Mesh {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        self.bvh
+            .closest_hit(ray, |index, ray| self.intersect_face(index, ray))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_almost_eq;
+    use crate::material::Material;
+    use crate::mesh::{Mesh, MeshVertex, Triangle};
+    use crate::scene::{closest_intersection, Intersection, Motion, Ray, Shape, Sphere, Vector};
+    use crate::testing::vector;
+    use crate::traits::AlmostEqual;
+
+    #[test]
+    fn test_triangle_ray_intersection() {
+        let triangle = Triangle {
+            a: vector(0.0, 0.0, 0.0),
+            b: vector(1.0, 0.0, 0.0),
+            c: vector(0.0, 1.0, 0.0),
+            material: Material::dummy(),
+        };
+
+        let in_front = Ray::new(vector(0.25, 0.25, 5.0), -Vector::unitz());
+        assert_almost_eq!(
+            triangle.intersect_ray(&in_front),
+            Some(Intersection {
+                position: vector(0.25, 0.25, 0.0),
+                t: 5.0,
+                normal: Vector::unitz(),
+                geometric_normal: Vector::unitz(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.25, 0.25),
+                object: &triangle,
+                time: 0.0,
+            })
+        );
+
+        let behind = Ray::new(vector(0.25, 0.25, -5.0), Vector::unitz());
+        assert_almost_eq!(
+            triangle.intersect_ray(&behind),
+            Some(Intersection {
+                position: vector(0.25, 0.25, 0.0),
+                t: 5.0,
+                normal: -Vector::unitz(),
+                geometric_normal: -Vector::unitz(),
+                front_face: false,
+                position_error: 0.0,
+                uv: (0.25, 0.25),
+                object: &triangle,
+                time: 0.0,
+            })
+        );
+
+        let outside_edge = Ray::new(vector(0.75, 0.75, 5.0), -Vector::unitz());
+        assert_almost_eq!(triangle.intersect_ray(&outside_edge), None);
+
+        let parallel = Ray::new(vector(-1.0, 0.25, 0.0), Vector::unitx());
+        assert_almost_eq!(triangle.intersect_ray(&parallel), None);
+
+        let pointing_away = Ray::new(vector(0.25, 0.25, 5.0), Vector::unitz());
+        assert_almost_eq!(triangle.intersect_ray(&pointing_away), None);
+    }
+
+    #[test]
+    fn test_mesh_smooth_normals() {
+        let corner = |position, normal| MeshVertex {
+            position,
+            normal: Some(normal),
+            uv: None,
+        };
+        let mesh = Mesh::new(
+            vec![
+                vector(-1.0, 0.0, 0.0),
+                vector(1.0, 0.0, 0.0),
+                vector(0.0, 0.0, -1.0),
+                vector(0.0, 0.0, 1.0),
+            ],
+            vec![
+                vector(-1.0, 1.0, 0.0).normalized(),
+                vector(1.0, 1.0, 0.0).normalized(),
+            ],
+            vec![],
+            vec![
+                [corner(0, 0), corner(3, 0), corner(1, 1)],
+                [corner(0, 0), corner(1, 1), corner(2, 0)],
+            ],
+            Material::dummy(),
+        );
+        // Halfway between the vertices the normals get averaged.
+        let ray = Ray::new(vector(0.0, 5.0, 0.0), -Vector::unity());
+        let intersection = mesh.intersect_ray(&ray).unwrap();
+        assert_almost_eq!(intersection.position, Vector::zero());
+        assert_almost_eq!(intersection.normal, Vector::unity());
+        assert!(intersection.front_face);
+        // Hitting from below flips the normal.
+        let ray = Ray::new(vector(-0.5, -5.0, -0.25), Vector::unity());
+        let intersection = mesh.intersect_ray(&ray).unwrap();
+        assert_almost_eq!(intersection.position, vector(-0.5, 0.0, -0.25));
+        assert!(intersection.normal.0.y < 0.0);
+        assert!(intersection.normal.0.x > 0.0);
+        assert!(!intersection.front_face);
+    }
+
+    #[test]
+    fn test_smooth_mesh_secondary_rays_leave_the_surface() {
+        // A flat face far from the origin whose vertex normals lean heavily along x.
+        let corner = |position| MeshVertex {
+            position,
+            normal: Some(0),
+            uv: None,
+        };
+        let mesh = Mesh::new(
+            vec![
+                vector(900.0, 0.0, -100.0),
+                vector(1100.0, 0.0, -100.0),
+                vector(1000.0, 0.0, 100.0),
+            ],
+            vec![vector(1.0, 0.1, 0.0).normalized()],
+            vec![],
+            vec![[corner(0), corner(2), corner(1)]],
+            Material::dummy(),
+        );
+        let ray = Ray::new(vector(1000.0, 5.0, 0.0), -Vector::unity());
+        let intersection = mesh.intersect_ray(&ray).unwrap();
+        assert_almost_eq!(intersection.geometric_normal, Vector::unity());
+        assert!(intersection.front_face);
+        // Grazing away from the surface, but against the shading normal.
+        let dir = vector(-1.0, 0.05, 0.0).normalized();
+        assert!(dir.0.dot(&intersection.normal.0) < 0.0);
+        let spawned = intersection.spawn_ray(dir);
+        assert!(spawned.pos.y > 0.0);
+        assert!(mesh.intersect_ray(&spawned).is_none());
+    }
+
+    #[test]
+    fn test_texture_coordinates() {
+        let almost_equal = |(u, v): (f32, f32), (eu, ev): (f32, f32)| {
+            (u - eu).abs() < 1e-6 && (v - ev).abs() < 1e-6
+        };
+        let ray = |x, y| Ray::new(vector(x, y, 5.0), -Vector::unitz());
+        let triangle = Triangle {
+            a: vector(0.0, 0.0, 0.0),
+            b: vector(2.0, 0.0, 0.0),
+            c: vector(0.0, 2.0, 0.0),
+            material: Material::dummy(),
+        };
+        let uv = triangle.intersect_ray(&ray(0.5, 1.0)).unwrap().uv;
+        assert!(almost_equal(uv, (0.25, 0.5)), "{:?}", uv);
+
+        // A square with a texture stretched over it twice along y, all but the second face have
+        // texture coordinates.
+        let corner = |position, uv| MeshVertex {
+            position,
+            normal: None,
+            uv,
+        };
+        let mesh = Mesh::new(
+            vec![
+                vector(-1.0, -1.0, 0.0),
+                vector(1.0, -1.0, 0.0),
+                vector(1.0, 1.0, 0.0),
+                vector(-1.0, 1.0, 0.0),
+            ],
+            vec![],
+            vec![(0.0, 0.0), (1.0, 0.0), (1.0, 2.0), (0.0, 2.0)],
+            vec![
+                [corner(0, Some(0)), corner(1, Some(1)), corner(2, Some(2))],
+                [corner(0, Some(0)), corner(2, None), corner(3, Some(3))],
+            ],
+            Material::dummy(),
+        );
+        let uv = mesh.intersect_ray(&ray(0.5, -0.5)).unwrap().uv;
+        assert!(almost_equal(uv, (0.75, 0.5)), "{:?}", uv);
+        let uv = mesh.intersect_ray(&ray(-0.5, 0.5)).unwrap().uv;
+        assert!(almost_equal(uv, (0.25, 0.5)), "{:?}", uv);
+    }
+
+    #[test]
+    fn test_mesh_with_other_shapes() {
+        let flat = |position| MeshVertex {
+            position,
+            normal: None,
+            uv: None,
+        };
+        // A unit square at z = -2 made of two triangles, partially hidden by a sphere.
+        let shapes: Vec<Box<dyn Shape>> = vec![
+            Box::new(Mesh::new(
+                vec![
+                    vector(-1.0, -1.0, -2.0),
+                    vector(1.0, -1.0, -2.0),
+                    vector(1.0, 1.0, -2.0),
+                    vector(-1.0, 1.0, -2.0),
+                ],
+                vec![],
+                vec![],
+                vec![[flat(0), flat(1), flat(2)], [flat(0), flat(2), flat(3)]],
+                Material::dummy(),
+            )),
+            Box::new(Sphere {
+                center: vector(0.5, 0.5, 0.0),
+                radius: 0.25,
+                material: Material::dummy(),
+                motion: Motion::default(),
+            }),
+        ];
+        let ray = |x, y| Ray::new(vector(x, y, 5.0), -Vector::unitz());
+        let hit = closest_intersection(&shapes, &ray(-0.5, 0.5)).unwrap();
+        assert_almost_eq!(hit.position, vector(-0.5, 0.5, -2.0));
+        assert_almost_eq!(hit.normal, Vector::unitz());
+        let hit = closest_intersection(&shapes, &ray(0.5, 0.5)).unwrap();
+        assert_almost_eq!(hit.position, vector(0.5, 0.5, 0.25));
+        assert_almost_eq!(closest_intersection(&shapes, &ray(1.5, 0.5)), None);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::scene::Vector;
+use std::f32;
+
+// Perfectly smooth surfaces would need a distribution that's infinitely narrow, this keeps the
+// numbers finite while being indistinguishable from a mirror.
+const MIN_ALPHA: f32 = 1e-3;
+
+// The GGX (Trowbridge-Reitz) distribution of microfacet normals on a rough surface, together with
+// the Smith model of how the microfacets mask and shadow each other. See "Microfacet Models for
+// Refraction through Rough Surfaces" by Walter et al. and "Understanding the Masking-Shadowing
+// Function in Microfacet-Based BRDFs" by Heitz.
+//
+// All directions are given in a local frame with the (macro) surface normal along z.
+#[derive(Copy, Clone, Debug)]
+pub struct Ggx {
+    pub alpha: f32,
+}
+
+impl Ggx {
+    // Squaring the roughness makes it look perceptually linear.
+    pub fn from_roughness(roughness: f32) -> Ggx {
+        Ggx {
+            alpha: (roughness * roughness).max(MIN_ALPHA),
+        }
+    }
+
+    // The density of microfacets with normal h, per unit of the surface's projected area.
+    pub fn d(&self, h: &Vector) -> f32 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let alpha_squared = self.alpha * self.alpha;
+        let denominator = h.z * h.z * (alpha_squared - 1.0) + 1.0;
+        alpha_squared / (f32::consts::PI * denominator * denominator)
+    }
+
+    fn lambda(&self, w: &Vector) -> f32 {
+        let cos_squared = w.z * w.z;
+        if cos_squared >= 1.0 {
+            return 0.0;
+        }
+        let tan_squared = (1.0 - cos_squared) / cos_squared;
+        ((1.0 + self.alpha * self.alpha * tan_squared).sqrt() - 1.0) / 2.0
+    }
+
+    // The fraction of microfacets visible from direction w.
+    pub fn g1(&self, w: &Vector) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // The fraction of microfacets visible from both directions. Microfacets high up on the
+    // surface are more likely to be visible from either, so the two aren't independent
+    // (height-correlated masking-shadowing).
+    pub fn g2(&self, wo: &Vector, wi: &Vector) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Picks a microfacet normal among the ones visible from wo, proportionally to their visible
+    // area. Unlike sampling the distribution itself this never produces normals facing away from
+    // wo, which keeps the variance low. See "Sampling the GGX Distribution of Visible Normals" by
+    // Heitz.
+    pub fn sample_visible_normal(&self, wo: &Vector, u: f32, v: f32) -> Vector {
+        // Stretching the view direction turns the problem into sampling the visible half of a
+        // hemisphere of microfacets.
+        let view = Vector {
+            x: self.alpha * wo.x,
+            y: self.alpha * wo.y,
+            z: wo.z,
+        }
+        .normalized()
+        .0;
+        let length_squared = view.x * view.x + view.y * view.y;
+        let t1 = if length_squared > 0.0 {
+            Vector {
+                x: -view.y,
+                y: view.x,
+                z: 0.0,
+            } / length_squared.sqrt()
+        } else {
+            Vector::unitx().0
+        };
+        let t2 = view.cross(&t1);
+        // A point on a disk, its lower half squeezed to match the projected area of the visible
+        // hemisphere.
+        let radius = u.sqrt();
+        let angle = 2.0 * f32::consts::PI * v;
+        let p1 = radius * angle.cos();
+        let p2 = radius * angle.sin();
+        let s = (1.0 + view.z) / 2.0;
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * p2;
+        let normal = t1 * p1 + t2 * p2 + view * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        // Unstretching it.
+        Vector {
+            x: self.alpha * normal.x,
+            y: self.alpha * normal.y,
+            z: normal.z.max(0.0),
+        }
+        .normalized()
+        .0
+    }
+
+    // The probability density of getting wi by reflecting wo off a normal picked with
+    // sample_visible_normal.
+    pub fn reflection_pdf(&self, wo: &Vector, wi: &Vector) -> f32 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let h = (*wo + *wi).normalized().0;
+        // The density of the visible normal, G1(wo) * (wo . h) * D(h) / wo.z, times the Jacobian
+        // of the reflection, 1 / (4 * (wo . h)).
+        self.g1(wo) * self.d(&h) / (4.0 * wo.z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::microfacet::Ggx;
+    use crate::sampler::Sampler;
+    use crate::scene::Vector;
+    use std::f32;
+
+    fn uniform_hemisphere(u: f32, v: f32) -> Vector {
+        let z = u;
+        let radius = (1.0 - z * z).max(0.0).sqrt();
+        let angle = 2.0 * f32::consts::PI * v;
+        Vector {
+            x: radius * angle.cos(),
+            y: radius * angle.sin(),
+            z,
+        }
+    }
+
+    #[test]
+    fn test_distribution() {
+        for &roughness in &[0.3, 0.6, 1.0] {
+            let ggx = Ggx::from_roughness(roughness);
+            // The projected area of all microfacets adds up to that of the surface. D only
+            // depends on the angle to the normal so integrating over that is enough.
+            let steps = 100000;
+            let step = f32::consts::FRAC_PI_2 / steps as f32;
+            let mut area = 0.0;
+            for i in 0..steps {
+                let angle = (i as f32 + 0.5) * step;
+                let h = Vector {
+                    x: angle.sin(),
+                    y: 0.0,
+                    z: angle.cos(),
+                };
+                area += ggx.d(&h) * h.z * h.x * 2.0 * f32::consts::PI * step;
+            }
+            assert!((area - 1.0).abs() < 1e-3, "{}: {}", roughness, area);
+        }
+        // Everything is visible head on.
+        let ggx = Ggx::from_roughness(0.5);
+        assert_eq!(ggx.g1(&Vector::unitz().0), 1.0);
+        let grazing = Vector {
+            x: 1.0,
+            y: 0.0,
+            z: 0.01,
+        }
+        .normalized()
+        .0;
+        assert!(ggx.g1(&grazing) < 0.2);
+        assert!(ggx.g2(&grazing, &grazing) < ggx.g1(&grazing));
+    }
+
+    #[test]
+    fn test_visible_normal_sampling() {
+        let ggx = Ggx::from_roughness(0.5);
+        let wo = Vector {
+            x: 0.6,
+            y: -0.2,
+            z: 0.5,
+        }
+        .normalized()
+        .0;
+        let mut sampler = Sampler::new(1, 0);
+        let count = 100000;
+        // The sampled reflections have the density reflection_pdf says, so integrating a
+        // function over them gives the same as integrating it over uniformly picked
+        // directions. The function here is the fraction of light a white metal reflects, which
+        // can't be more than 1.
+        let mut sampled = 0.0;
+        let mut uniform = 0.0;
+        for _ in 0..count {
+            let h = ggx.sample_visible_normal(&wo, sampler.next_f32(), sampler.next_f32());
+            assert!(h.z >= 0.0 && h.dot(&wo) >= 0.0);
+            let wi = h * (2.0 * wo.dot(&h)) - wo;
+            if wi.z > 0.0 {
+                let pdf = ggx.reflection_pdf(&wo, &wi);
+                let h = (wo + wi).normalized().0;
+                sampled += ggx.d(&h) * ggx.g2(&wo, &wi) / (4.0 * wo.z) / pdf;
+            }
+
+            let wi = uniform_hemisphere(sampler.next_f32(), sampler.next_f32());
+            let h = (wo + wi).normalized().0;
+            uniform += ggx.d(&h) * ggx.g2(&wo, &wi) / (4.0 * wo.z) * 2.0 * f32::consts::PI;
+        }
+        let sampled = sampled / count as f32;
+        let uniform = uniform / count as f32;
+        assert!(sampled > 0.8 && sampled <= 1.0, "{}", sampled);
+        assert!((sampled - uniform).abs() < 0.02, "{} {}", sampled, uniform);
+    }
+}
@@ -0,0 +1,277 @@
+use crate::material::Material;
+use crate::mesh::{Mesh, MeshVertex};
+use crate::scene::{split_words, ParseError, UnitVector, Vector, Word};
+
+// Loads a mesh from a Wavefront OBJ file, see http://paulbourke.net/dataformats/obj/
+//
+// Only vertex positions (v), normals (vn), texture coordinates (vt) and faces (f) are used, all
+// the other statements (groups, smoothing groups, material libraries etc.) are ignored. Polygons
+// with more than three vertices are split into triangle fans, which works for the convex
+// polygons modelling tools export. Errors are reported with the statement keyword as the key.
+pub fn load_obj(source: &str, material: Material) -> Result<Mesh, ParseError> {
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut faces = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let words = split_words(line);
+        let (keyword, values) = match words.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+        let statement = Statement {
+            line: index + 1,
+            keyword,
+            values,
+        };
+        match keyword.text {
+            "v" => {
+                // There may be an optional fourth (w) coordinate that we don't need.
+                let [x, y, z] = statement.numbers::<3>(3, 4)?;
+                positions.push(Vector { x, y, z });
+            }
+            "vn" => {
+                let [x, y, z] = statement.numbers::<3>(3, 3)?;
+                let normal = Vector { x, y, z };
+                if normal.len() == 0.0 {
+                    return Err(statement.error(keyword, "normals cannot be zero length"));
+                }
+                normals.push(normal.normalized());
+            }
+            "vt" => {
+                // The v coordinate is optional and there's an optional w we ignore.
+                let [u, v] = statement.numbers::<2>(1, 3)?;
+                uvs.push((u, v));
+            }
+            "f" => {
+                if values.len() < 3 {
+                    return Err(statement.error(keyword, "faces need at least 3 vertices"));
+                }
+                let vertices = values
+                    .iter()
+                    .map(|value| statement.face_vertex(value, &positions, &normals, &uvs))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for i in 1..vertices.len() - 1 {
+                    faces.push([vertices[0], vertices[i], vertices[i + 1]]);
+                }
+            }
+            _ => (),
+        }
+    }
+    if faces.is_empty() {
+        return Err(ParseError {
+            line: source.lines().count().max(1),
+            column: 1,
+            key: None,
+            message: "the file contains no faces".to_string(),
+        });
+    }
+    Ok(Mesh::new(positions, normals, uvs, faces, material))
+}
+
+struct Statement<'a, 'b> {
+    line: usize,
+    keyword: &'b Word<'a>,
+    values: &'b [Word<'a>],
+}
+
+impl<'a, 'b> Statement<'a, 'b> {
+    fn error(&self, word: &Word, message: &str) -> ParseError {
+        ParseError {
+            key: Some(self.keyword.text.to_string()),
+            ..word.error(self.line, message)
+        }
+    }
+
+    // Parses between min and max numbers, the first N of which are returned (missing ones are 0).
+    fn numbers<const N: usize>(&self, min: usize, max: usize) -> Result<[f32; N], ParseError> {
+        if self.values.len() < min || self.values.len() > max {
+            let message = if min == max {
+                format!("expected {} numbers, got {}", min, self.values.len())
+            } else {
+                format!(
+                    "expected {} to {} numbers, got {}",
+                    min,
+                    max,
+                    self.values.len()
+                )
+            };
+            return Err(self.error(self.values.get(max).unwrap_or(self.keyword), &message));
+        }
+        let mut numbers = [0.0; N];
+        for (number, word) in numbers.iter_mut().zip(self.values) {
+            *number = match word.text.parse::<f32>() {
+                Ok(value) if value.is_finite() => value,
+                _ => {
+                    return Err(
+                        self.error(word, &format!("expected a number, got \"{}\"", word.text))
+                    )
+                }
+            };
+        }
+        Ok(numbers)
+    }
+
+    // Face vertices look like "position", "position/uv", "position//normal" or
+    // "position/uv/normal". Indices start at 1, negative ones count back from the most recently
+    // defined element (-1 being the last one).
+    fn face_vertex(
+        &self,
+        word: &Word,
+        positions: &[Vector],
+        normals: &[UnitVector],
+        uvs: &[(f32, f32)],
+    ) -> Result<MeshVertex, ParseError> {
+        let parts: Vec<&str> = word.text.split('/').collect();
+        let (position, uv, normal) = match parts.as_slice() {
+            [position] => (*position, "", ""),
+            [position, uv] => (*position, *uv, ""),
+            [position, uv, normal] => (*position, *uv, *normal),
+            _ => return Err(self.error(word, &format!("invalid face vertex \"{}\"", word.text))),
+        };
+        let resolve = |text: &str, count: usize, what: &str| -> Result<usize, ParseError> {
+            let invalid = || {
+                self.error(
+                    word,
+                    &format!("invalid {} index \"{}\" ({} defined)", what, text, count),
+                )
+            };
+            let index = text.parse::<isize>().map_err(|_| invalid())?;
+            let resolved = if index > 0 {
+                index - 1
+            } else {
+                count as isize + index
+            };
+            if index == 0 || resolved < 0 || resolved >= count as isize {
+                return Err(invalid());
+            }
+            Ok(resolved as usize)
+        };
+        Ok(MeshVertex {
+            position: resolve(position, positions.len(), "position")?,
+            uv: match uv {
+                "" => None,
+                uv => Some(resolve(uv, uvs.len(), "texture coordinate")?),
+            },
+            normal: match normal {
+                "" => None,
+                normal => Some(resolve(normal, normals.len(), "normal")?),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_almost_eq;
+    use crate::material::Material;
+    use crate::mesh::MeshVertex;
+    use crate::obj::load_obj;
+    use crate::scene::{ParseError, Ray, Shape, Vector};
+    use crate::traits::AlmostEqual;
+
+    #[test]
+    fn test_load_obj() {
+        let mesh = load_obj(
+            "# A square and a triangle
+            o square
+            v -1 -1 0
+            v 1 -1 0
+            v 1 1 0 1.0
+            v -1 1 0
+            vt 0 0
+            vt 1 0 0
+            vt 1
+            vn 0 0 2
+            s off
+            f 1/1/1 2/2/1 3/3/1 4//1
+            v 0 0 -1
+            v 1 0 -1
+            v 0 1 -1
+            f -3 -2 -1
+            ",
+            Material::dummy(),
+        )
+        .unwrap();
+        assert_eq!(mesh.positions().len(), 7);
+        assert_eq!(mesh.uvs(), &[(0.0, 0.0), (1.0, 0.0), (1.0, 0.0)]);
+        assert_eq!(mesh.normals().len(), 1);
+        assert_almost_eq!(mesh.normals()[0], Vector::unitz());
+        let vertex = |position, uv, normal| MeshVertex {
+            position,
+            uv,
+            normal,
+        };
+        assert_eq!(
+            mesh.faces(),
+            &[
+                [
+                    vertex(0, Some(0), Some(0)),
+                    vertex(1, Some(1), Some(0)),
+                    vertex(2, Some(2), Some(0)),
+                ],
+                [
+                    vertex(0, Some(0), Some(0)),
+                    vertex(2, Some(2), Some(0)),
+                    vertex(3, None, Some(0)),
+                ],
+                [
+                    vertex(4, None, None),
+                    vertex(5, None, None),
+                    vertex(6, None, None)
+                ],
+            ]
+        );
+
+        let ray = Ray::new(
+            Vector {
+                x: -0.5,
+                y: 0.5,
+                z: 5.0,
+            },
+            -Vector::unitz(),
+        );
+        assert_almost_eq!(
+            mesh.intersect_ray(&ray).unwrap().position,
+            Vector {
+                x: -0.5,
+                y: 0.5,
+                z: 0.0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_load_obj_errors() {
+        fn error(source: &str) -> (usize, usize, Option<String>) {
+            let ParseError {
+                line, column, key, ..
+            } = load_obj(source, Material::dummy()).err().unwrap();
+            (line, column, key)
+        }
+        assert_eq!(error("v 1 2"), (1, 1, Some("v".to_string())));
+        assert_eq!(error("v 1 2 x"), (1, 7, Some("v".to_string())));
+        assert_eq!(error("v 1 2 3 4 5"), (1, 11, Some("v".to_string())));
+        assert_eq!(
+            error("v 0 0 0\nv 1 0 0\nf 1 2"),
+            (3, 1, Some("f".to_string()))
+        );
+        assert_eq!(
+            error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4"),
+            (4, 7, Some("f".to_string()))
+        );
+        assert_eq!(
+            error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 -4 3"),
+            (4, 5, Some("f".to_string()))
+        );
+        assert_eq!(
+            error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2"),
+            (4, 3, Some("f".to_string()))
+        );
+        assert_eq!(
+            error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1//1 2 3"),
+            (4, 3, Some("f".to_string()))
+        );
+        assert_eq!(error("v 0 0 0\n"), (1, 1, None));
+    }
+}
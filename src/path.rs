@@ -0,0 +1,366 @@
+use crate::integrator::Integrator;
+use crate::material::Color;
+use crate::sampler::Sampler;
+use crate::scene::{Intersection, Ray, Scene, UnitVector, Vector};
+use std::f32;
+use std::ptr;
+
+// Paths are never cut short by Russian roulette before this many bounces.
+const ROULETTE_MIN_BOUNCES: usize = 3;
+
+// Estimates the light arriving along rays with Monte Carlo path tracing: the path is extended with
+// a randomly chosen direction at every surface it hits, collecting light from emissive surfaces
+// and from the scene's lights along the way. Averaged over many samples this converges to the
+// global illumination of the scene.
+//
+// Light from emissive shapes that can be sampled (like spheres) is found two ways at every
+// vertex: by aiming a ray at a random one and by running into them with the next path segment.
+// Each estimate is weighted by how likely it was to find the light the other way (multiple
+// importance sampling, see Veach's thesis, chapter 9), small bright lights are then found
+// reliably by the former and big ones by the latter. The scene's lights are infinitely small so
+// they can only be aimed at.
+//
+// Paths end after max_bounces bounces or earlier, randomly, when little light could still be
+// carried along them (Russian roulette). Paths that survive the roulette are weighted up so the
+// result stays unbiased.
+#[derive(Copy, Clone, Debug)]
+pub struct PathTracer {
+    pub max_bounces: usize,
+}
+
+impl Integrator for PathTracer {
+    fn li(&self, scene: &Scene, ray: &Ray, sampler: &mut Sampler) -> Color {
+        let mut radiance = Color::new_black();
+        // How much of the light arriving at the current vertex makes it back to the camera.
+        let mut throughput = Color::new_white();
+        let mut ray = *ray;
+        // Where the current path segment starts and the probability density of its direction,
+        // None for camera rays and ones leaving specular surfaces (which can't be aimed at
+        // lights).
+        let mut previous: Option<(Vector, f32)> = None;
+        for bounce in 0..=self.max_bounces {
+            let intersection = match scene.closest_intersection(&ray) {
+                Some(intersection) => intersection,
+                None => break,
+            };
+            let material = intersection.object.material();
+            // The light reaching the back of a surface has travelled through the medium behind it.
+            if !intersection.front_face {
+                throughput = throughput * material.transmittance(intersection.t);
+            }
+            if !material.emission.is_black() {
+                let weight = match previous {
+                    None => 1.0,
+                    Some((origin, pdf)) => {
+                        let light_pdf = scene.emitter_probability(intersection.object)
+                            * intersection
+                                .object
+                                .solid_angle_pdf(&origin, ray.time, &ray.dir);
+                        power_heuristic(pdf, light_pdf)
+                    }
+                };
+                radiance += throughput * material.emission * weight;
+            }
+            // The last vertex has no next segment to find emissive shapes with.
+            let last = bounce == self.max_bounces;
+            let wo = -ray.dir;
+            if !material.is_specular() {
+                radiance += throughput
+                    * (scene.direct_lighting(&intersection, &wo)
+                        + sample_emitter(scene, &intersection, &wo, last, sampler));
+            }
+            if last {
+                break;
+            }
+
+            let sample = match material.sample(&intersection, &wo, sampler) {
+                Some(sample) => sample,
+                None => break,
+            };
+            throughput = throughput * sample.weight;
+            if bounce >= ROULETTE_MIN_BOUNCES {
+                let survival = throughput.max_component().min(0.95);
+                if sampler.next_f32() >= survival {
+                    break;
+                }
+                throughput = throughput / survival;
+            }
+            if throughput.is_black() {
+                break;
+            }
+            previous = sample.pdf.map(|pdf| (intersection.position, pdf));
+            ray = intersection.spawn_ray(sample.direction);
+        }
+        radiance
+    }
+}
+
+// Aims at a random emissive shape and returns the light arriving from it scattered towards wo,
+// like Scene::direct_lighting, black when it's blocked or can't be sampled. Unless only_strategy
+// is set the result is weighted against the chance of running into the shape with a path segment
+// sampled from the surface's BSDF instead.
+fn sample_emitter(
+    scene: &Scene,
+    intersection: &Intersection,
+    wo: &UnitVector,
+    only_strategy: bool,
+    sampler: &mut Sampler,
+) -> Color {
+    let emitter = match scene.pick_emitter(sampler.next_f32()) {
+        Some(emitter) => emitter,
+        None => return Color::new_black(),
+    };
+    let point = &intersection.position;
+    let time = intersection.time;
+    let direction =
+        match emitter.sample_solid_angle(point, time, sampler.next_f32(), sampler.next_f32()) {
+            Some(direction) => direction,
+            None => return Color::new_black(),
+        };
+    let normal = &intersection.normal;
+    let cosine = direction.0.dot(&normal.0);
+    if cosine <= 0.0 {
+        return Color::new_black();
+    }
+    let material = intersection.object.material();
+    let bsdf = material.eval(intersection, wo, &direction);
+    if bsdf.is_black() {
+        return Color::new_black();
+    }
+    match scene.closest_intersection(&intersection.spawn_ray(direction)) {
+        Some(hit) if ptr::addr_eq(hit.object, emitter) => {
+            let pdf = scene.emitter_probability(emitter)
+                * emitter.solid_angle_pdf(point, time, &direction);
+            if pdf == 0.0 {
+                return Color::new_black();
+            }
+            let weight = if only_strategy {
+                1.0
+            } else {
+                power_heuristic(pdf, material.pdf(intersection, wo, &direction))
+            };
+            emitter.material().emission * bsdf * (cosine * weight / pdf)
+        }
+        _ => Color::new_black(),
+    }
+}
+
+// The weight of a sample taken with a strategy that has probability density pdf for it, when
+// another strategy with density other_pdf could have produced it too.
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b == 0.0 {
+        0.0
+    } else {
+        a / (a + b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::integrator::Integrator;
+    use crate::light::PointLight;
+    use crate::material::{Color, Material, Surface};
+    use crate::path::PathTracer;
+    use crate::sampler::Sampler;
+    use crate::scene::{Motion, Plane, Ray, Scene, Sphere, Vector};
+    use crate::testing::scene_with;
+    use std::f32;
+
+    // Averages the radiance of many paths starting at the center of the scene.
+    fn mean_radiance(scene: &Scene, max_bounces: usize) -> Color {
+        let mut sampler = Sampler::new(5, 0);
+        let count = 4000;
+        let mut sum = Color::new_black();
+        for i in 0..count {
+            let direction = Vector {
+                x: (i % 7) as f32 - 2.5,
+                y: (i % 5) as f32 - 1.5,
+                z: (i % 3) as f32 - 0.5,
+            };
+            let ray = Ray::new(Vector::zero(), direction.normalized());
+            sum += PathTracer { max_bounces }.li(scene, &ray, &mut sampler);
+        }
+        sum / count as f32
+    }
+
+    #[test]
+    fn test_furnace() {
+        // Inside a closed emissive sphere reflecting a fraction a of the light every point
+        // receives the emission e bounced any number of times: e * (1 + a + a^2 + ...), which is
+        // e / (1 - a). That only holds if the integrator conserves energy.
+        let albedo = 0.5;
+        let enclosure = Sphere {
+            center: Vector::zero(),
+            radius: 10.0,
+            material: Material {
+                emission: Color::new_white(),
+                ..Material::diffuse(Color::new(albedo, albedo, albedo))
+            },
+            motion: Motion::default(),
+        };
+        let scene = scene_with(vec![Box::new(enclosure)], vec![]);
+        let expected = 1.0 / (1.0 - albedo);
+        let radiance = mean_radiance(&scene, 100);
+        assert!((radiance.r - expected).abs() < 0.05, "{:?}", radiance);
+        assert_eq!(radiance.r, radiance.g);
+
+        // Without bounces only the emission is seen.
+        let radiance = mean_radiance(&scene, 0);
+        assert!((radiance.r - 1.0).abs() < 1e-5, "{:?}", radiance);
+    }
+
+    #[test]
+    fn test_microfacet_furnace() {
+        // A white metal reflects all the light arriving at it when perfectly smooth, so every
+        // bounce adds the emission once more. Rough surfaces lose some of it to microfacets
+        // shadowing each other and plastics to light bouncing back at the coating, but none of
+        // them may ever reflect more than arrives.
+        let furnace = |roughness: f32, metallic: f32| {
+            let enclosure = Sphere {
+                center: Vector::zero(),
+                radius: 10.0,
+                material: Material {
+                    emission: Color::new_white(),
+                    ..Material::microfacet(Color::new_white(), roughness, metallic)
+                },
+                motion: Motion::default(),
+            };
+            let scene = scene_with(vec![Box::new(enclosure)], vec![]);
+            mean_radiance(&scene, 4).r
+        };
+        for &(roughness, metallic, min) in &[(0.0, 1.0, 4.95), (0.5, 1.0, 3.9), (0.5, 0.0, 3.9)] {
+            let radiance = furnace(roughness, metallic);
+            assert!(radiance > min && radiance < 5.05, "{}", radiance);
+        }
+    }
+
+    #[test]
+    fn test_direct_lighting() {
+        // A white Lambertian sphere seen from the inside, lit by a point light in its center: the
+        // irradiance is I / r^2 everywhere, the radiance leaving the wall towards the center
+        // is that times color / pi, and without further bounces that's all.
+        let enclosure = Sphere {
+            center: Vector::zero(),
+            radius: 2.0,
+            material: Material::diffuse(Color::new(0.5, 0.5, 0.5)),
+            motion: Motion::default(),
+        };
+        let light = PointLight {
+            position: Vector::zero(),
+            color: Color::new_white(),
+            intensity: 8.0,
+        };
+        let scene = scene_with(vec![Box::new(enclosure)], vec![Box::new(light)]);
+        let direct = 0.5 * 8.0 / 4.0 / f32::consts::PI;
+        let radiance = mean_radiance(&scene, 0);
+        assert!((radiance.r - direct).abs() < 1e-5, "{:?}", radiance);
+        // Every bounce adds the same amount again, times the albedo.
+        let radiance = mean_radiance(&scene, 100);
+        assert!(
+            (radiance.r - 2.0 * direct).abs() < 0.05 * direct,
+            "{:?}",
+            radiance
+        );
+    }
+
+    #[test]
+    fn test_area_lights() {
+        // An emissive sphere hanging right above a point on a floor. Seen from there it covers a
+        // cone with sin^2 of its half angle being (r / d)^2, the irradiance it gives is
+        // pi * L * (r / d)^2 and the floor reflects color / pi of that.
+        let radiance_below = |radius: f32, height: f32| {
+            let floor = Plane {
+                point: Vector::zero(),
+                normal: Vector::unity(),
+                material: Material::diffuse(Color::new(0.5, 0.5, 0.5)),
+            };
+            let light = Sphere {
+                center: Vector {
+                    x: 0.0,
+                    y: height,
+                    z: 0.0,
+                },
+                radius,
+                material: Material {
+                    emission: Color::new(10.0, 10.0, 10.0),
+                    ..Material::dummy()
+                },
+                motion: Motion::default(),
+            };
+            let scene = scene_with(vec![Box::new(floor), Box::new(light)], vec![]);
+            let ray = Ray::new(
+                Vector {
+                    x: 0.0,
+                    y: 0.1,
+                    z: 0.0,
+                },
+                -Vector::unity(),
+            );
+            let mut sampler = Sampler::new(9, 0);
+            let count = 4000;
+            let mut sum = Color::new_black();
+            for _ in 0..count {
+                sum += PathTracer { max_bounces: 1 }.li(&scene, &ray, &mut sampler);
+            }
+            (sum / count as f32).r
+        };
+        for &(radius, height) in &[(0.05f32, 4.0), (1.0, 3.0), (1.5, 2.0)] {
+            let expected = 0.5 * 10.0 * (radius / height).powi(2);
+            let radiance = radiance_below(radius, height);
+            assert!(
+                (radiance - expected).abs() < 0.03 * expected,
+                "radius {}, height {}: {} instead of {}",
+                radius,
+                height,
+                radiance,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_dielectric() {
+        // A glass ball inside a glowing enclosure, every path from its center ends up at the
+        // enclosure so all light there arrives no matter how it's reflected and refracted on the
+        // way. Head on 4% of the light is reflected back inside, absorption dims the light by
+        // e^(-a * 2) for every trip from the center to the surface (or through the ball).
+        let scene = |absorption: f32| {
+            let glass = Sphere {
+                center: Vector::zero(),
+                radius: 2.0,
+                material: Material {
+                    surface: Surface::Dielectric {
+                        ior: 1.5,
+                        absorption: Color::new(absorption, absorption, absorption),
+                    },
+                    ..Material::dielectric(1.5)
+                },
+                motion: Motion::default(),
+            };
+            let enclosure = Sphere {
+                center: Vector::zero(),
+                radius: 10.0,
+                material: Material {
+                    emission: Color::new_white(),
+                    ..Material::dummy()
+                },
+                motion: Motion::default(),
+            };
+            scene_with(vec![Box::new(glass), Box::new(enclosure)], vec![])
+        };
+        let radiance = mean_radiance(&scene(0.0), 20);
+        assert!((radiance.r - 1.0).abs() < 0.02, "{:?}", radiance);
+
+        let a = 0.5f32;
+        let trip = (-2.0 * a).exp();
+        let expected = trip * 0.96 / (1.0 - 0.04 * trip * trip);
+        let radiance = mean_radiance(&scene(a), 20);
+        assert!(
+            (radiance.r - expected).abs() < 0.03 * expected,
+            "{} instead of {}",
+            radiance.r,
+            expected
+        );
+    }
+}
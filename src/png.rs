@@ -0,0 +1,915 @@
+// A minimal PNG encoder and decoder along with the zlib/deflate compression they need, see
+// https://www.w3.org/TR/PNG/ and https://tools.ietf.org/html/rfc1950 and rfc1951.
+use std::io::Write;
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 is the largest number of bytes that can be summed up before b could overflow.
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+// Writes bits the way deflate expects them: starting with the least significant bit of every
+// byte.
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            buffer: 0,
+            bits: 0,
+        }
+    }
+
+    // Writes the count lowest bits of value, least significant first.
+    fn write(&mut self, value: u32, count: u32) {
+        self.buffer |= value << self.bits;
+        self.bits += count;
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    // Huffman codes are stored starting with their most significant bit.
+    fn write_code(&mut self, code: u32, length: u32) {
+        self.write(code.reverse_bits() >> (32 - length), length);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// Writes a literal/length symbol using the fixed Huffman code from section 3.2.6 of RFC 1951.
+fn write_fixed_literal(writer: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASES
+        .iter()
+        .rposition(|&base| base as usize <= length)
+        .unwrap();
+    write_fixed_literal(writer, 257 + code as u32);
+    writer.write(
+        (length - LENGTH_BASES[code] as usize) as u32,
+        u32::from(LENGTH_EXTRA_BITS[code]),
+    );
+    let code = DISTANCE_BASES
+        .iter()
+        .rposition(|&base| base as usize <= distance)
+        .unwrap();
+    // Distance codes are all 5 bits long in the fixed code.
+    writer.write_code(code as u32, 5);
+    writer.write(
+        (distance - DISTANCE_BASES[code] as usize) as u32,
+        u32::from(DISTANCE_EXTRA_BITS[code]),
+    );
+}
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+// How many earlier positions with the same hash are checked when looking for the longest match,
+// more is slower but compresses better.
+const MAX_CHAIN: usize = 64;
+
+// Finds earlier occurrences of the bytes at a given position using hash chains: head holds the
+// most recent position for every hash of 3 bytes, prev links every position to the previous one
+// with the same hash.
+struct Matcher<'a> {
+    data: &'a [u8],
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(data: &'a [u8]) -> Matcher<'a> {
+        Matcher {
+            data,
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; data.len()],
+        }
+    }
+
+    fn hash(&self, i: usize) -> usize {
+        let d = self.data;
+        let value = u32::from(d[i]) << 16 | u32::from(d[i + 1]) << 8 | u32::from(d[i + 2]);
+        (value.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, i: usize) {
+        if i + MIN_MATCH <= self.data.len() {
+            let hash = self.hash(i);
+            self.prev[i] = self.head[hash];
+            self.head[hash] = i;
+        }
+    }
+
+    // Returns the length and distance of the longest match for the bytes starting at i.
+    fn longest_match(&self, i: usize) -> (usize, usize) {
+        let mut best = (0, 0);
+        if i + MIN_MATCH > self.data.len() {
+            return best;
+        }
+        let max_length = MAX_MATCH.min(self.data.len() - i);
+        let mut candidate = self.head[self.hash(i)];
+        let mut chain = 0;
+        while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+            let length = self.data[candidate..candidate + max_length]
+                .iter()
+                .zip(&self.data[i..i + max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best.0 {
+                best = (length, i - candidate);
+                if length == max_length {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate];
+            chain += 1;
+        }
+        best
+    }
+}
+
+// Compresses data into a single deflate block using LZ77 and the fixed Huffman code. A dynamic
+// code would compress better, but this is already several times smaller than no compression for
+// typical images.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    // BFINAL = 1, BTYPE = 01 (fixed Huffman codes)
+    writer.write(1, 1);
+    writer.write(1, 2);
+    let mut matcher = Matcher::new(data);
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = matcher.longest_match(i);
+        if length >= MIN_MATCH {
+            write_match(&mut writer, length, distance);
+            for j in i..i + length {
+                matcher.insert(j);
+            }
+            i += length;
+        } else {
+            write_fixed_literal(&mut writer, u32::from(data[i]));
+            matcher.insert(i);
+            i += 1;
+        }
+    }
+    write_fixed_literal(&mut writer, 256);
+    writer.finish()
+}
+
+pub fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, the check bits make the header a multiple of 31.
+    let mut compressed = vec![0x78, 0x9c];
+    compressed.extend(deflate(data));
+    compressed.extend(&adler32(data).to_be_bytes());
+    compressed
+}
+
+fn write_chunk(w: &mut dyn Write, kind: &[u8; 4], data: &[u8]) {
+    let mut checked = kind.to_vec();
+    checked.extend(data);
+    w.write_all(&(data.len() as u32).to_be_bytes())
+        .and_then(|_| w.write_all(&checked))
+        .and_then(|_| w.write_all(&crc32(&checked).to_be_bytes()))
+        .expect("Cannot write");
+}
+
+// The Paeth predictor from the PNG specification.
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = i16::from(a) + i16::from(b) - i16::from(c);
+    let (pa, pb, pc) = (
+        (p - i16::from(a)).abs(),
+        (p - i16::from(b)).abs(),
+        (p - i16::from(c)).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// Applies the given PNG filter type to a row of RGB pixels, previous is the unfiltered row above
+// (all zeros for the first row).
+fn filter_row(filter: u8, row: &[u8], previous: &[u8]) -> Vec<u8> {
+    const BYTES_PER_PIXEL: usize = 3;
+    let mut filtered = Vec::with_capacity(row.len() + 1);
+    filtered.push(filter);
+    for i in 0..row.len() {
+        let left = if i >= BYTES_PER_PIXEL {
+            row[i - BYTES_PER_PIXEL]
+        } else {
+            0
+        };
+        let up = previous[i];
+        let up_left = if i >= BYTES_PER_PIXEL {
+            previous[i - BYTES_PER_PIXEL]
+        } else {
+            0
+        };
+        let predicted = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((u16::from(left) + u16::from(up)) / 2) as u8,
+            _ => paeth(left, up, up_left),
+        };
+        filtered.push(row[i].wrapping_sub(predicted));
+    }
+    filtered
+}
+
+// Encodes 8 bit RGB pixels (rows top to bottom) as a PNG image.
+pub fn write_png(w: &mut dyn Write, width: usize, height: usize, pixels: &[u8]) {
+    assert_eq!(pixels.len(), width * height * 3);
+    w.write_all(b"\x89PNG\r\n\x1a\n").expect("Cannot write");
+
+    let mut header = Vec::new();
+    header.extend(&(width as u32).to_be_bytes());
+    header.extend(&(height as u32).to_be_bytes());
+    // 8 bits per channel, truecolor, deflate, adaptive filtering, no interlacing
+    header.extend(&[8, 2, 0, 0, 0]);
+    write_chunk(w, b"IHDR", &header);
+
+    // Every row is filtered with the filter that produces the smallest values (treating bytes as
+    // signed), the heuristic recommended by the specification.
+    let mut filtered = Vec::with_capacity(pixels.len() + height);
+    let mut previous = vec![0; width * 3];
+    for row in pixels.chunks(width * 3) {
+        let best = (0..5)
+            .map(|filter| filter_row(filter, row, &previous))
+            .min_by_key(|candidate| {
+                candidate[1..]
+                    .iter()
+                    .map(|&byte| u32::from((byte as i8).unsigned_abs()))
+                    .sum::<u32>()
+            })
+            .unwrap();
+        filtered.extend(best);
+        previous = row.to_vec();
+    }
+    write_chunk(w, b"IDAT", &zlib_compress(&filtered));
+    write_chunk(w, b"IEND", &[]);
+}
+
+// Reads bits the way deflate stores them, the counterpart of BitWriter.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    buffer: u32,
+    bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            bytes,
+            position: 0,
+            buffer: 0,
+            bits: 0,
+        }
+    }
+
+    // Reads count (at most 24) bits, the first one read ending up as the least significant.
+    fn read(&mut self, count: u32) -> Result<u32, String> {
+        while self.bits < count {
+            let byte = *self
+                .bytes
+                .get(self.position)
+                .ok_or("unexpected end of compressed data")?;
+            self.buffer |= u32::from(byte) << self.bits;
+            self.position += 1;
+            self.bits += 8;
+        }
+        let value = self.buffer & ((1 << count) - 1);
+        self.buffer >>= count;
+        self.bits -= count;
+        Ok(value)
+    }
+
+    // Skips to the next byte boundary, stored blocks start at one.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.bits = 0;
+    }
+}
+
+// A canonical Huffman code, described by how many codes there are of every length and the
+// symbols sorted by code. Decoding reads a bit at a time, which is slow but simple (see puff.c
+// in the zlib distribution).
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    // Builds the code from the code length of every symbol, 0 meaning the symbol isn't used.
+    fn new(lengths: &[u8]) -> Result<Huffman, String> {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        // Too many codes of some length can't be told apart.
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = left * 2 - i32::from(count);
+            if left < 0 {
+                return Err("invalid Huffman code".to_string());
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        counts[0] = 0;
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        // The first code of the current length, and the index of its symbol.
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= reader.read(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code".to_string())
+    }
+}
+
+// The fixed codes from section 3.2.6 of RFC 1951, for literals and lengths and for distances.
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        *length = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (
+        Huffman::new(&lengths).unwrap(),
+        Huffman::new(&[5; 30]).unwrap(),
+    )
+}
+
+// The order the lengths of the code length code are stored in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+// Reads the description of the codes of a block compressed with dynamic Huffman codes.
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literal_count = reader.read(5)? as usize + 257;
+    let distance_count = reader.read(5)? as usize + 1;
+    let code_length_count = reader.read(4)? as usize + 4;
+    let mut code_length_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_length_lengths[symbol] = reader.read(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_length_lengths)?;
+    // The code lengths of both codes are compressed together, repeats can cross from one to the
+    // other.
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let symbol = code_length_code.decode(reader)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => match lengths.last() {
+                Some(&previous) => (previous, 3 + reader.read(2)?),
+                None => return Err("repeated code length without a previous one".to_string()),
+            },
+            17 => (0, 3 + reader.read(3)?),
+            _ => (0, 11 + reader.read(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(length);
+        }
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err("too many code lengths".to_string());
+    }
+    if lengths[256] == 0 {
+        return Err("no code for the end of block".to_string());
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count])?,
+        Huffman::new(&lengths[literal_count..])?,
+    ))
+}
+
+const TOO_LARGE: &str = "decompressed data too large";
+
+// Decompresses the data of a block up to its end of block symbol, without letting the output grow
+// past limit bytes.
+fn inflate_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    limit: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 if output.len() >= limit => return Err(TOO_LARGE.to_string()),
+            0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let code = symbol - 257;
+                let length = LENGTH_BASES[code] as usize
+                    + reader.read(u32::from(LENGTH_EXTRA_BITS[code]))? as usize;
+                let code = distances.decode(reader)? as usize;
+                if code >= DISTANCE_BASES.len() {
+                    return Err("invalid distance code".to_string());
+                }
+                let distance = DISTANCE_BASES[code] as usize
+                    + reader.read(u32::from(DISTANCE_EXTRA_BITS[code]))? as usize;
+                if distance > output.len() {
+                    return Err("distance too far back".to_string());
+                }
+                if length > limit - output.len() {
+                    return Err(TOO_LARGE.to_string());
+                }
+                // The copy can overlap the bytes it produces, so it goes a byte at a time.
+                let start = output.len() - distance;
+                for i in 0..length {
+                    output.push(output[start + i]);
+                }
+            }
+            _ => return Err("invalid literal/length code".to_string()),
+        }
+    }
+}
+
+// The inverse of deflate, handling all three kinds of blocks (stored, fixed and dynamic Huffman
+// codes). A few bytes of compressed data can expand to gigabytes, so decompression fails as soon
+// as the output would be longer than limit.
+pub fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader::new(data);
+    let mut output = Vec::new();
+    loop {
+        let last = reader.read(1)? == 1;
+        match reader.read(2)? {
+            0 => {
+                reader.align();
+                let header = data
+                    .get(reader.position..reader.position + 4)
+                    .ok_or("unexpected end of compressed data")?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                let complement = u16::from_le_bytes([header[2], header[3]]);
+                if length != !complement {
+                    return Err("corrupt stored block length".to_string());
+                }
+                let start = reader.position + 4;
+                let stored = data
+                    .get(start..start + length as usize)
+                    .ok_or("unexpected end of compressed data")?;
+                if stored.len() > limit - output.len() {
+                    return Err(TOO_LARGE.to_string());
+                }
+                output.extend(stored);
+                reader.position = start + length as usize;
+            }
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_block(&mut reader, &mut output, limit, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut output, limit, &literals, &distances)?;
+            }
+            _ => return Err("invalid block type".to_string()),
+        }
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+pub fn zlib_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    if data.len() < 6 {
+        return Err("compressed data too short".to_string());
+    }
+    let (method, flags) = (data[0], data[1]);
+    if method & 0x0f != 8 || (u16::from(method) << 8 | u16::from(flags)) % 31 != 0 {
+        return Err("invalid zlib header".to_string());
+    }
+    if flags & 0x20 != 0 {
+        return Err("preset dictionaries are not supported".to_string());
+    }
+    let decompressed = inflate(&data[2..data.len() - 4], limit)?;
+    let checksum = &data[data.len() - 4..];
+    if adler32(&decompressed).to_be_bytes() != checksum {
+        return Err("zlib checksum mismatch".to_string());
+    }
+    Ok(decompressed)
+}
+
+// Reverses filter_row in place, row includes the filter type byte and previous is the already
+// unfiltered row above (all zeros for the first row).
+fn unfilter_row(row: &mut [u8], previous: &[u8], bytes_per_pixel: usize) -> Result<(), String> {
+    let filter = row[0];
+    let row = &mut row[1..];
+    for i in 0..row.len() {
+        let left = if i >= bytes_per_pixel {
+            row[i - bytes_per_pixel]
+        } else {
+            0
+        };
+        let up = previous[i];
+        let up_left = if i >= bytes_per_pixel {
+            previous[i - bytes_per_pixel]
+        } else {
+            0
+        };
+        let predicted = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((u16::from(left) + u16::from(up)) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err(format!("invalid filter type {}", filter)),
+        };
+        row[i] = row[i].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+// Decodes a PNG image into its size and RGB pixels (rows top to bottom), every channel scaled to
+// [0.0, 1.0]. All color types and bit depths are supported, transparency is ignored. Interlaced
+// images aren't.
+pub fn read_png(data: &[u8]) -> Result<(usize, usize, Vec<f32>), String> {
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Err("not a PNG file".to_string());
+    }
+    let mut position = 8;
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut compressed = Vec::new();
+    loop {
+        let length = data
+            .get(position..position + 4)
+            .ok_or("unexpected end of file")?;
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        let checked = data
+            .get(position + 4..position + 8 + length)
+            .ok_or("unexpected end of file")?;
+        let crc = data
+            .get(position + 8 + length..position + 12 + length)
+            .ok_or("unexpected end of file")?;
+        if crc32(checked).to_be_bytes() != crc {
+            return Err("chunk checksum mismatch".to_string());
+        }
+        let (kind, chunk) = checked.split_at(4);
+        match kind {
+            b"IHDR" if chunk.len() == 13 => header = Some(chunk),
+            b"PLTE" => palette = chunk,
+            b"IDAT" => compressed.extend(chunk),
+            b"IEND" => break,
+            _ => (),
+        }
+        position += 12 + length;
+    }
+    let header = header.ok_or("missing image header")?;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let (depth, color_type) = (header[8], header[9]);
+    if header[12] != 0 {
+        return Err("interlaced images are not supported".to_string());
+    }
+    let channels = match (color_type, depth) {
+        (0, 1) | (0, 2) | (0, 4) | (0, 8) | (0, 16) => 1,
+        (2, 8) | (2, 16) => 3,
+        (3, 1) | (3, 2) | (3, 4) | (3, 8) => 1,
+        (4, 8) | (4, 16) => 2,
+        (6, 8) | (6, 16) => 4,
+        _ => {
+            return Err(format!(
+                "invalid color type {} with bit depth {}",
+                color_type, depth
+            ))
+        }
+    };
+    if width == 0 || height == 0 {
+        return Err("the image has no pixels".to_string());
+    }
+
+    // The size in the header can be anything up to 2^32 - 1 pixels each way, it's only trusted
+    // once there's as much image data, every row starting with a filter type byte.
+    let bits_per_pixel = channels * depth as usize;
+    let row_length = width
+        .checked_mul(bits_per_pixel)
+        .map(|bits| bits.div_ceil(8))
+        .ok_or("the image is too large")?;
+    let expected = (row_length + 1)
+        .checked_mul(height)
+        .ok_or("the image is too large")?;
+    let samples = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(3))
+        .ok_or("the image is too large")?;
+    let mut filtered = zlib_decompress(&compressed, expected)?;
+    if filtered.len() != expected {
+        return Err("wrong amount of image data".to_string());
+    }
+    let mut previous = vec![0; row_length];
+    for row in filtered.chunks_mut(row_length + 1) {
+        unfilter_row(row, &previous, bits_per_pixel.div_ceil(8))?;
+        previous.copy_from_slice(&row[1..]);
+    }
+
+    let max = ((1u32 << depth) - 1) as f32;
+    let mut pixels = Vec::with_capacity(samples);
+    for row in filtered.chunks(row_length + 1) {
+        let row = &row[1..];
+        // Samples are packed most significant bits first, 16 bit ones are big endian.
+        let sample = |index: usize| -> u32 {
+            match depth {
+                16 => u32::from(row[2 * index]) << 8 | u32::from(row[2 * index + 1]),
+                8 => u32::from(row[index]),
+                _ => {
+                    let bit = index * depth as usize;
+                    let shift = 8 - depth as usize - bit % 8;
+                    (u32::from(row[bit / 8]) >> shift) & ((1 << depth) - 1)
+                }
+            }
+        };
+        for x in 0..width {
+            let first = x * channels;
+            match color_type {
+                0 | 4 => {
+                    let gray = sample(first) as f32 / max;
+                    pixels.extend(&[gray, gray, gray]);
+                }
+                3 => {
+                    let index = sample(first) as usize;
+                    let rgb = palette
+                        .get(index * 3..index * 3 + 3)
+                        .ok_or("palette index out of range")?;
+                    pixels.extend(rgb.iter().map(|&value| f32::from(value) / 255.0));
+                }
+                _ => pixels.extend((first..first + 3).map(|i| sample(i) as f32 / max)),
+            }
+        }
+    }
+    Ok((width, height, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::png::{
+        adler32, crc32, inflate, read_png, write_chunk, write_png, zlib_compress, zlib_decompress,
+    };
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_zlib_compress() {
+        assert_eq!(
+            zlib_compress(b""),
+            vec![0x78, 0x9c, 0x03, 0x00, 0x00, 0x00, 0x00, 0x01]
+        );
+        assert_eq!(
+            zlib_compress(b"a"),
+            vec![0x78, 0x9c, 0x4b, 0x04, 0x00, 0x00, 0x62, 0x00, 0x62]
+        );
+        let repetitive: Vec<u8> = (0..10000).map(|i| (i % 7) as u8).collect();
+        assert!(zlib_compress(&repetitive).len() < 200);
+    }
+
+    #[test]
+    fn test_write_png() {
+        let mut buffer = Vec::new();
+        write_png(&mut buffer, 2, 1, &[255, 0, 0, 0, 0, 255]);
+        assert_eq!(&buffer[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(
+            &buffer[8..33],
+            &[
+                0, 0, 0, 13, b'I', b'H', b'D', b'R', 0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0, 0x7b,
+                0x40, 0xe8, 0xdd
+            ]
+        );
+        assert_eq!(
+            &buffer[buffer.len() - 12..],
+            &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]
+        );
+    }
+
+    #[test]
+    fn test_inflate() {
+        // A stored block followed by a fixed Huffman one.
+        assert_eq!(
+            inflate(
+                &[0x00, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c', 0x4b, 0x04, 0x00],
+                4
+            ),
+            Ok(b"abca".to_vec())
+        );
+        assert_eq!(
+            inflate(
+                &[0x00, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c', 0x4b, 0x04, 0x00],
+                3
+            ),
+            Err("decompressed data too large".to_string())
+        );
+        for data in &[
+            b"".to_vec(),
+            b"a".to_vec(),
+            (0..10000).map(|i| (i % 7) as u8).collect(),
+            (0..1000).map(|i| (i * i % 251) as u8).collect(),
+        ] {
+            assert_eq!(
+                zlib_decompress(&zlib_compress(data), data.len()).as_ref(),
+                Ok(data)
+            );
+        }
+        // Compressed by zlib itself, with dynamic Huffman codes.
+        assert_eq!(
+            zlib_decompress(
+                &[
+                    120, 218, 29, 137, 177, 9, 0, 0, 0, 130, 110, 213, 254, 255, 33, 106, 8, 197,
+                    2, 1, 196, 67, 167, 60, 250, 185, 19, 11, 56, 224, 15, 65
+                ],
+                usize::MAX
+            ),
+            Ok(b"caacaaababcaaabbbababaaacaabcaabcbaabaab".to_vec())
+        );
+        let mut corrupt = zlib_compress(b"abc");
+        corrupt[3] ^= 1;
+        assert!(zlib_decompress(&corrupt, usize::MAX).is_err());
+        assert!(zlib_decompress(&[0x78, 0x9c, 0x4b], usize::MAX).is_err());
+        let repeated = zlib_compress(&[0; 10000]);
+        assert_eq!(
+            zlib_decompress(&repeated, 9999),
+            Err("decompressed data too large".to_string())
+        );
+    }
+
+    fn png(
+        width: u32,
+        height: u32,
+        depth: u8,
+        color_type: u8,
+        chunks: &[(&[u8; 4], &[u8])],
+    ) -> Vec<u8> {
+        let mut buffer = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut header = Vec::new();
+        header.extend(&width.to_be_bytes());
+        header.extend(&height.to_be_bytes());
+        header.extend(&[depth, color_type, 0, 0, 0]);
+        write_chunk(&mut buffer, b"IHDR", &header);
+        for (kind, data) in chunks {
+            write_chunk(&mut buffer, kind, data);
+        }
+        write_chunk(&mut buffer, b"IEND", &[]);
+        buffer
+    }
+
+    #[test]
+    fn test_read_png() {
+        let pixels: Vec<u8> = (0..3 * 4 * 3).map(|i| (i * 37 % 256) as u8).collect();
+        let mut buffer = Vec::new();
+        write_png(&mut buffer, 4, 3, &pixels);
+        let (width, height, read) = read_png(&buffer).unwrap();
+        assert_eq!((width, height), (4, 3));
+        let read: Vec<u8> = read
+            .iter()
+            .map(|&value| (value * 255.0).round() as u8)
+            .collect();
+        assert_eq!(read, pixels);
+
+        // 2 bit grayscale, with every row using a different filter.
+        let data = zlib_compress(&[0, 0b0001_1011, 1, 0b1100_0000, 2, 0b0001_0000]);
+        let (width, height, read) = read_png(&png(3, 3, 2, 0, &[(b"IDAT", &data)])).unwrap();
+        assert_eq!((width, height), (3, 3));
+        let gray: Vec<f32> = read.iter().step_by(3).cloned().collect();
+        let third = 1.0 / 3.0;
+        let expected = [0.0, third, 2.0 * third, 1.0, 0.0, 0.0, 1.0, third, 0.0];
+        assert_eq!(gray, expected);
+        assert!(read
+            .chunks(3)
+            .all(|rgb| rgb[0] == rgb[1] && rgb[1] == rgb[2]));
+
+        // A 1 bit palette, split over two data chunks.
+        let data = zlib_compress(&[0, 0b0110_0000]);
+        let (first, second) = data.split_at(3);
+        let image = png(
+            3,
+            1,
+            1,
+            3,
+            &[
+                (b"PLTE", &[255, 0, 0, 0, 0, 255]),
+                (b"IDAT", first),
+                (b"IDAT", second),
+            ],
+        );
+        assert_eq!(
+            read_png(&image).unwrap().2,
+            vec![1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0]
+        );
+
+        // 16 bit RGBA, the alpha is dropped.
+        let data = zlib_compress(&[0, 0xff, 0xff, 0x80, 0x00, 0x00, 0x00, 0x12, 0x34]);
+        let read = read_png(&png(1, 1, 16, 6, &[(b"IDAT", &data)])).unwrap().2;
+        assert_eq!(read, vec![1.0, 32768.0 / 65535.0, 0.0]);
+
+        let mut corrupt = buffer.clone();
+        corrupt[20] ^= 1;
+        assert!(read_png(&corrupt).is_err());
+        assert!(read_png(&buffer[..buffer.len() - 1]).is_err());
+        assert!(read_png(b"P6\n1 1\n255\n").is_err());
+        assert!(read_png(&png(1, 1, 8, 2, &[(b"IDAT", &zlib_compress(&[0, 1, 2]))])).is_err());
+        assert!(read_png(&png(1, 1, 3, 2, &[(b"IDAT", &zlib_compress(&[0, 1]))])).is_err());
+        // Far more data than a 1x1 image needs, and a size that overflows.
+        let data = zlib_compress(&[0; 100000]);
+        assert_eq!(
+            read_png(&png(1, 1, 8, 2, &[(b"IDAT", &data)])),
+            Err("decompressed data too large".to_string())
+        );
+        let huge = png(u32::MAX, u32::MAX, 16, 6, &[(b"IDAT", &data)]);
+        assert_eq!(read_png(&huge), Err("the image is too large".to_string()));
+    }
+}
@@ -0,0 +1,994 @@
+use crate::bvh::Aabb;
+use crate::material::Material;
+use crate::scene::{rounding_error, Intersection, Ray, Shape, UnitVector, Vector};
+use std::f32;
+
+// The real roots of a t² + b t + c = 0 in increasing order (the same one twice when a is 0). They
+// are computed in double precision, and in a way that doesn't lose precision to cancellation when
+// one is much closer to 0 than the other.
+fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    let (a, b, c) = (f64::from(a), f64::from(b), f64::from(c));
+    if a == 0.0 {
+        let t = (-c / b) as f32;
+        return if b == 0.0 { None } else { Some((t, t)) };
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let q = -0.5 * (b + discriminant.sqrt().copysign(b));
+    let (t0, t1) = ((q / a) as f32, (c / q) as f32);
+    Some((t0.min(t1), t0.max(t1)))
+}
+
+// The largest real root of x³ + a x² + b x + c = 0, see section 5.6 of Numerical Recipes.
+fn largest_cubic_root(a: f64, b: f64, c: f64) -> f64 {
+    let q = (a * a - 3.0 * b) / 9.0;
+    let r = (2.0 * a * a * a - 9.0 * a * b + 27.0 * c) / 54.0;
+    if r * r < q * q * q {
+        // Three real roots, the largest is the one with the smallest cosine.
+        let theta = (r / (q * q * q).sqrt()).acos();
+        -2.0 * q.sqrt() * ((theta + 2.0 * std::f64::consts::PI) / 3.0).cos() - a / 3.0
+    } else {
+        let s = -(r.abs() + (r * r - q * q * q).sqrt()).cbrt().copysign(r);
+        let t = if s == 0.0 { 0.0 } else { q / s };
+        s + t - a / 3.0
+    }
+}
+
+// The real roots of x⁴ + a x³ + b x² + c x + d = 0, in no particular order, using Ferrari's
+// method: once x is shifted to get rid of the cubic term, a root of the resolvent cubic splits the
+// quartic into two quadratics. Every root is then polished with a couple of Newton steps, which
+// wins back most of the precision lost on the way.
+pub(crate) fn solve_quartic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    let a2 = a * a;
+    let p = b - 3.0 * a2 / 8.0;
+    let q = c - a * b / 2.0 + a2 * a / 8.0;
+    let r = d - a * c / 4.0 + a2 * b / 16.0 - 3.0 * a2 * a2 / 256.0;
+    let m = largest_cubic_root(p, p * p / 4.0 - r, -q * q / 8.0);
+    let mut roots = Vec::with_capacity(4);
+    let mut add_quadratic_roots = |b: f64, c: f64| {
+        let discriminant = b * b - 4.0 * c;
+        if discriminant >= 0.0 {
+            let root = discriminant.sqrt();
+            roots.push((-b - root) / 2.0);
+            roots.push((-b + root) / 2.0);
+        }
+    };
+    if m > 0.0 {
+        let s = (2.0 * m).sqrt();
+        add_quadratic_roots(-s, p / 2.0 + m + q / (2.0 * s));
+        add_quadratic_roots(s, p / 2.0 + m - q / (2.0 * s));
+    } else {
+        // Without a positive root q is 0 and the quartic is a quadratic in y².
+        let discriminant = p * p - 4.0 * r;
+        if discriminant >= 0.0 {
+            for y_squared in [
+                (-p - discriminant.sqrt()) / 2.0,
+                (-p + discriminant.sqrt()) / 2.0,
+            ] {
+                if y_squared >= 0.0 {
+                    roots.push(-y_squared.sqrt());
+                    roots.push(y_squared.sqrt());
+                }
+            }
+        }
+    }
+    for root in &mut roots {
+        *root -= a / 4.0;
+        for _ in 0..2 {
+            let x = *root;
+            let value = (((x + a) * x + b) * x + c) * x + d;
+            let derivative = ((4.0 * x + 3.0 * a) * x + 2.0 * b) * x + c;
+            if derivative != 0.0 {
+                *root -= value / derivative;
+            }
+        }
+    }
+    roots
+}
+
+// The closest of the candidate hits within the ray's interval.
+fn closest<T>(ray: &Ray, candidates: impl IntoIterator<Item = (f32, T)>) -> Option<(f32, T)> {
+    candidates
+        .into_iter()
+        .filter(|(t, _)| ray.contains(*t))
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+}
+
+// The angle of (x, y) counter-clockwise from the x axis as a fraction of a full turn, from 0 to 1.
+fn turn(x: f32, y: f32) -> f32 {
+    let fraction = y.atan2(x) / (2.0 * f32::consts::PI);
+    if fraction < 0.0 {
+        fraction + 1.0
+    } else {
+        fraction
+    }
+}
+
+// The box around a disk of radius around center, facing axis.
+fn disk_bounds(center: &Vector, axis: &UnitVector, radius: f32) -> Aabb {
+    let extent = axis
+        .0
+        .components()
+        .map(|n| radius * (1.0 - n * n).max(0.0).sqrt());
+    let extent = Vector::from_components(extent);
+    Aabb {
+        min: *center - extent,
+        max: *center + extent,
+    }
+}
+
+// A hit in the local coordinates of a shape with an axis (see UnitVector::to_local), positioned
+// exactly on its surface.
+struct LocalHit {
+    t: f32,
+    position: Vector,
+    // Pointing out of the shape, or to the front of a flat one.
+    normal: Vector,
+    uv: (f32, f32),
+}
+
+impl LocalHit {
+    // Hits on a disk of the given radii at height z, facing up when up is true. v goes from the
+    // inner edge to the outer one.
+    fn on_disk(t: f32, point: &Vector, z: f32, up: bool, inner_radius: f32, radius: f32) -> Self {
+        let distance = (point.x * point.x + point.y * point.y).sqrt();
+        LocalHit {
+            t,
+            position: Vector { z, ..*point },
+            normal: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: if up { 1.0 } else { -1.0 },
+            },
+            uv: (
+                turn(point.x, point.y),
+                (distance - inner_radius) / (radius - inner_radius),
+            ),
+        }
+    }
+
+    // The intersection for a hit on a shape whose local coordinates start at origin, magnitude
+    // being how far from it the shape reaches.
+    fn intersection<'a>(
+        &self,
+        ray: &Ray,
+        origin: &Vector,
+        axis: &UnitVector,
+        magnitude: f32,
+        object: &'a dyn Shape,
+    ) -> Intersection<'a> {
+        let normal = axis.from_local(&self.normal).normalized();
+        let front_face = normal.0.dot(&ray.dir.0) < 0.0;
+        let normal = if front_face { normal } else { -normal };
+        Intersection {
+            position: *origin + axis.from_local(&self.position),
+            t: self.t,
+            normal,
+            geometric_normal: normal,
+            front_face,
+            position_error: rounding_error(origin.max_abs_component() + 2.0 * magnitude),
+            uv: self.uv,
+            time: ray.time,
+            object,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Part {
+    Side,
+    Bottom,
+    Top,
+}
+
+// A box with faces perpendicular to the axes, spanning from min to max. The texture coordinates go
+// from 0 to 1 across every face: (y, z) on the faces across x, (x, z) across y and (x, y) across z.
+#[derive(Clone, Debug)]
+pub struct Cuboid {
+    pub min: Vector,
+    pub max: Vector,
+    pub material: Material,
+}
+
+impl Shape for Cuboid {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let (pos, dir) = (ray.pos.components(), ray.dir.0.components());
+        let (min, max) = (self.min.components(), self.max.components());
+        // The ray is between the two faces across each axis over an interval (the slab method),
+        // it's inside the box where all three overlap. Both ends of the overlap remember which
+        // axis they came from.
+        let mut near = (f32::NEG_INFINITY, 0);
+        let mut far = (f32::INFINITY, 0);
+        for axis in 0..3 {
+            if dir[axis] == 0.0 {
+                if pos[axis] < min[axis] || pos[axis] > max[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (min[axis] - pos[axis]) / dir[axis];
+            let t1 = (max[axis] - pos[axis]) / dir[axis];
+            if t0.min(t1) > near.0 {
+                near = (t0.min(t1), axis);
+            }
+            if t0.max(t1) < far.0 {
+                far = (t0.max(t1), axis);
+            }
+        }
+        if near.0 > far.0 {
+            return None;
+        }
+        // Like with spheres the ray either enters the box, or starts inside and leaves it.
+        let ((t, axis), front_face) = if ray.contains(near.0) {
+            (near, true)
+        } else if near.0 <= ray.t_min && ray.contains(far.0) {
+            (far, false)
+        } else {
+            return None;
+        };
+        // Entering through the min face means moving towards +axis, leaving through it -axis.
+        let on_min_face = (dir[axis] > 0.0) == front_face;
+        let mut position = ray.at(t).components();
+        position[axis] = if on_min_face { min[axis] } else { max[axis] };
+        let mut normal = [0.0; 3];
+        normal[axis] = if on_min_face == front_face { -1.0 } else { 1.0 };
+        let (u, v) = match axis {
+            0 => (1, 2),
+            1 => (0, 2),
+            _ => (0, 1),
+        };
+        let fraction = |i: usize| (position[i] - min[i]) / (max[i] - min[i]);
+        let position = Vector::from_components(position);
+        let normal = UnitVector(Vector::from_components(normal));
+        Some(Intersection {
+            position,
+            t,
+            normal,
+            geometric_normal: normal,
+            front_face,
+            position_error: rounding_error(position.max_abs_component()),
+            uv: (fraction(u), fraction(v)),
+            time: ray.time,
+            object: self,
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb {
+            min: self.min,
+            max: self.max,
+        })
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+// A flat disk around center facing normal, it can be hit from both sides like a plane. A non-zero
+// inner_radius cuts a hole in the middle, making it an annulus. u goes around the center
+// (starting from the first direction of UnitVector::orthonormal_basis), v from the inner edge to
+// the outer one.
+#[derive(Clone, Debug)]
+pub struct Disk {
+    pub center: Vector,
+    pub normal: UnitVector,
+    pub radius: f32,
+    pub inner_radius: f32,
+    pub material: Material,
+}
+
+impl Shape for Disk {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let origin = self.normal.to_local(&(ray.pos - self.center));
+        let dir = self.normal.to_local(&ray.dir.0);
+        // Like with planes, rays parallel to the disk never hit it.
+        if dir.z == 0.0 {
+            return None;
+        }
+        let t = -origin.z / dir.z;
+        if !ray.contains(t) {
+            return None;
+        }
+        let point = origin + dir * t;
+        let distance_squared = point.x * point.x + point.y * point.y;
+        if distance_squared > self.radius * self.radius
+            || distance_squared < self.inner_radius * self.inner_radius
+        {
+            return None;
+        }
+        let hit = LocalHit::on_disk(t, &point, 0.0, true, self.inner_radius, self.radius);
+        Some(hit.intersection(ray, &self.center, &self.normal, self.radius, self))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(disk_bounds(&self.center, &self.normal, self.radius))
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+// A closed cylinder of radius around axis, starting at base and height long, with disks capping
+// both ends. On the side u goes around the axis (like on disks) and v along it, on the caps v goes
+// from the center out.
+#[derive(Clone, Debug)]
+pub struct Cylinder {
+    pub base: Vector,
+    pub axis: UnitVector,
+    pub height: f32,
+    pub radius: f32,
+    pub material: Material,
+}
+
+impl Shape for Cylinder {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let (radius, height) = (self.radius, self.height);
+        let origin = self.axis.to_local(&(ray.pos - self.base));
+        let dir = self.axis.to_local(&ray.dir.0);
+        let at = |t: f32| origin + dir * t;
+        let within_side = |t: f32| (0.0..=height).contains(&at(t).z);
+        let within_cap = |t: f32| {
+            let point = at(t);
+            point.x * point.x + point.y * point.y <= radius * radius
+        };
+        // Points on the side are radius away from the axis.
+        let side = solve_quadratic(
+            dir.x * dir.x + dir.y * dir.y,
+            2.0 * (origin.x * dir.x + origin.y * dir.y),
+            origin.x * origin.x + origin.y * origin.y - radius * radius,
+        );
+        let (bottom, top) = (-origin.z / dir.z, (height - origin.z) / dir.z);
+        let candidates = [
+            side.map(|(t, _)| (t, Part::Side)),
+            side.map(|(_, t)| (t, Part::Side)),
+            Some((bottom, Part::Bottom)),
+            Some((top, Part::Top)),
+        ];
+        let (t, part) = closest(
+            ray,
+            candidates
+                .iter()
+                .flatten()
+                .copied()
+                .filter(|&(t, part)| match part {
+                    Part::Side => within_side(t),
+                    _ => within_cap(t),
+                }),
+        )?;
+        let point = at(t);
+        let hit = match part {
+            Part::Side => {
+                let outward = Vector { z: 0.0, ..point }.normalized().0;
+                LocalHit {
+                    t,
+                    position: Vector {
+                        z: point.z.clamp(0.0, height),
+                        ..outward * radius
+                    },
+                    normal: outward,
+                    uv: (turn(point.x, point.y), point.z / height),
+                }
+            }
+            Part::Bottom => LocalHit::on_disk(t, &point, 0.0, false, 0.0, radius),
+            Part::Top => LocalHit::on_disk(t, &point, height, true, 0.0, radius),
+        };
+        Some(hit.intersection(ray, &self.base, &self.axis, height + radius, self))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let top = self.base + self.axis.0 * self.height;
+        Some(
+            disk_bounds(&self.base, &self.axis, self.radius).union(&disk_bounds(
+                &top,
+                &self.axis,
+                self.radius,
+            )),
+        )
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+// A cone standing on a disk of radius around base, with its tip height away along axis. The
+// texture coordinates are laid out like on cylinders, v going from the base to the tip.
+#[derive(Clone, Debug)]
+pub struct Cone {
+    pub base: Vector,
+    pub axis: UnitVector,
+    pub height: f32,
+    pub radius: f32,
+    pub material: Material,
+}
+
+impl Shape for Cone {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let (radius, height) = (self.radius, self.height);
+        let origin = self.axis.to_local(&(ray.pos - self.base));
+        let dir = self.axis.to_local(&ray.dir.0);
+        let at = |t: f32| origin + dir * t;
+        // Points on the side are slope * (height - z) away from the axis. The equation also holds
+        // for the mirrored cone above the tip, which is why hits are checked against the height.
+        let slope = radius / height;
+        let slope_squared = slope * slope;
+        let to_tip = height - origin.z;
+        let side = solve_quadratic(
+            dir.x * dir.x + dir.y * dir.y - slope_squared * dir.z * dir.z,
+            2.0 * (origin.x * dir.x + origin.y * dir.y + slope_squared * to_tip * dir.z),
+            origin.x * origin.x + origin.y * origin.y - slope_squared * to_tip * to_tip,
+        );
+        let bottom = -origin.z / dir.z;
+        let candidates = [
+            side.map(|(t, _)| (t, Part::Side)),
+            side.map(|(_, t)| (t, Part::Side)),
+            Some((bottom, Part::Bottom)),
+        ];
+        let (t, part) = closest(
+            ray,
+            candidates.iter().flatten().copied().filter(|&(t, part)| {
+                let point = at(t);
+                match part {
+                    Part::Side => (0.0..=height).contains(&point.z),
+                    _ => point.x * point.x + point.y * point.y <= radius * radius,
+                }
+            }),
+        )?;
+        let point = at(t);
+        let hit = match part {
+            Part::Bottom => LocalHit::on_disk(t, &point, 0.0, false, 0.0, radius),
+            _ => {
+                let z = point.z.clamp(0.0, height);
+                let distance = (point.x * point.x + point.y * point.y).sqrt();
+                // The tip has no direction away from the axis, any will do.
+                let away = if distance > 0.0 {
+                    Vector { z: 0.0, ..point } / distance
+                } else {
+                    Vector::unitx().0
+                };
+                LocalHit {
+                    t,
+                    position: Vector {
+                        z,
+                        ..away * (slope * (height - z))
+                    },
+                    normal: (away * height
+                        + Vector {
+                            x: 0.0,
+                            y: 0.0,
+                            z: radius,
+                        })
+                    .normalized()
+                    .0,
+                    uv: (turn(point.x, point.y), z / height),
+                }
+            }
+        };
+        Some(hit.intersection(ray, &self.base, &self.axis, height + radius, self))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let tip = self.base + self.axis.0 * self.height;
+        Some(disk_bounds(&self.base, &self.axis, self.radius).including(&tip))
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+// A ring shaped tube of minor_radius going around center at major_radius, in the plane
+// perpendicular to axis. u goes around the axis (like on disks), v around the tube, starting from
+// its outer edge and going towards the axis over the top first.
+#[derive(Clone, Debug)]
+pub struct Torus {
+    pub center: Vector,
+    pub axis: UnitVector,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+    pub material: Material,
+}
+
+impl Shape for Torus {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let (major, minor) = (self.major_radius, self.minor_radius);
+        let origin = self.axis.to_local(&(ray.pos - self.center));
+        let dir = self.axis.to_local(&ray.dir.0);
+        // Roots far from the origin of the ray lose a lot of precision, so the quartic is solved
+        // from where the ray enters the sphere around the torus.
+        let (enter, exit) = solve_quadratic(
+            dir.dot(&dir),
+            2.0 * origin.dot(&dir),
+            origin.dot(&origin) - (major + minor) * (major + minor),
+        )?;
+        if exit <= ray.t_min || enter > ray.t_max {
+            return None;
+        }
+        let shift = enter.max(0.0);
+        let [ox, oy, oz] = (origin + dir * shift).components().map(f64::from);
+        let [dx, dy, dz] = dir.components().map(f64::from);
+        let (major_squared, minor_squared) = (f64::from(major).powi(2), f64::from(minor).powi(2));
+        // Points p on the surface satisfy (|p|² + R² - r²)² = 4 R² (px² + py²), with R and r being
+        // the major and minor radii, it's a quartic in t for p along the ray.
+        let dd = dx * dx + dy * dy + dz * dz;
+        let od = ox * dx + oy * dy + oz * dz;
+        let k = ox * ox + oy * oy + oz * oz + major_squared - minor_squared;
+        let a4 = dd * dd;
+        let a3 = 4.0 * dd * od;
+        let a2 = 2.0 * dd * k + 4.0 * od * od - 4.0 * major_squared * (dx * dx + dy * dy);
+        let a1 = 4.0 * od * k - 8.0 * major_squared * (ox * dx + oy * dy);
+        let a0 = k * k - 4.0 * major_squared * (ox * ox + oy * oy);
+        let roots = solve_quartic(a3 / a4, a2 / a4, a1 / a4, a0 / a4);
+        let (t, _) = closest(ray, roots.iter().map(|&t| (t as f32 + shift, ())))?;
+        let point = origin + dir * t;
+        // The point is projected onto the surface from the closest point on the circle going
+        // through the middle of the tube.
+        let distance = (point.x * point.x + point.y * point.y).sqrt();
+        let away = if distance > 0.0 {
+            Vector { z: 0.0, ..point } / distance
+        } else {
+            Vector::unitx().0
+        };
+        let outward = (point - away * major).normalized().0;
+        let hit = LocalHit {
+            t,
+            position: away * major + outward * minor,
+            normal: outward,
+            uv: (turn(point.x, point.y), turn(outward.dot(&away), outward.z)),
+        };
+        Some(hit.intersection(ray, &self.center, &self.axis, major + minor, self))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let ring = disk_bounds(&self.center, &self.axis, self.major_radius);
+        let tube = Vector {
+            x: self.minor_radius,
+            y: self.minor_radius,
+            z: self.minor_radius,
+        };
+        Some(Aabb {
+            min: ring.min - tube,
+            max: ring.max + tube,
+        })
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_almost_eq;
+    use crate::bvh::Aabb;
+    use crate::material::Material;
+    use crate::primitives::{solve_quartic, Cone, Cuboid, Cylinder, Disk, Torus};
+    use crate::scene::{Intersection, Ray, Shape, Vector};
+    use crate::testing::vector;
+    use crate::traits::AlmostEqual;
+    use std::f32;
+
+    fn assert_close(a: Vector, b: Vector) {
+        assert!(a.almost_equal_with_epsilon(&b, 1e-5), "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_solve_quartic() {
+        let sorted = |mut roots: Vec<f64>| {
+            roots.sort_by(f64::total_cmp);
+            roots
+        };
+        let assert_roots = |roots: Vec<f64>, expected: &[f64]| {
+            let roots = sorted(roots);
+            assert_eq!(roots.len(), expected.len(), "{:?}", roots);
+            for (root, expected) in roots.iter().zip(expected) {
+                assert!(
+                    (root - expected).abs() < 1e-9 * (1.0 + expected.abs()),
+                    "{:?}",
+                    roots
+                );
+            }
+        };
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        assert_roots(
+            solve_quartic(-10.0, 35.0, -50.0, 24.0),
+            &[1.0, 2.0, 3.0, 4.0],
+        );
+        // (x² - 1)(x² - 4), without a cubic or linear term
+        assert_roots(solve_quartic(0.0, -5.0, 0.0, 4.0), &[-2.0, -1.0, 1.0, 2.0]);
+        // (x - 1)(x + 3)(x² + 1)
+        assert_roots(solve_quartic(2.0, -2.0, 2.0, -3.0), &[-3.0, 1.0]);
+        // x⁴ + 1
+        assert_roots(solve_quartic(0.0, 0.0, 0.0, 1.0), &[]);
+        // (x - 0.25)(x - 0.5)(x + 100)(x - 1000), roots of very different sizes.
+        assert_roots(
+            solve_quartic(-900.75, -99324.875, 74887.5, -12500.0),
+            &[-100.0, 0.25, 0.5, 1000.0],
+        );
+    }
+
+    #[test]
+    fn test_box_ray_intersection() {
+        let cuboid = Cuboid {
+            min: vector(-1.0, 0.0, -2.0),
+            max: vector(1.0, 2.0, 2.0),
+            material: Material::dummy(),
+        };
+        assert_almost_eq!(
+            cuboid.bounding_box(),
+            Some(Aabb {
+                min: vector(-1.0, 0.0, -2.0),
+                max: vector(1.0, 2.0, 2.0),
+            })
+        );
+
+        let outside_pointing_towards = Ray::new(vector(0.5, 1.5, 10.0), -Vector::unitz());
+        assert_almost_eq!(
+            cuboid.intersect_ray(&outside_pointing_towards),
+            Some(Intersection {
+                position: vector(0.5, 1.5, 2.0),
+                t: 8.0,
+                normal: Vector::unitz(),
+                geometric_normal: Vector::unitz(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.75, 0.75),
+                time: 0.0,
+                object: &cuboid,
+            })
+        );
+
+        let outside_pointing_away = Ray::new(vector(0.5, 1.5, 10.0), Vector::unitz());
+        assert_almost_eq!(cuboid.intersect_ray(&outside_pointing_away), None);
+
+        // Passing beside the box along one of its faces' planes.
+        let beside = Ray::new(vector(1.5, 1.0, 10.0), -Vector::unitz());
+        assert_almost_eq!(cuboid.intersect_ray(&beside), None);
+
+        let inside = Ray::new(vector(0.0, 1.0, 0.0), vector(0.0, -1.0, 1.0).normalized());
+        assert_almost_eq!(
+            cuboid.intersect_ray(&inside),
+            Some(Intersection {
+                position: vector(0.0, 0.0, 1.0),
+                t: 2.0f32.sqrt(),
+                normal: Vector::unity(),
+                geometric_normal: Vector::unity(),
+                front_face: false,
+                position_error: 0.0,
+                uv: (0.5, 0.75),
+                time: 0.0,
+                object: &cuboid,
+            })
+        );
+
+        let diagonal = Ray::new(vector(-3.0, 0.5, 0.0), Vector::unitx());
+        assert_almost_eq!(
+            cuboid.intersect_ray(&diagonal),
+            Some(Intersection {
+                position: vector(-1.0, 0.5, 0.0),
+                t: 2.0,
+                normal: -Vector::unitx(),
+                geometric_normal: -Vector::unitx(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.25, 0.5),
+                time: 0.0,
+                object: &cuboid,
+            })
+        );
+        let short = Ray {
+            t_max: 1.5,
+            ..diagonal
+        };
+        assert_almost_eq!(cuboid.intersect_ray(&short), None);
+    }
+
+    #[test]
+    fn test_disk_ray_intersection() {
+        let annulus = Disk {
+            center: vector(0.0, 1.0, 0.0),
+            normal: Vector::unity(),
+            radius: 2.0,
+            inner_radius: 1.0,
+            material: Material::dummy(),
+        };
+        let (tangent, bitangent) = Vector::unity().orthonormal_basis();
+
+        let above = Ray::new(vector(0.0, 5.0, 0.0) + tangent.0 * 1.5, -Vector::unity());
+        assert_almost_eq!(
+            annulus.intersect_ray(&above),
+            Some(Intersection {
+                position: vector(0.0, 1.0, 0.0) + tangent.0 * 1.5,
+                t: 4.0,
+                normal: Vector::unity(),
+                geometric_normal: Vector::unity(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.0, 0.5),
+                time: 0.0,
+                object: &annulus,
+            })
+        );
+
+        let below = Ray::new(vector(0.0, -5.0, 0.0) + bitangent.0 * 1.75, Vector::unity());
+        assert_almost_eq!(
+            annulus.intersect_ray(&below),
+            Some(Intersection {
+                position: vector(0.0, 1.0, 0.0) + bitangent.0 * 1.75,
+                t: 6.0,
+                normal: -Vector::unity(),
+                geometric_normal: -Vector::unity(),
+                front_face: false,
+                position_error: 0.0,
+                uv: (0.25, 0.75),
+                time: 0.0,
+                object: &annulus,
+            })
+        );
+
+        let through_the_hole = Ray::new(vector(0.0, 5.0, 0.0) + tangent.0 * 0.5, -Vector::unity());
+        assert_almost_eq!(annulus.intersect_ray(&through_the_hole), None);
+        let outside = Ray::new(vector(0.0, 5.0, 0.0) + tangent.0 * 2.5, -Vector::unity());
+        assert_almost_eq!(annulus.intersect_ray(&outside), None);
+        let parallel = Ray::new(vector(0.0, 1.0, 0.0), Vector::unitx());
+        assert_almost_eq!(annulus.intersect_ray(&parallel), None);
+
+        let disk = Disk {
+            inner_radius: 0.0,
+            ..annulus
+        };
+        assert!(disk.intersect_ray(&through_the_hole).is_some());
+        assert_almost_eq!(
+            disk.bounding_box(),
+            Some(Aabb {
+                min: vector(-2.0, 1.0, -2.0),
+                max: vector(2.0, 1.0, 2.0),
+            })
+        );
+    }
+
+    #[test]
+    fn test_cylinder_ray_intersection() {
+        let cylinder = Cylinder {
+            base: vector(0.0, 0.0, -5.0),
+            axis: Vector::unity(),
+            height: 2.0,
+            radius: 1.0,
+            material: Material::dummy(),
+        };
+        let (tangent, _) = Vector::unity().orthonormal_basis();
+
+        let towards_side = Ray::new(vector(0.0, 0.5, 0.0), -Vector::unitz());
+        assert_almost_eq!(
+            cylinder.intersect_ray(&towards_side),
+            Some(Intersection {
+                position: vector(0.0, 0.5, -4.0),
+                t: 4.0,
+                normal: Vector::unitz(),
+                geometric_normal: Vector::unitz(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.75, 0.25),
+                time: 0.0,
+                object: &cylinder,
+            })
+        );
+
+        let towards_top = Ray::new(vector(0.0, 10.0, -5.0) + tangent.0 * 0.5, -Vector::unity());
+        assert_almost_eq!(
+            cylinder.intersect_ray(&towards_top),
+            Some(Intersection {
+                position: vector(0.0, 2.0, -5.0) + tangent.0 * 0.5,
+                t: 8.0,
+                normal: Vector::unity(),
+                geometric_normal: Vector::unity(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.0, 0.5),
+                time: 0.0,
+                object: &cylinder,
+            })
+        );
+
+        // Over the top, and just under it into the side.
+        let over = Ray::new(vector(0.0, 2.5, -3.0), vector(0.0, -0.1, -1.0).normalized());
+        assert_almost_eq!(cylinder.intersect_ray(&over), None);
+        let under = Ray::new(vector(0.0, 2.1, -3.0), vector(0.0, -0.1, -1.0).normalized());
+        let hit = cylinder.intersect_ray(&under).unwrap();
+        assert_close(hit.position, vector(0.0, 2.0, -4.0));
+        assert_almost_eq!(hit.normal.0, Vector::unitz().0);
+
+        let inside = Ray::new(vector(0.0, 1.0, -5.0), vector(1.0, 1.0, 0.0).normalized());
+        let hit = cylinder.intersect_ray(&inside).unwrap();
+        assert_close(hit.position, vector(1.0, 2.0, -5.0));
+        assert_almost_eq!(hit.normal.0, -Vector::unity().0);
+        assert!(!hit.front_face);
+        let inside = Ray::new(vector(0.0, 1.0, -5.0), Vector::unitx());
+        let hit = cylinder.intersect_ray(&inside).unwrap();
+        assert_close(hit.normal.0, -Vector::unitx().0);
+        assert!(!hit.front_face);
+
+        let along_axis = Ray::new(vector(0.0, -3.0, -5.0), Vector::unity());
+        let hit = cylinder.intersect_ray(&along_axis).unwrap();
+        assert_almost_eq!(hit.t, 3.0);
+        assert_almost_eq!(hit.normal.0, -Vector::unity().0);
+        assert!(hit.front_face);
+
+        let bounds = cylinder.bounding_box().unwrap();
+        assert_almost_eq!(bounds.min, vector(-1.0, 0.0, -6.0));
+        assert_almost_eq!(bounds.max, vector(1.0, 2.0, -4.0));
+    }
+
+    #[test]
+    fn test_cone_ray_intersection() {
+        let cone = Cone {
+            base: Vector::zero(),
+            axis: Vector::unitz(),
+            height: 2.0,
+            radius: 1.0,
+            material: Material::dummy(),
+        };
+        let (tangent, bitangent) = Vector::unitz().orthonormal_basis();
+
+        // Halfway up the cone is half as wide.
+        let towards_side = Ray::new(vector(0.0, 0.0, 1.0) + tangent.0 * 5.0, -tangent);
+        let normal = (tangent.0 * 2.0 + Vector::unitz().0).normalized();
+        assert_almost_eq!(
+            cone.intersect_ray(&towards_side),
+            Some(Intersection {
+                position: vector(0.0, 0.0, 1.0) + tangent.0 * 0.5,
+                t: 4.5,
+                normal,
+                geometric_normal: normal,
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.0, 0.5),
+                time: 0.0,
+                object: &cone,
+            })
+        );
+
+        let towards_tip = Ray::new(vector(0.0, 0.0, 5.0), -Vector::unitz());
+        let hit = cone.intersect_ray(&towards_tip).unwrap();
+        assert_almost_eq!(hit.t, 3.0);
+        assert_almost_eq!(hit.position, vector(0.0, 0.0, 2.0));
+        assert!(hit.front_face);
+
+        // Above the tip the equation has a mirrored cone that mustn't be hit.
+        let above_tip = Ray::new(vector(0.0, 0.0, 3.0) + bitangent.0 * 5.0, -bitangent);
+        assert_almost_eq!(cone.intersect_ray(&above_tip), None);
+
+        let towards_base = Ray::new(vector(0.0, 0.0, -5.0) + bitangent.0 * 0.5, Vector::unitz());
+        assert_almost_eq!(
+            cone.intersect_ray(&towards_base),
+            Some(Intersection {
+                position: bitangent.0 * 0.5,
+                t: 5.0,
+                normal: -Vector::unitz(),
+                geometric_normal: -Vector::unitz(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.25, 0.5),
+                time: 0.0,
+                object: &cone,
+            })
+        );
+
+        let inside = Ray::new(vector(0.0, 0.0, 0.5), Vector::unitz());
+        let hit = cone.intersect_ray(&inside).unwrap();
+        assert_almost_eq!(hit.t, 1.5);
+        assert!(!hit.front_face);
+
+        let bounds = cone.bounding_box().unwrap();
+        assert_almost_eq!(bounds.min, vector(-1.0, -1.0, 0.0));
+        assert_almost_eq!(bounds.max, vector(1.0, 1.0, 2.0));
+    }
+
+    #[test]
+    fn test_torus_ray_intersection() {
+        let torus = Torus {
+            center: vector(0.0, 0.0, -10.0),
+            axis: Vector::unity(),
+            major_radius: 2.0,
+            minor_radius: 0.5,
+            material: Material::dummy(),
+        };
+
+        // Straight through the middle, hitting both sides of the ring.
+        let through = Ray::new(Vector::zero(), -Vector::unitz());
+        assert_almost_eq!(
+            torus.intersect_ray(&through),
+            Some(Intersection {
+                position: vector(0.0, 0.0, -7.5),
+                t: 7.5,
+                normal: Vector::unitz(),
+                geometric_normal: Vector::unitz(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.75, 0.0),
+                time: 0.0,
+                object: &torus,
+            })
+        );
+        let past_the_front = Ray {
+            t_min: 8.0,
+            ..through
+        };
+        let hit = torus.intersect_ray(&past_the_front).unwrap();
+        assert_almost_eq!(hit.t, 8.5);
+        assert_almost_eq!(hit.normal.0, Vector::unitz().0);
+        assert!(!hit.front_face);
+        let past_the_tube = Ray {
+            t_min: 9.0,
+            ..through
+        };
+        let hit = torus.intersect_ray(&past_the_tube).unwrap();
+        assert_almost_eq!(hit.t, 11.5);
+        assert_almost_eq!(hit.normal.0, Vector::unitz().0);
+        assert!(hit.front_face);
+
+        // Down the hole along the axis.
+        let down_the_hole = Ray::new(vector(0.0, 5.0, -10.0), -Vector::unity());
+        assert_almost_eq!(torus.intersect_ray(&down_the_hole), None);
+
+        // Onto the top of the tube, where v is a quarter turn around it.
+        let onto_the_top = Ray::new(vector(2.0, 5.0, -10.0), -Vector::unity());
+        let (tangent, bitangent) = Vector::unity().orthonormal_basis();
+        let local_x = tangent.0.dot(&Vector::unitx().0);
+        let local_y = bitangent.0.dot(&Vector::unitx().0);
+        let u = local_y.atan2(local_x) / (2.0 * f32::consts::PI);
+        assert_almost_eq!(
+            torus.intersect_ray(&onto_the_top),
+            Some(Intersection {
+                position: vector(2.0, 0.5, -10.0),
+                t: 4.5,
+                normal: Vector::unity(),
+                geometric_normal: Vector::unity(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (if u < 0.0 { u + 1.0 } else { u }, 0.25),
+                time: 0.0,
+                object: &torus,
+            })
+        );
+
+        // Just inside the outer edge of the ring, from far away.
+        let grazing = Ray::new(vector(2.4, 0.0, 100.0), -Vector::unitz());
+        let hit = torus.intersect_ray(&grazing).unwrap();
+        assert!((hit.t - (110.0 - (2.5f32 * 2.5 - 2.4 * 2.4).sqrt())).abs() < 1e-4);
+        assert!((hit.normal.0.len() - 1.0).abs() < 1e-6);
+        let missing = Ray::new(vector(2.6, 0.0, 100.0), -Vector::unitz());
+        assert_almost_eq!(torus.intersect_ray(&missing), None);
+
+        let bounds = torus.bounding_box().unwrap();
+        assert_almost_eq!(bounds.min, vector(-2.5, -0.5, -12.5));
+        assert_almost_eq!(bounds.max, vector(2.5, 0.5, -7.5));
+
+        // Tilted, every hit lies on the surface.
+        let tilted = Torus {
+            axis: vector(1.0, 2.0, 3.0).normalized(),
+            ..torus
+        };
+        let mut hits = 0;
+        for i in 0..64 {
+            let angle = i as f32 / 64.0 * 2.0 * f32::consts::PI;
+            let target = vector(angle.cos() * 2.0, angle.sin() * 2.0, -10.0);
+            let eye = vector(1.0, 3.0, 0.0);
+            let ray = Ray::new(eye, (target - eye).normalized());
+            if let Some(hit) = tilted.intersect_ray(&ray) {
+                hits += 1;
+                let local = tilted.axis.to_local(&(hit.position - tilted.center));
+                let ring_distance = (local.x * local.x + local.y * local.y).sqrt() - 2.0;
+                let tube_distance = (ring_distance * ring_distance + local.z * local.z).sqrt();
+                assert!((tube_distance - 0.5).abs() < 1e-4, "{:?}", ray);
+                assert_close(ray.at(hit.t), hit.position);
+                assert!((hit.normal.0.len() - 1.0).abs() < 1e-6);
+            }
+        }
+        assert!(hits > 32, "{} hits", hits);
+    }
+}
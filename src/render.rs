@@ -1,33 +1,407 @@
 use crate::image::Image;
-use crate::scene::{trace_ray, Camera, Sphere};
-
-pub fn render(
-    spheres: &[Sphere],
-    camera: &Camera,
-    width: usize,
-    height: usize,
-    bounces: usize,
-) -> Image {
+use crate::integrator::{AmbientOcclusion, Integrator, Normals, Whitted};
+use crate::material::Color;
+use crate::path::PathTracer;
+use crate::sampler::Sampler;
+use crate::scene::{Ray, Scene};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+// How the light arriving at the camera is computed, see RenderSettings::integrator.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RenderMode {
+    // Direct lighting from the scene's lights plus reflections and refractions. Fast and noise free
+    // for mirrors and glass, rough microfacet surfaces reflect in a randomly sampled direction so
+    // they need more samples per pixel to look smooth.
+    Classic,
+    // Monte Carlo path tracing with global illumination and emissive surfaces, needs many samples
+    // per pixel to converge.
+    PathTracing,
+    // How exposed surfaces are to their surroundings within the given distance.
+    AmbientOcclusion { distance: f32 },
+    // The surface normals as colors.
+    Normals,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub bounces: usize,
+    // The number of worker threads to render with, 0 means one per available CPU core.
+    pub threads: usize,
+    // Rays shot through every pixel, with a single sample the ray goes through the pixel's center.
+    pub samples: usize,
+    // Renders with the same seed are identical.
+    pub seed: u64,
+    pub mode: RenderMode,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            width: 800,
+            height: 600,
+            bounces: 3,
+            threads: 0,
+            samples: 1,
+            seed: 0,
+            mode: RenderMode::Classic,
+        }
+    }
+}
+
+impl RenderSettings {
+    pub fn integrator(&self) -> Box<dyn Integrator> {
+        match self.mode {
+            RenderMode::Classic => Box::new(Whitted {
+                bounces: self.bounces,
+            }),
+            RenderMode::PathTracing => Box::new(PathTracer {
+                max_bounces: self.bounces,
+            }),
+            RenderMode::AmbientOcclusion { distance } => Box::new(AmbientOcclusion { distance }),
+            RenderMode::Normals => Box::new(Normals),
+        }
+    }
+
+    pub fn thread_count(&self) -> usize {
+        match self.threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+    }
+}
+
+// Renders the scene with the integrator picked by its settings.
+pub fn render(scene: &Scene) -> Image {
+    render_with(scene, scene.settings.integrator().as_ref())
+}
+
+pub fn render_with(scene: &Scene, integrator: &dyn Integrator) -> Image {
+    let threads = scene.settings.thread_count();
+    if threads > 1 {
+        render_parallel(scene, integrator, threads)
+    } else {
+        render_serial(scene, integrator)
+    }
+}
+
+fn render_serial(scene: &Scene, integrator: &dyn Integrator) -> Image {
+    let settings = &scene.settings;
+    let mut image = Image::new(settings.width, settings.height);
+    let progress = Progress::new(settings.width * settings.height);
+    for i in 0..settings.width {
+        for j in 0..settings.height {
+            image.set_color(i, j, render_pixel(scene, integrator, i, j));
+            progress.advance(1);
+        }
+    }
+    image
+}
+
+// The image is split into scanlines which the worker threads pick up one by one until there are
+// none left. Every pixel is computed exactly the same way as in the serial path so the results
+// are identical no matter how many threads are used.
+fn render_parallel(scene: &Scene, integrator: &dyn Integrator, threads: usize) -> Image {
+    let (width, height) = (scene.settings.width, scene.settings.height);
+    let progress = Progress::new(width * height);
+    let next_row = AtomicUsize::new(0);
+    let rows = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(height))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut rows = Vec::new();
+                    loop {
+                        let j = next_row.fetch_add(1, Ordering::Relaxed);
+                        if j >= height {
+                            return rows;
+                        }
+                        let row: Vec<Color> = (0..width)
+                            .map(|i| render_pixel(scene, integrator, i, j))
+                            .collect();
+                        progress.advance(width);
+                        rows.push((j, row));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Render thread panicked"))
+            .collect::<Vec<_>>()
+    });
+
     let mut image = Image::new(width, height);
-    let pixels_total = width * height;
-    let mut pixels_done = 0;
-    let mut percent = 0;
-    for i in 0..width {
-        for j in 0..height {
-            // -1s here because we want to provide x and y coordinates between 0 and 1 inclusive
-            let ray = camera.screen_ray(
-                i as f32 / (width - 1) as f32,
-                j as f32 / (height - 1) as f32,
-            );
-            let color = trace_ray(&spheres, &ray, bounces);
+    for (j, row) in rows {
+        for (i, color) in row.into_iter().enumerate() {
             image.set_color(i, j, color);
-            pixels_done += 1;
-            let new_percent = pixels_done * 100 / pixels_total;
-            if new_percent != percent {
-                eprintln!("{}% done...", new_percent);
-                percent = new_percent;
-            }
         }
     }
     image
 }
+
+fn render_pixel(scene: &Scene, integrator: &dyn Integrator, i: usize, j: usize) -> Color {
+    let settings = &scene.settings;
+    // Every pixel gets its own random number stream so the result doesn't depend on the order the
+    // pixels are rendered in.
+    let mut sampler = Sampler::new(settings.seed, (j * settings.width + i) as u64);
+    let samples = settings.samples.max(1);
+    // When the number of samples is a perfect square the pixel is divided into a grid of cells and
+    // every sample is jittered within its own cell, which spreads them more evenly than jittering
+    // within the whole pixel.
+    let grid = (samples as f32).sqrt() as usize;
+    let stratified = grid * grid == samples;
+    let mut color = Color::new_black();
+    for sample in 0..samples {
+        let (dx, dy) = if samples == 1 {
+            (0.5, 0.5)
+        } else if stratified {
+            (
+                ((sample % grid) as f32 + sampler.next_f32()) / grid as f32,
+                ((sample / grid) as f32 + sampler.next_f32()) / grid as f32,
+            )
+        } else {
+            (sampler.next_f32(), sampler.next_f32())
+        };
+        // -1s here because we want to provide x and y coordinates between 0 and 1 inclusive, pixel
+        // centers lie exactly on the screen edges so samples near them are clamped.
+        let x = ((i as f32 + dx - 0.5) / (settings.width - 1) as f32).clamp(0.0, 1.0);
+        let y = ((j as f32 + dy - 0.5) / (settings.height - 1) as f32).clamp(0.0, 1.0);
+        // Pinhole cameras don't need lens samples, not taking them keeps their renders the same.
+        let camera = &scene.camera;
+        let ray = if camera.aperture > 0.0 {
+            camera.lens_ray(x, y, sampler.next_f32(), sampler.next_f32())
+        } else {
+            camera.screen_ray(x, y)
+        };
+        // Likewise for the time, which only matters when the shutter stays open.
+        let time = if camera.shutter_close > camera.shutter_open {
+            camera.shutter_time(sampler.next_f32())
+        } else {
+            camera.shutter_open
+        };
+        let ray = Ray { time, ..ray };
+        color += integrator.li(scene, &ray, &mut sampler);
+    }
+    color / samples as f32
+}
+
+// Reports the percentage of pixels done to stderr, can be shared between threads.
+struct Progress {
+    total: usize,
+    done: AtomicUsize,
+    // The last percentage reported. Workers race to report, the lock makes sure every percentage
+    // is printed once and in order.
+    reported: Mutex<usize>,
+}
+
+impl Progress {
+    fn new(total: usize) -> Progress {
+        Progress {
+            total,
+            done: AtomicUsize::new(0),
+            reported: Mutex::new(0),
+        }
+    }
+
+    fn advance(&self, pixels: usize) {
+        let done = self.done.fetch_add(pixels, Ordering::Relaxed) + pixels;
+        let percent = done * 100 / self.total;
+        if percent != (done - pixels) * 100 / self.total {
+            let mut reported = self.reported.lock().unwrap();
+            if percent > *reported {
+                eprintln!("{}% done...", percent);
+                *reported = percent;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::Image;
+    use crate::integrator::Integrator;
+    use crate::light::{DirectionalLight, Light};
+    use crate::material::{Color, Material};
+    use crate::mesh::Triangle;
+    use crate::render::{render, render_with, RenderMode, RenderSettings};
+    use crate::sampler::Sampler;
+    use crate::scene::{Motion, Plane, Ray, Scene, Shape, Sphere, Vector};
+    use crate::testing::camera;
+    use std::f32;
+
+    fn test_scene(settings: RenderSettings) -> Scene {
+        let shapes: Vec<Box<dyn Shape>> = vec![
+            Box::new(Sphere {
+                center: Vector {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -5.0,
+                },
+                radius: 1.0,
+                material: Material::diffuse(Color::new_red()),
+                motion: Motion::default(),
+            }),
+            Box::new(Sphere {
+                center: Vector {
+                    x: 1.0,
+                    y: 1.0,
+                    z: -4.0,
+                },
+                radius: 1.0,
+                material: Material::diffuse(Color::new_green()),
+                motion: Motion::default(),
+            }),
+            Box::new(Plane {
+                point: Vector {
+                    x: 0.0,
+                    y: -2.0,
+                    z: 0.0,
+                },
+                normal: Vector::unity(),
+                material: Material::diffuse(Color::new_white()),
+            }),
+        ];
+        let camera = camera(4.0 / 3.0);
+        let lights: Vec<Box<dyn Light>> = vec![Box::new(DirectionalLight {
+            direction: Vector {
+                x: 0.5,
+                y: -1.0,
+                z: -1.0,
+            }
+            .normalized(),
+            color: Color::new_white(),
+            intensity: 1.0,
+        })];
+        Scene::new(shapes, lights, camera, settings)
+    }
+
+    #[test]
+    fn test_parallel_render_matches_serial() {
+        for &mode in &[
+            RenderMode::Classic,
+            RenderMode::PathTracing,
+            RenderMode::AmbientOcclusion { distance: 1.0 },
+            RenderMode::Normals,
+        ] {
+            for &samples in &[1, 4, 5] {
+                let mut scene = test_scene(RenderSettings {
+                    width: 40,
+                    height: 30,
+                    bounces: 2,
+                    threads: 1,
+                    samples,
+                    seed: 0,
+                    mode,
+                });
+                let serial = render(&scene);
+                for threads in &[2, 3, 8, 64] {
+                    scene.settings.threads = *threads;
+                    let parallel = render(&scene);
+                    assert!(
+                        serial == parallel,
+                        "{:?}, {} samples, {} threads",
+                        mode,
+                        samples,
+                        threads
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_antialiasing() {
+        // A white triangle covering the right half of the screen, its left edge runs through the
+        // middle of the 21st column of pixels.
+        let triangle: Vec<Box<dyn Shape>> = vec![Box::new(Triangle {
+            a: Vector {
+                x: 0.0123,
+                y: -10.0,
+                z: -1.0,
+            },
+            b: Vector {
+                x: 10.0,
+                y: -10.0,
+                z: -1.0,
+            },
+            c: Vector {
+                x: 0.0123,
+                y: 10.0,
+                z: -1.0,
+            },
+            material: Material::diffuse(Color::new_white()),
+        })];
+        let camera = camera(4.0 / 3.0);
+        let settings = RenderSettings {
+            width: 40,
+            height: 30,
+            bounces: 0,
+            threads: 1,
+            samples: 16,
+            seed: 1,
+            mode: RenderMode::Classic,
+        };
+        let light: Vec<Box<dyn Light>> = vec![Box::new(DirectionalLight {
+            direction: -Vector::unitz(),
+            color: Color::new_white(),
+            intensity: f32::consts::PI,
+        })];
+        let mut scene = Scene::new(triangle, light, camera, settings);
+        let image = render(&scene);
+        assert!(image == render(&scene));
+        let row = |image: &Image| -> Vec<f32> {
+            (0..settings.width)
+                .map(|x| image.get_color(x, settings.height / 2).r)
+                .collect()
+        };
+        let antialiased = row(&image);
+        assert!(
+            antialiased[20] > 0.5 && antialiased[20] < 0.9,
+            "{:?}",
+            antialiased
+        );
+
+        scene.settings.seed = 2;
+        assert!(image != render(&scene));
+
+        scene.settings.samples = 1;
+        let aliased = row(&render(&scene));
+        assert!(
+            aliased.iter().all(|&r| r == 0.0 || r > 0.7),
+            "{:?}",
+            aliased
+        );
+    }
+
+    #[test]
+    fn test_render_with_custom_integrator() {
+        // Shows which way the camera rays point, averaged over the samples.
+        struct Directions;
+
+        impl Integrator for Directions {
+            fn li(&self, _scene: &Scene, ray: &Ray, _sampler: &mut Sampler) -> Color {
+                Color::new(ray.dir.0.x, ray.dir.0.y, -ray.dir.0.z)
+            }
+        }
+
+        let scene = test_scene(RenderSettings {
+            width: 3,
+            height: 3,
+            threads: 1,
+            ..RenderSettings::default()
+        });
+        let image = render_with(&scene, &Directions);
+        let center = image.get_color(1, 1);
+        assert!(
+            center.r.abs() < 1e-6 && center.g.abs() < 1e-6,
+            "{:?}",
+            center
+        );
+        assert!(image.get_color(0, 1).r < 0.0);
+        assert!(image.get_color(2, 1).r > 0.0);
+        assert!(image != render(&scene));
+    }
+}
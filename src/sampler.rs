@@ -0,0 +1,195 @@
+use crate::scene::{UnitVector, Vector};
+use std::f32;
+
+// A source of random numbers for rendering. It's a PCG32 generator (see https://www.pcg-random.org/)
+// so sequences depend only on the seed and the stream, which makes renders reproducible across
+// runs, thread counts and machines.
+#[derive(Clone, Debug)]
+pub struct Sampler {
+    state: u64,
+    increment: u64,
+}
+
+const MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+
+impl Sampler {
+    // Generators with the same seed but different streams produce independent sequences, the
+    // renderer uses the pixel index as the stream.
+    pub fn new(seed: u64, stream: u64) -> Sampler {
+        let mut sampler = Sampler {
+            state: 0,
+            increment: (stream << 1) | 1,
+        };
+        sampler.next_u32();
+        sampler.state = sampler.state.wrapping_add(seed);
+        sampler.next_u32();
+        sampler
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old_state = self.state;
+        self.state = old_state
+            .wrapping_mul(MULTIPLIER)
+            .wrapping_add(self.increment);
+        let xorshifted = (((old_state >> 18) ^ old_state) >> 27) as u32;
+        let rotation = (old_state >> 59) as u32;
+        xorshifted.rotate_right(rotation)
+    }
+
+    // Returns a number in the [0.0, 1.0) range.
+    pub fn next_f32(&mut self) -> f32 {
+        // Only 24 bits fit in the f32 mantissa, using more could round up to 1.0.
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+// Maps a pair of uniform [0.0, 1.0) numbers to a direction in the hemisphere around normal, with
+// a probability density proportional to the cosine of the angle between the two (cos / pi). Points
+// are picked uniformly on a disk and projected up onto the hemisphere (Malley's method).
+pub fn cosine_hemisphere(normal: &UnitVector, u: f32, v: f32) -> UnitVector {
+    let radius = u.sqrt();
+    let angle = 2.0 * f32::consts::PI * v;
+    let local = Vector {
+        x: radius * angle.cos(),
+        y: radius * angle.sin(),
+        z: (1.0 - u).max(0.0).sqrt(),
+    };
+    UnitVector(normal.from_local(&local))
+}
+
+// Maps a pair of uniform [0.0, 1.0) numbers to a uniformly distributed point on the unit disk.
+// Squares around the center of the unit square become circles around the center of the disk
+// (Shirley and Chiu's concentric mapping), which keeps stratified samples stratified.
+pub fn concentric_disk(u: f32, v: f32) -> (f32, f32) {
+    let (x, y) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if x == 0.0 && y == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (radius, angle) = if x.abs() > y.abs() {
+        (x, f32::consts::FRAC_PI_4 * (y / x))
+    } else {
+        (y, f32::consts::FRAC_PI_2 - f32::consts::FRAC_PI_4 * (x / y))
+    };
+    (radius * angle.cos(), radius * angle.sin())
+}
+
+// Like concentric_disk but for a regular polygon with the given number of sides inscribed in the
+// unit circle, with a corner pointing along y. u picks one of the triangles between the center
+// and the sides and what's left of it picks a point within it.
+pub fn regular_polygon(sides: u32, u: f32, v: f32) -> (f32, f32) {
+    let scaled = u * sides as f32;
+    let side = (scaled.floor() as u32).min(sides - 1);
+    let u = (scaled - side as f32).min(1.0);
+    let corner = |i: u32| {
+        let angle = f32::consts::FRAC_PI_2 + 2.0 * f32::consts::PI * i as f32 / sides as f32;
+        (angle.cos(), angle.sin())
+    };
+    let (a, b) = (corner(side), corner(side + 1));
+    // The square root makes points uniform over the triangle's area rather than its height.
+    let distance = u.sqrt();
+    (
+        distance * (a.0 * (1.0 - v) + b.0 * v),
+        distance * (a.1 * (1.0 - v) + b.1 * v),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_almost_eq;
+    use crate::sampler::{concentric_disk, cosine_hemisphere, regular_polygon, Sampler};
+    use crate::scene::Vector;
+    use crate::traits::AlmostEqual;
+    use std::f32;
+
+    #[test]
+    fn test_sampler_matches_reference_implementation() {
+        // The first numbers generated by the pcg32-demo program from the PCG C library.
+        let mut sampler = Sampler::new(42, 54);
+        let expected = [
+            0xa15c02b7, 0x7b47f409, 0xba1d3330, 0x83d2f293, 0xbfa4784b, 0xcbed606e,
+        ];
+        for &number in &expected {
+            assert_eq!(sampler.next_u32(), number);
+        }
+    }
+
+    #[test]
+    fn test_sampler_floats() {
+        let mut sampler = Sampler::new(1, 2);
+        let numbers: Vec<f32> = (0..10000).map(|_| sampler.next_f32()).collect();
+        assert!(numbers.iter().all(|n| (0.0..1.0).contains(n)));
+        let mean = numbers.iter().sum::<f32>() / numbers.len() as f32;
+        assert!((mean - 0.5).abs() < 0.01, "{}", mean);
+
+        let mut other_stream = Sampler::new(1, 3);
+        assert_ne!(numbers[0], other_stream.next_f32());
+    }
+
+    #[test]
+    fn test_cosine_hemisphere() {
+        let normal = Vector {
+            x: 1.0,
+            y: -2.0,
+            z: 0.5,
+        }
+        .normalized();
+        let mut sampler = Sampler::new(3, 4);
+        let count = 20000;
+        let mut cosine_sum = 0.0;
+        for _ in 0..count {
+            let direction = cosine_hemisphere(&normal, sampler.next_f32(), sampler.next_f32());
+            assert!((direction.0.len() - 1.0).abs() < 1e-5);
+            let cosine = direction.0.dot(&normal.0);
+            assert!(cosine >= 0.0);
+            cosine_sum += cosine;
+        }
+        // The mean of cos over the cos / pi density is 2 / 3.
+        let mean = cosine_sum / count as f32;
+        assert!((mean - 2.0 / 3.0).abs() < 0.01, "{}", mean);
+
+        assert_almost_eq!(cosine_hemisphere(&normal, 0.0, 0.0), normal);
+    }
+
+    #[test]
+    fn test_lens_shapes() {
+        assert_eq!(concentric_disk(0.5, 0.5), (0.0, 0.0));
+        let (x, y) = concentric_disk(1.0, 0.5);
+        assert!((x - 1.0).abs() < 1e-6 && y.abs() < 1e-6);
+        assert_eq!(regular_polygon(6, 0.0, 0.0), (0.0, 0.0));
+        // The far edge of the first triangle, at its first corner.
+        let (x, y) = regular_polygon(6, 0.1666, 0.0);
+        assert!(x.abs() < 1e-3 && (y - 1.0).abs() < 1e-3);
+
+        let mut sampler = Sampler::new(5, 6);
+        let count = 20000;
+        for &sides in &[3, 5, 8] {
+            // Points are inside the polygon and spread evenly, with their mean at its center.
+            let apothem = (f32::consts::PI / sides as f32).cos();
+            let (mut x_sum, mut y_sum) = (0.0, 0.0);
+            for _ in 0..count {
+                let (x, y) = regular_polygon(sides, sampler.next_f32(), sampler.next_f32());
+                let angle = y.atan2(x) - f32::consts::FRAC_PI_2;
+                let sector = 2.0 * f32::consts::PI / sides as f32;
+                let offset = angle.rem_euclid(sector) - sector / 2.0;
+                let distance = (x * x + y * y).sqrt();
+                assert!(distance * offset.cos() <= apothem + 1e-5);
+                x_sum += x;
+                y_sum += y;
+            }
+            assert!((x_sum / count as f32).abs() < 0.01);
+            assert!((y_sum / count as f32).abs() < 0.01);
+        }
+        let mut inner = 0;
+        for _ in 0..count {
+            let (x, y) = concentric_disk(sampler.next_f32(), sampler.next_f32());
+            let distance_squared = x * x + y * y;
+            assert!(distance_squared <= 1.0 + 1e-5);
+            if distance_squared < 0.25 {
+                inner += 1;
+            }
+        }
+        // A circle of half the radius has a quarter of the area.
+        let fraction = inner as f32 / count as f32;
+        assert!((fraction - 0.25).abs() < 0.01, "{}", fraction);
+    }
+}
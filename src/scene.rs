@@ -1,7 +1,28 @@
-use crate::material::{Color, Material};
+use crate::bvh::{Aabb, Bvh, BvhStats};
+use crate::image::image_from_file;
+use crate::light::{DirectionalLight, Light, LightSample, PointLight};
+use crate::material::{Color, Material, Surface};
+use crate::mesh::{Mesh, Triangle};
+use crate::obj::load_obj;
+use crate::primitives::{Cone, Cuboid, Cylinder, Disk, Torus};
+use crate::render::{RenderMode, RenderSettings};
+use crate::sampler::{concentric_disk, regular_polygon};
+use crate::texture::{
+    Checkerboard, Filter, Gradient, ImageTexture, Marble, Noise, Stripes, Texture, Turbulence,
+    Wood, Wrap,
+};
 use crate::traits::AlmostEqual;
+use crate::transform::{Instance, Transform};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
 use std::f32;
+use std::fmt;
+use std::fs;
 use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::path::Path;
+use std::ptr;
+use std::sync::Arc;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Vector {
@@ -68,6 +89,19 @@ impl Vector {
     pub fn normalized(&self) -> UnitVector {
         UnitVector(*self / self.len())
     }
+
+    pub fn components(&self) -> [f32; 3] {
+        [self.x, self.y, self.z]
+    }
+
+    pub fn from_components([x, y, z]: [f32; 3]) -> Vector {
+        Vector { x, y, z }
+    }
+
+    pub fn max_abs_component(&self) -> f32 {
+        self.x.abs().max(self.y.abs()).max(self.z.abs())
+    }
+
     pub fn is_normalized(&self) -> bool {
         self.len().almost_equal(&1.0)
     }
@@ -89,6 +123,60 @@ impl UnitVector {
         let n = &normal.0;
         UnitVector(*ri - 2.0 * *n * ri.dot(n))
     }
+
+    // Bends the direction crossing a boundary between two media following Snell's law, normal
+    // pointing against it and eta being the ratio of the index of refraction on its side to the
+    // one on the other side. None when the light can't cross the boundary (total internal
+    // reflection).
+    pub fn refracted(&self, normal: &UnitVector, eta: f32) -> Option<UnitVector> {
+        let cos_incident = (-self.0.dot(&normal.0)).clamp(0.0, 1.0);
+        let sin_transmitted_squared = eta * eta * (1.0 - cos_incident * cos_incident);
+        if sin_transmitted_squared >= 1.0 {
+            return None;
+        }
+        let cos_transmitted = (1.0 - sin_transmitted_squared).sqrt();
+        Some(UnitVector(
+            self.0 * eta + normal.0 * (eta * cos_incident - cos_transmitted),
+        ))
+    }
+
+    // Returns two unit vectors that together with this one form an orthonormal basis, see
+    // "Building an Orthonormal Basis, Revisited" by Duff et al.
+    pub fn orthonormal_basis(&self) -> (UnitVector, UnitVector) {
+        let n = &self.0;
+        let sign = 1.0f32.copysign(n.z);
+        let a = -1.0 / (sign + n.z);
+        let b = n.x * n.y * a;
+        (
+            UnitVector(Vector {
+                x: 1.0 + sign * n.x * n.x * a,
+                y: sign * b,
+                z: -sign * n.x,
+            }),
+            UnitVector(Vector {
+                x: b,
+                y: sign + n.y * n.y * a,
+                z: -n.y,
+            }),
+        )
+    }
+
+    // Converts a vector given in the basis returned by orthonormal_basis (with this vector as the
+    // z axis) to world coordinates.
+    pub fn from_local(&self, local: &Vector) -> Vector {
+        let (tangent, bitangent) = self.orthonormal_basis();
+        tangent.0 * local.x + bitangent.0 * local.y + self.0 * local.z
+    }
+
+    // The inverse of from_local.
+    pub fn to_local(&self, world: &Vector) -> Vector {
+        let (tangent, bitangent) = self.orthonormal_basis();
+        Vector {
+            x: tangent.0.dot(world),
+            y: bitangent.0.dot(world),
+            z: self.0.dot(world),
+        }
+    }
 }
 
 impl AlmostEqual for UnitVector {
@@ -169,82 +257,323 @@ impl Neg for Vector {
     }
 }
 
+// Only the part of the ray between t_min and t_max (measured along dir from pos) can hit shapes.
+// Moving shapes are hit where they are at the ray's time, see Motion.
 #[derive(Copy, Clone, Debug)]
 pub struct Ray {
     pub pos: Vector,
     pub dir: UnitVector,
+    pub t_min: f32,
+    pub t_max: f32,
+    pub time: f32,
 }
 
 impl Ray {
-    fn forwarded(&self, distance: f32) -> Ray {
+    // An unbounded ray starting at pos.
+    pub fn new(pos: Vector, dir: UnitVector) -> Ray {
         Ray {
-            pos: self.pos + self.dir.0 * distance,
-            dir: self.dir,
+            pos,
+            dir,
+            t_min: 0.0,
+            t_max: f32::INFINITY,
+            time: 0.0,
         }
     }
 
-    pub fn reflected(&self, position: Vector, normal: &UnitVector) -> Ray {
-        Ray {
-            pos: position,
-            dir: self.dir.reflected(normal),
-        }
+    pub fn at(&self, t: f32) -> Vector {
+        self.pos + self.dir.0 * t
+    }
+
+    // Whether a hit at t lies within the ray's interval. The interval is closed at t_max so hits
+    // at exactly the same distance as the closest one found so far can still be considered (see
+    // ClosestHit).
+    pub fn contains(&self, t: f32) -> bool {
+        t > self.t_min && t <= self.t_max
+    }
+
+    pub fn reflected(&self, intersection: &Intersection) -> Ray {
+        intersection.spawn_ray(self.dir.reflected(&intersection.normal))
     }
 }
 
 impl AlmostEqual for Ray {
     fn almost_equal(&self, other: &Ray) -> bool {
-        self.pos.almost_equal(&other.pos) && self.dir.0.almost_equal(&other.dir.0)
+        self.pos.almost_equal(&other.pos)
+            && self.dir.0.almost_equal(&other.dir.0)
+            && self.t_min == other.t_min
+            && self.t_max == other.t_max
+            && self.time == other.time
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+// Where a moving shape is over time: offsets from where it rests at given times, interpolated
+// linearly in between and held before the first and after the last. Shapes without keyframes
+// don't move.
+#[derive(Clone, Debug, Default)]
+pub struct Motion {
+    keyframes: Vec<(f32, Vector)>,
+}
+
+impl Motion {
+    // The keyframes are (time, offset) pairs in any order.
+    pub fn new(mut keyframes: Vec<(f32, Vector)>) -> Motion {
+        keyframes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Motion { keyframes }
+    }
+
+    // Moving at a constant speed, by offset between time 0 and 1.
+    pub fn linear(offset: Vector) -> Motion {
+        Motion::new(vec![(0.0, Vector::zero()), (1.0, offset)])
+    }
+
+    pub fn is_static(&self) -> bool {
+        self.keyframes.is_empty()
+    }
+
+    pub fn offset(&self, time: f32) -> Vector {
+        let next = self.keyframes.iter().position(|&(t, _)| t > time);
+        match next {
+            _ if self.keyframes.is_empty() => Vector::zero(),
+            Some(0) => self.keyframes[0].1,
+            None => self.keyframes[self.keyframes.len() - 1].1,
+            Some(i) => {
+                let (t0, offset0) = self.keyframes[i - 1];
+                let (t1, offset1) = self.keyframes[i];
+                let fraction = (time - t0) / (t1 - t0);
+                offset0 * (1.0 - fraction) + offset1 * fraction
+            }
+        }
+    }
+
+    // The box containing everything inside bounds at any time. Between keyframes the offsets are
+    // blends of the ones at the keyframes, so the boxes at the keyframes are enough.
+    pub fn bounds(&self, bounds: &Aabb) -> Aabb {
+        if self.keyframes.is_empty() {
+            return *bounds;
+        }
+        self.keyframes
+            .iter()
+            .fold(Aabb::empty(), |swept, (_, offset)| {
+                swept.union(&Aabb {
+                    min: bounds.min + *offset,
+                    max: bounds.max + *offset,
+                })
+            })
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Sphere {
     pub center: Vector,
     pub radius: f32,
     pub material: Material,
+    pub motion: Motion,
 }
 
-impl Sphere {
-    pub fn intersect_ray<'a>(&'a self, ray: &Ray) -> Option<Intersection<'a>> {
+// Anything that can be hit by a ray and rendered.
+pub trait Shape: fmt::Debug + Send + Sync {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>>;
+
+    // Unbounded shapes (like planes) return None, they can't be put in a BVH and are tested
+    // against every ray instead.
+    fn bounding_box(&self) -> Option<Aabb>;
+
+    fn material(&self) -> &Material;
+
+    // Picks a direction from point towards the shape (where it is at the given time), used to
+    // sample emissive shapes as lights. Shapes that don't support it, or can't be sampled from the
+    // given point, return None.
+    fn sample_solid_angle(
+        &self,
+        _point: &Vector,
+        _time: f32,
+        _u: f32,
+        _v: f32,
+    ) -> Option<UnitVector> {
+        None
+    }
+
+    // The probability density (per unit solid angle) of sample_solid_angle returning direction,
+    // 0 when it never does.
+    fn solid_angle_pdf(&self, _point: &Vector, _time: f32, _direction: &UnitVector) -> f32 {
+        0.0
+    }
+
+    // Whether sample_solid_angle is implemented at all, only emissive shapes that have it are
+    // sampled as lights.
+    fn can_sample_solid_angle(&self) -> bool {
+        false
+    }
+}
+
+impl<S: Shape + ?Sized> Shape for Box<S> {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        (**self).intersect_ray(ray)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        (**self).bounding_box()
+    }
+
+    fn material(&self) -> &Material {
+        (**self).material()
+    }
+
+    fn sample_solid_angle(&self, point: &Vector, time: f32, u: f32, v: f32) -> Option<UnitVector> {
+        (**self).sample_solid_angle(point, time, u, v)
+    }
+
+    fn solid_angle_pdf(&self, point: &Vector, time: f32, direction: &UnitVector) -> f32 {
+        (**self).solid_angle_pdf(point, time, direction)
+    }
+
+    fn can_sample_solid_angle(&self) -> bool {
+        (**self).can_sample_solid_angle()
+    }
+}
+
+impl Shape for Sphere {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>> {
         // Math based on information found on
         // http://kylehalladay.com/blog/tutorial/math/2013/12/24/Ray-Sphere-Intersection.html
         //
-        let pos_to_center = self.center - ray.pos;
-        // No support for intersections with rays coming from inside the sphere at the moment.
-        if pos_to_center.len() <= self.radius {
-            return None;
-        }
+        let center = self.center_at(ray.time);
+        let pos_to_center = center - ray.pos;
         // tcenter is how far along the ray dir we need to go in order for the line orthogonal to
-        // the ray to cross the sphere's center. Let's call that point on the ray C.
+        // the ray to cross the sphere's center. Let's call that point on the ray C. It's negative
+        // if the center is behind the ray origin, the ray can still hit the sphere if it starts
+        // inside of it.
         let tcenter = pos_to_center.dot(&ray.dir.0);
-        // The sphere is in the opposite direction.
-        if tcenter < 0.0 {
-            return None;
-        }
         // We now have a right triangle with [ray.pos C] being one of its leg and [ray.pos
         // sphere.center] being its hypotenuse. The distance between C and self.center is what we
         // need to find out and its the remaining leg of the triangle – let's use the Pythagorean
-        // theorem. We'll call the [C self.center] distance d.
-        let d = (pos_to_center.len().powf(2.0) - tcenter.powf(2.0)).sqrt();
+        // theorem. We'll call the [C self.center] distance d. Rounding errors can make d squared
+        // slightly negative for rays going through the center.
+        let d_squared = (pos_to_center.dot(&pos_to_center) - tcenter * tcenter).max(0.0);
         // If we miss the sphere totally the distance d will be greater than the radius, let's bail
         // in that case.
-        if d > self.radius {
+        let radius_squared = self.radius * self.radius;
+        if d_squared > radius_squared {
             return None;
         }
         // Now we have two right triangles with self.radius being its hypotenuse and d forming one
         // of its legs. The remaining leg is a distance tdelta that we'll use to move forward and
         // backward along the ray starting with point C in order to get two points at which we
-        // intersect the sphere. Again – just Pythagorean theorem at work here.
-        let tdelta = (self.radius.powf(2.0) - d.powf(2.0)).sqrt();
-        // We can now calculate two points at which we cross the sphere, but we only need the
-        // closer one so let's do just that.
-        let intersection_point = ray.forwarded(tcenter - tdelta).pos;
+        // intersect the sphere. Again – just Pythagorean theorem at work here.
+        let tdelta = (radius_squared - d_squared).sqrt();
+        // The closer point is where the ray enters the sphere, if it's before the start of the ray
+        // the ray starts inside and we take the point where it leaves. If the closer point is past
+        // the end of the ray so is the other one.
+        let (t, front_face) = if ray.contains(tcenter - tdelta) {
+            (tcenter - tdelta, true)
+        } else if tcenter - tdelta <= ray.t_min && ray.contains(tcenter + tdelta) {
+            (tcenter + tdelta, false)
+        } else {
+            return None;
+        };
+        let outward_normal = (ray.at(t) - center).normalized();
+        let normal = if front_face {
+            outward_normal
+        } else {
+            -outward_normal
+        };
+        // t can be way off for big spheres, projecting the point back onto the surface
+        // makes its error depend only on the magnitude of the sphere's coordinates.
         Some(Intersection {
-            position: intersection_point,
-            normal: (intersection_point - self.center).normalized(),
-            sphere: &self,
+            position: center + outward_normal.0 * self.radius,
+            t,
+            time: ray.time,
+            position_error: rounding_error(center.max_abs_component() + self.radius),
+            uv: Sphere::uv(&outward_normal),
+            normal,
+            geometric_normal: normal,
+            front_face,
+            object: self,
         })
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = Vector {
+            x: self.radius,
+            y: self.radius,
+            z: self.radius,
+        };
+        Some(self.motion.bounds(&Aabb {
+            min: self.center - extent,
+            max: self.center + extent,
+        }))
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    // Directions are picked uniformly within the cone the sphere fills as seen from point, which
+    // wastes no samples on directions missing it no matter how small it looks. See "Monte Carlo
+    // Techniques for Direct Lighting Calculations" by Shirley et al.
+    fn sample_solid_angle(&self, point: &Vector, time: f32, u: f32, v: f32) -> Option<UnitVector> {
+        let (axis, _, one_minus_cos_max) = self.visible_cone(point, time)?;
+        let one_minus_cos = u * one_minus_cos_max;
+        let sin = (one_minus_cos * (2.0 - one_minus_cos)).max(0.0).sqrt();
+        let angle = 2.0 * f32::consts::PI * v;
+        let local = Vector {
+            x: sin * angle.cos(),
+            y: sin * angle.sin(),
+            z: 1.0 - one_minus_cos,
+        };
+        Some(UnitVector(axis.from_local(&local)))
+    }
+
+    fn solid_angle_pdf(&self, point: &Vector, time: f32, direction: &UnitVector) -> f32 {
+        match self.visible_cone(point, time) {
+            Some((axis, cos_max, one_minus_cos_max)) if direction.0.dot(&axis.0) >= cos_max => {
+                1.0 / (2.0 * f32::consts::PI * one_minus_cos_max)
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn can_sample_solid_angle(&self) -> bool {
+        true
+    }
+}
+
+impl Sphere {
+    pub fn center_at(&self, time: f32) -> Vector {
+        self.center + self.motion.offset(time)
+    }
+
+    // Spherical coordinates of the point with the given outward normal: u is the longitude, going
+    // around the y axis from -x through +z, v the latitude, going from the bottom (-y) to the
+    // top.
+    fn uv(normal: &UnitVector) -> (f32, f32) {
+        let n = &normal.0;
+        let longitude = (-n.z).atan2(n.x) + f32::consts::PI;
+        let latitude = (-n.y).clamp(-1.0, 1.0).acos();
+        (
+            longitude / (2.0 * f32::consts::PI),
+            latitude / f32::consts::PI,
+        )
+    }
+
+    // The cone of directions the sphere is seen in from point at the given time: its axis, the
+    // cosine of its half angle and 1 minus that cosine. There's no such cone from inside of the
+    // sphere, nor from points on its surface (which, given rounding errors, could be on either
+    // side of it).
+    fn visible_cone(&self, point: &Vector, time: f32) -> Option<(UnitVector, f32, f32)> {
+        let to_center = self.center_at(time) - *point;
+        let sin_squared = self.radius * self.radius / to_center.dot(&to_center);
+        if sin_squared >= 1.0 - 1e-4 {
+            return None;
+        }
+        let cos_max = (1.0 - sin_squared).sqrt();
+        // 1 - cos_max computed directly would lose all precision for small or distant spheres.
+        Some((
+            to_center.normalized(),
+            cos_max,
+            sin_squared / (1.0 + cos_max),
+        ))
+    }
 }
 
 impl AlmostEqual for Sphere {
@@ -253,18 +582,127 @@ impl AlmostEqual for Sphere {
     }
 }
 
+// A conservative bound on the rounding error of a short computation (a handful of additions and
+// multiplications) involving values no larger than magnitude.
+pub(crate) fn rounding_error(magnitude: f32) -> f32 {
+    magnitude * 8.0 * f32::EPSILON
+}
+
+// An infinite plane going through point, it can be hit from both sides. Its texture coordinates
+// are the distances from point along two directions perpendicular to the normal (see
+// UnitVector::orthonormal_basis).
+#[derive(Clone, Debug)]
+pub struct Plane {
+    pub point: Vector,
+    pub normal: UnitVector,
+    pub material: Material,
+}
+
+impl Shape for Plane {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let n = &self.normal.0;
+        // The cosine of the angle between the ray and the normal, zero means the ray is parallel
+        // to the plane and never hits it.
+        let cos = ray.dir.0.dot(n);
+        if cos == 0.0 {
+            return None;
+        }
+        let t = (self.point - ray.pos).dot(n) / cos;
+        if !ray.contains(t) {
+            return None;
+        }
+        // The normal we report always faces the side the ray came from.
+        let front_face = cos < 0.0;
+        let position = ray.at(t);
+        let (tangent, bitangent) = self.normal.orthonormal_basis();
+        let offset = position - self.point;
+        let normal = if front_face {
+            self.normal
+        } else {
+            -self.normal
+        };
+        Some(Intersection {
+            position,
+            t,
+            position_error: rounding_error(
+                position.max_abs_component() + self.point.max_abs_component(),
+            ),
+            uv: (tangent.0.dot(&offset), bitangent.0.dot(&offset)),
+            time: ray.time,
+            normal,
+            geometric_normal: normal,
+            front_face,
+            object: self,
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Intersection<'a> {
     pub position: Vector,
+    // How far along the ray the hit is.
+    pub t: f32,
+    // Always faces the side the ray came from, even when it hits the back (inside) of a surface.
     pub normal: UnitVector,
-    pub sphere: &'a Sphere,
+    // The normal of the actual surface, facing the same side as normal. They're the same except
+    // for shapes that bend the normal for shading, like meshes with vertex normals.
+    pub geometric_normal: UnitVector,
+    // Whether the ray hit the outside of a closed shape, or the side a flat shape's normal points
+    // to. The normal above has been flipped when this is false.
+    pub front_face: bool,
+    // A bound on the rounding error of every coordinate of position, see spawn_ray.
+    pub position_error: f32,
+    // The texture coordinates of the point, how they're laid out is up to the shape.
+    pub uv: (f32, f32),
+    // The time of the ray that hit, rays leaving the surface keep it.
+    pub time: f32,
+    pub object: &'a dyn Shape,
+}
+
+impl<'a> Intersection<'a> {
+    // Returns a ray leaving the surface in the given direction. The computed position may lie
+    // slightly on either side of the true surface, so starting secondary rays right at it can make
+    // them hit the same surface again (shadow acne). Instead the origin is moved along the
+    // geometric normal, just far enough to be on the right side no matter the rounding error. A
+    // shading normal wouldn't do, it isn't perpendicular to the surface so it can't tell which
+    // side of it a direction leads to.
+    pub fn spawn_ray(&self, dir: UnitVector) -> Ray {
+        let normal = self.geometric_normal.0;
+        let distance = self.position_error * (normal.x.abs() + normal.y.abs() + normal.z.abs());
+        let offset = if dir.0.dot(&normal) < 0.0 {
+            -distance
+        } else {
+            distance
+        };
+        Ray {
+            time: self.time,
+            ..Ray::new(self.position + normal * offset, dir)
+        }
+    }
 }
 
+// The error bound is an estimate rather than part of the result, so it's not compared. Texture
+// coordinates come out of trigonometry and divisions for most shapes, they're compared with a
+// looser tolerance.
 impl<'a> AlmostEqual for Intersection<'a> {
     fn almost_equal(&self, other: &Intersection) -> bool {
         self.position.almost_equal(&other.position)
+            && self.t.almost_equal(&other.t)
             && self.normal.almost_equal(&other.normal)
-            && self.sphere.almost_equal(other.sphere)
+            && self.geometric_normal.almost_equal(&other.geometric_normal)
+            && self.front_face == other.front_face
+            && almost_equal_with_epsilon(self.uv.0, other.uv.0, 1e-6)
+            && almost_equal_with_epsilon(self.uv.1, other.uv.1, 1e-6)
+            && self.time == other.time
+            && ptr::addr_eq(self.object, other.object)
     }
 }
 
@@ -277,13 +715,10 @@ impl AlmostEqual for f32 {
 impl<T: AlmostEqual> AlmostEqual for Option<T> {
     fn almost_equal(&self, other: &Option<T>) -> bool {
         match self {
-            None => match other {
-                None => true,
-                Some(_) => false,
-            },
+            None => other.is_none(),
             Some(v1) => match other {
                 None => false,
-                Some(v2) => v1.almost_equal(&v2),
+                Some(v2) => v1.almost_equal(v2),
             },
         }
     }
@@ -293,43 +728,289 @@ pub fn almost_equal_with_epsilon(a: f32, b: f32, epsilon: f32) -> bool {
     (a - b).abs() < epsilon
 }
 
+// How directions around the camera map to points on the image. Horizontal fields of view are
+// spread over the width of the image, the height follows from the aspect ratio.
+#[derive(Copy, Clone, Debug)]
+pub enum Projection {
+    // The usual pinhole projection which keeps straight lines straight, the field of view has to
+    // be below 180 degrees.
+    Perspective { fovx: Radians },
+    // Parallel rays, things keep their size regardless of their distance. The view is width
+    // units across.
+    Orthographic { width: f32 },
+    // Fisheye lenses can see up to 360 degrees across the image. In an equidistant one the
+    // distance from the center of the image is proportional to the angle from forward.
+    EquidistantFisheye { fovx: Radians },
+    // The distance is proportional to the sine of half the angle instead, which keeps areas in
+    // proportion.
+    EquisolidFisheye { fovx: Radians },
+    // The whole sphere of directions, with longitude going across the image and latitude down it.
+    // Panoramas like this usually have an aspect ratio of 2.
+    Equirectangular,
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct Camera {
     pub position: Vector,
-    // The forward and up vectors have to be normalized
+    // The forward and up vectors have to be normalized and perpendicular to each other, use
+    // Camera::new or Camera::look_at to get them right.
     pub forward: UnitVector,
     pub up: UnitVector,
     pub aspect_ratio: f32,
-    pub fovx: Radians,
+    pub projection: Projection,
+    // The radius of the lens, 0 for a pinhole camera with everything in focus. Otherwise only
+    // things focus_distance away (along forward) are sharp.
+    pub aperture: f32,
+    pub focus_distance: f32,
+    // The number of blades forming the aperture, which gives out of focus highlights its polygonal
+    // shape. 0 means a round aperture.
+    pub blades: u32,
+    // Rays get times between the shutter opening and closing, so anything moving in the meantime
+    // gets blurred.
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+// The reasons a camera can't be constructed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraError {
+    // The forward vector is zero (or the camera looks at its own position).
+    NoDirection,
+    // The up vector is zero or parallel to the forward vector.
+    UpParallelToForward,
+    // The horizontal field of view is not in the (0, 180) degrees range, or (0, 360] for
+    // fisheyes.
+    InvalidFov,
+    InvalidWidth,
+    InvalidAspectRatio,
+    InvalidAperture,
+    InvalidFocusDistance,
+    // An aperture can't be formed by 1 or 2 blades.
+    TooFewBlades,
+    // The shutter closes before it opens.
+    InvalidShutter,
+}
+
+impl fmt::Display for CameraError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            CameraError::NoDirection => "the camera doesn't look in any direction",
+            CameraError::UpParallelToForward => "the up vector is parallel to the view direction",
+            CameraError::InvalidFov => {
+                "the field of view has to be between 0 and 180 degrees (360 for fisheyes)"
+            }
+            CameraError::InvalidWidth => "the width of the view has to be positive",
+            CameraError::InvalidAspectRatio => "the aspect ratio has to be positive",
+            CameraError::InvalidAperture => "the aperture can't be negative",
+            CameraError::InvalidFocusDistance => "the focus distance has to be positive",
+            CameraError::TooFewBlades => "the aperture needs at least 3 blades",
+            CameraError::InvalidShutter => "the shutter can't close before it opens",
+        };
+        write!(f, "{}", message)
+    }
 }
 
+impl Error for CameraError {}
+
 impl Camera {
+    // Neither forward nor up need to be normalized and up only has to point somewhat upwards, the
+    // part of it that's parallel to forward is dropped. The camera is a pinhole one, see with_lens.
+    pub fn new(
+        position: Vector,
+        forward: Vector,
+        up: Vector,
+        projection: Projection,
+        aspect_ratio: f32,
+    ) -> Result<Camera, CameraError> {
+        let valid_projection = match projection {
+            Projection::Perspective { fovx } => fovx.0 > 0.0 && fovx.0 < f32::consts::PI,
+            Projection::EquidistantFisheye { fovx } | Projection::EquisolidFisheye { fovx } => {
+                fovx.0 > 0.0 && fovx.0 <= 2.0 * f32::consts::PI
+            }
+            Projection::Orthographic { width } => width > 0.0 && width.is_finite(),
+            Projection::Equirectangular => true,
+        };
+        if !valid_projection {
+            return Err(match projection {
+                Projection::Orthographic { .. } => CameraError::InvalidWidth,
+                _ => CameraError::InvalidFov,
+            });
+        }
+        if !(aspect_ratio > 0.0 && aspect_ratio.is_finite()) {
+            return Err(CameraError::InvalidAspectRatio);
+        }
+        let forward_length = forward.len();
+        if !(forward_length > 0.0 && forward_length.is_finite()) {
+            return Err(CameraError::NoDirection);
+        }
+        let forward = forward.normalized();
+        // Gram-Schmidt, what's left of up has to be long enough to normalize without losing
+        // precision (this allows for about 0.06 degrees between up and forward).
+        let up_length = up.len();
+        let up = up - forward.0 * up.dot(&forward.0);
+        if up.len() <= 1e-3 * up_length {
+            return Err(CameraError::UpParallelToForward);
+        }
+        Ok(Camera {
+            position,
+            forward,
+            up: up.normalized(),
+            aspect_ratio,
+            projection,
+            aperture: 0.0,
+            focus_distance: 1.0,
+            blades: 0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        })
+    }
+
+    // Gives the camera a lens with the given radius, focused at focus_distance, for depth of field.
+    pub fn with_lens(
+        self,
+        aperture: f32,
+        focus_distance: f32,
+        blades: u32,
+    ) -> Result<Camera, CameraError> {
+        if !(aperture >= 0.0 && aperture.is_finite()) {
+            return Err(CameraError::InvalidAperture);
+        }
+        if !(focus_distance > 0.0 && focus_distance.is_finite()) {
+            return Err(CameraError::InvalidFocusDistance);
+        }
+        if blades == 1 || blades == 2 {
+            return Err(CameraError::TooFewBlades);
+        }
+        Ok(Camera {
+            aperture,
+            focus_distance,
+            blades,
+            ..self
+        })
+    }
+
+    // Keeps the shutter open from open to close, an instant when they're the same (which is the
+    // default, at time 0).
+    pub fn with_shutter(self, open: f32, close: f32) -> Result<Camera, CameraError> {
+        if !(open.is_finite() && close.is_finite() && open <= close) {
+            return Err(CameraError::InvalidShutter);
+        }
+        Ok(Camera {
+            shutter_open: open,
+            shutter_close: close,
+            ..self
+        })
+    }
+
+    // The time at which the shutter has been open for the given [0.0, 1.0) fraction of the time.
+    pub fn shutter_time(&self, fraction: f32) -> f32 {
+        self.shutter_open + (self.shutter_close - self.shutter_open) * fraction
+    }
+
+    // A camera at eye looking towards target.
+    pub fn look_at(
+        eye: Vector,
+        target: Vector,
+        up_hint: Vector,
+        projection: Projection,
+        aspect_ratio: f32,
+    ) -> Result<Camera, CameraError> {
+        Camera::new(eye, target - eye, up_hint, projection, aspect_ratio)
+    }
+
     pub fn screen_ray(&self, x: f32, y: f32) -> Ray {
-        // We assume that a screen lies 1 unit in front of the camera. The center (x: 0.5, y: 0.5) of the screen
-        // lies directly on the forward axis.
-        assert!(0.0 <= x && x <= 1.0);
-        assert!(0.0 <= y && y <= 1.0);
+        // The center (x: 0.5, y: 0.5) of the screen lies directly on the forward axis in all
+        // projections.
+        assert!((0.0..=1.0).contains(&x));
+        assert!((0.0..=1.0).contains(&y));
         let right = self.forward.0.cross(&self.up.0);
         // top left corner is x -1.0, y 1.0
         let xunit = posunit_to_unit(x);
         let yunit = -posunit_to_unit(y);
-        // The distance between a point on the screen and the center of the screen forms a right
-        // triangle with the distance between the camera and the center of the screen and the
-        // distance between the camera and the point. Since we know the maximum angle we can go in
-        // either direction (fovx/2 for x, fovy/2 for y) we first calculate the size of the screen
-        // 1 unit in front of the camera using tangent:
-        let screen_width = 2.0 * (self.fovx.0 / 2.0).tan();
-        let screen_height = screen_width / self.aspect_ratio;
-        // What's left now is to calculate the point at the screen we're looking at and a ray
-        // pointing to it:
-        let point_at_screen = self.position
-            + self.forward.0
-            + right * xunit * screen_width / 2.0
-            + self.up.0 * yunit * screen_height / 2.0;
-        let ray = Ray {
-            pos: self.position,
-            dir: (point_at_screen - self.position).normalized(),
-        };
-        ray
+        let direction = match self.projection {
+            Projection::Perspective { fovx } => {
+                // We assume that a screen lies 1 unit in front of the camera. The distance
+                // between a point on the screen and the center of the screen forms a right
+                // triangle with the distance between the camera and the center of the screen and
+                // the distance between the camera and the point. Since we know the maximum angle
+                // we can go in either direction (fovx/2 for x, fovy/2 for y) we first calculate
+                // the size of the screen 1 unit in front of the camera using tangent:
+                let screen_width = 2.0 * (fovx.0 / 2.0).tan();
+                let screen_height = screen_width / self.aspect_ratio;
+                // What's left now is to calculate the point at the screen we're looking at and a
+                // ray pointing to it:
+                let point_at_screen = self.position
+                    + self.forward.0
+                    + right * xunit * screen_width / 2.0
+                    + self.up.0 * yunit * screen_height / 2.0;
+                (point_at_screen - self.position).normalized()
+            }
+            Projection::Orthographic { width } => {
+                let height = width / self.aspect_ratio;
+                let origin =
+                    self.position + right * xunit * width / 2.0 + self.up.0 * yunit * height / 2.0;
+                return Ray::new(origin, self.forward);
+            }
+            Projection::EquidistantFisheye { fovx } | Projection::EquisolidFisheye { fovx } => {
+                // The distance from the center in units of half the image width, so that pixels
+                // stay square.
+                let yunit = yunit / self.aspect_ratio;
+                let distance = (xunit * xunit + yunit * yunit).sqrt();
+                let half_fov = fovx.0 / 2.0;
+                let angle = match self.projection {
+                    Projection::EquidistantFisheye { .. } => distance * half_fov,
+                    _ => 2.0 * (distance * (half_fov / 2.0).sin()).min(1.0).asin(),
+                };
+                // Corners beyond the image circle of a 360 degree fisheye look straight back.
+                let angle = angle.min(f32::consts::PI);
+                let sideways = if distance > 0.0 {
+                    (right * xunit + self.up.0 * yunit) / distance
+                } else {
+                    Vector::zero()
+                };
+                (self.forward.0 * angle.cos() + sideways * angle.sin()).normalized()
+            }
+            Projection::Equirectangular => {
+                let longitude = xunit * f32::consts::PI;
+                let latitude = yunit * f32::consts::FRAC_PI_2;
+                (self.forward.0 * (latitude.cos() * longitude.cos())
+                    + right * (latitude.cos() * longitude.sin())
+                    + self.up.0 * latitude.sin())
+                .normalized()
+            }
+        };
+        Ray::new(self.position, direction)
+    }
+
+    // Like screen_ray but starting at a point on the lens picked by a pair of uniform [0.0, 1.0)
+    // numbers. All rays through the same screen point meet again at the focus distance, points
+    // nearer or farther away get blurred.
+    pub fn lens_ray(&self, x: f32, y: f32, u: f32, v: f32) -> Ray {
+        let ray = self.screen_ray(x, y);
+        if self.aperture == 0.0 {
+            return ray;
+        }
+        // Projections onto a plane focus on a plane. The wide angle ones focus on a sphere around
+        // the camera, with the lens facing along the ray since it can point anywhere.
+        let (right, up, distance) = match self.projection {
+            Projection::Perspective { .. } | Projection::Orthographic { .. } => (
+                self.forward.0.cross(&self.up.0),
+                self.up.0,
+                self.focus_distance / ray.dir.0.dot(&self.forward.0),
+            ),
+            _ => {
+                let (right, up) = ray.dir.orthonormal_basis();
+                (right.0, up.0, self.focus_distance)
+            }
+        };
+        let focus = ray.pos + ray.dir.0 * distance;
+        let (lens_x, lens_y) = if self.blades == 0 {
+            concentric_disk(u, v)
+        } else {
+            regular_polygon(self.blades, u, v)
+        };
+        let origin = ray.pos + (right * lens_x + up * lens_y) * self.aperture;
+        Ray::new(origin, (focus - origin).normalized())
     }
 }
 
@@ -338,36 +1019,18 @@ pub fn posunit_to_unit(value: f32) -> f32 {
     value * 2.0 - 1.0
 }
 
+#[derive(Copy, Clone, Debug)]
 pub struct Radians(pub f32);
 
-pub fn trace_ray(spheres: &[Sphere], ray: &Ray, bounces: usize) -> Color {
-    match closest_intersection(&spheres, &ray) {
-        None => Color::new_black(),
-        Some(intersection) => {
-            let brightness = intersection.normal.0.dot(&-ray.dir.0);
-
-            let mut color = intersection.sphere.material.color;
-            if bounces > 0 {
-                color = color
-                    + trace_ray(
-                        &spheres,
-                        &ray.reflected(intersection.position, &intersection.normal),
-                        bounces - 1,
-                    );
-            }
-            color * brightness
-        }
-    }
-}
-
-pub fn closest_intersection<'a>(spheres: &'a [Sphere], ray: &Ray) -> Option<Intersection<'a>> {
+// The ray is shortened whenever a hit is found so shapes can skip hits farther away than it.
+pub fn closest_intersection<'a, S: Shape>(shapes: &'a [S], ray: &Ray) -> Option<Intersection<'a>> {
+    let mut ray = *ray;
     let mut closest_hit = None;
-    let mut closest_hit_distance = f32::MAX;
-    for sphere in spheres {
-        if let Some(intersection) = sphere.intersect_ray(&ray) {
-            let distance = (intersection.position - ray.pos).len();
-            if distance < closest_hit_distance {
-                closest_hit_distance = distance;
+    for shape in shapes {
+        if let Some(intersection) = shape.intersect_ray(&ray) {
+            // Hits at the same distance as the closest one don't replace it.
+            if intersection.t < ray.t_max || closest_hit.is_none() {
+                ray.t_max = intersection.t;
                 closest_hit = Some(intersection);
             }
         }
@@ -375,83 +1038,1519 @@ pub fn closest_intersection<'a>(spheres: &'a [Sphere], ray: &Ray) -> Option<Inte
     closest_hit
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::assert_almost_eq;
-    use crate::material::{Color, Material};
-    use crate::scene::{
-        closest_intersection, trace_ray, Camera, Intersection, Radians, Ray, Sphere, UnitVector,
-        Vector,
-    };
-    use crate::traits::AlmostEqual;
+fn is_emitter(shape: &dyn Shape) -> bool {
+    !shape.material().emission.is_black() && shape.can_sample_solid_angle()
+}
 
-    #[test]
-    fn test_vector_addition() {
-        assert_almost_eq!(
-            Vector {
-                x: 1.0,
-                y: 1.0,
-                z: 1.0
-            } + Vector {
-                x: 1.0,
-                y: 2.0,
-                z: 3.0
-            },
-            Vector {
-                x: 2.0,
-                y: 3.0,
-                z: 4.0
-            }
-        );
+pub struct Scene {
+    shapes: Vec<Box<dyn Shape>>,
+    lights: Vec<Box<dyn Light>>,
+    // Indices of the shapes with emissive materials that can be sampled as lights.
+    emitters: Vec<usize>,
+    bvh: Bvh,
+    pub camera: Camera,
+    pub settings: RenderSettings,
+}
+
+impl Scene {
+    pub fn new(
+        shapes: Vec<Box<dyn Shape>>,
+        lights: Vec<Box<dyn Light>>,
+        camera: Camera,
+        settings: RenderSettings,
+    ) -> Scene {
+        let bvh = Bvh::build(&shapes);
+        let emitters = (0..shapes.len())
+            .filter(|&i| is_emitter(shapes[i].as_ref()))
+            .collect();
+        Scene {
+            shapes,
+            lights,
+            emitters,
+            bvh,
+            camera,
+            settings,
+        }
     }
 
-    #[test]
-    fn test_vector_subtraction() {
-        assert_almost_eq!(
-            Vector {
-                x: 5.0,
-                y: 5.0,
-                z: 5.0
-            } - Vector {
-                x: 1.0,
-                y: 2.0,
-                z: 3.0
-            },
-            Vector {
-                x: 4.0,
-                y: 3.0,
-                z: 2.0
-            }
-        );
+    pub fn shapes(&self) -> &[Box<dyn Shape>] {
+        &self.shapes
     }
 
-    #[test]
-    fn test_vector_scalar_multiplication() {
-        let initial_vector = Vector {
-            x: 1.0,
-            y: 2.0,
-            z: 3.0,
-        };
-        let expected_vector = Vector {
-            x: 2.0,
-            y: 4.0,
-            z: 6.0,
-        };
-        assert_almost_eq!(initial_vector * 2.0, expected_vector);
-        assert_almost_eq!(2.0 * initial_vector, expected_vector);
+    pub fn lights(&self) -> &[Box<dyn Light>] {
+        &self.lights
     }
 
-    #[test]
-    fn test_vector_scalar_division() {
-        assert_almost_eq!(
-            Vector {
-                x: 1.0,
-                y: 2.0,
-                z: 3.0
-            } / 2.0,
-            Vector {
-                x: 0.5,
-                y: 1.0,
+    // Picks one of the emissive shapes that can be sampled with equal probability, see
+    // emitter_probability. Others (planes, meshes...) only light the scene when paths run into
+    // them.
+    pub fn pick_emitter(&self, u: f32) -> Option<&dyn Shape> {
+        if self.emitters.is_empty() {
+            return None;
+        }
+        let index = ((u * self.emitters.len() as f32) as usize).min(self.emitters.len() - 1);
+        Some(self.shapes[self.emitters[index]].as_ref())
+    }
+
+    // The probability of pick_emitter picking shape.
+    pub fn emitter_probability(&self, shape: &dyn Shape) -> f32 {
+        if is_emitter(shape) {
+            1.0 / self.emitters.len() as f32
+        } else {
+            0.0
+        }
+    }
+
+    pub fn bvh_stats(&self) -> BvhStats {
+        self.bvh.stats()
+    }
+
+    pub fn closest_intersection(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        self.bvh.closest_intersection(&self.shapes, ray)
+    }
+
+    // Returns the light reaching a surface from every light that isn't blocked by a shape.
+    pub fn visible_lights<'a>(
+        &'a self,
+        intersection: &'a Intersection,
+    ) -> impl Iterator<Item = LightSample> + 'a {
+        self.lights
+            .iter()
+            .map(move |light| light.illuminate(&intersection.position))
+            .filter(move |sample| !self.occluded(intersection, sample))
+    }
+
+    // The light from the scene's lights scattered towards wo by the surface.
+    pub fn direct_lighting(&self, intersection: &Intersection, wo: &UnitVector) -> Color {
+        let material = intersection.object.material();
+        let normal = &intersection.normal;
+        let mut light = Color::new_black();
+        for sample in self.visible_lights(intersection) {
+            // Interpolated mesh normals can point slightly away from the light even when the face
+            // itself is lit.
+            let cosine = normal.0.dot(&sample.direction.0);
+            if cosine > 0.0 {
+                light +=
+                    material.eval(intersection, wo, &sample.direction) * sample.radiance * cosine;
+            }
+        }
+        light
+    }
+
+    fn occluded(&self, intersection: &Intersection, sample: &LightSample) -> bool {
+        // Shapes behind the light don't cast shadows.
+        let shadow_ray = Ray {
+            t_max: sample.distance,
+            ..intersection.spawn_ray(sample.direction)
+        };
+        self.closest_intersection(&shadow_ray).is_some()
+    }
+}
+
+// An error encountered while loading a scene description. Lines and columns are 1-based and
+// point at the offending token, key is the name of the setting the error relates to (if any).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: ", self.line, self.column)?;
+        if let Some(key) = &self.key {
+            write!(f, "{}: ", key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+// The scene description format is line based. Blocks of settings are opened with a line
+// containing the block type, an optional name and a "{", and closed with a line containing just
+// "}". Every setting inside a block is a "key = value" line, values consisting of one or more
+// whitespace separated words. Everything following a "#" is a comment:
+//
+//     render {
+//         width = 800
+//         height = 600
+//     }
+//
+//     material red {
+//         color = 1 0 0
+//     }
+//
+//     sphere {
+//         center = 0 0 -5
+//         radius = 1
+//         material = red
+//     }
+//
+//     point_light {
+//         position = 0 5 0
+//         intensity = 20
+//     }
+//
+// Files referenced by the scene (like meshes and images) are looked up relative to directory.
+pub fn load_scene(source: &str, directory: &Path) -> Result<Scene, ParseError> {
+    let blocks = parse_blocks(source)?;
+
+    let mut textures = HashMap::new();
+    for block in blocks.iter().filter(|b| b.kind.text == "texture") {
+        let name = match &block.name {
+            Some(name) => name,
+            None => return Err(block.kind.error(block.line, "texture blocks need a name")),
+        };
+        if textures.contains_key(name.text) {
+            return Err(name.error(block.line, "texture defined more than once"));
+        }
+        textures.insert(name.text, parse_texture(block, directory)?);
+    }
+
+    let mut materials = HashMap::new();
+    for block in blocks.iter().filter(|b| b.kind.text == "material") {
+        let name = match &block.name {
+            Some(name) => name,
+            None => return Err(block.kind.error(block.line, "material blocks need a name")),
+        };
+        if materials.contains_key(name.text) {
+            return Err(name.error(block.line, "material defined more than once"));
+        }
+        materials.insert(name.text, parse_material(block, &textures)?);
+    }
+
+    // Named shapes aren't part of the scene by themselves, only through instances placing them.
+    let mut named_shapes = HashMap::new();
+    for block in &blocks {
+        let name = match &block.name {
+            Some(name) if SHAPE_KINDS.contains(&block.kind.text) => name,
+            _ => continue,
+        };
+        if named_shapes.contains_key(name.text) {
+            return Err(name.error(block.line, "shape defined more than once"));
+        }
+        let shape = parse_shape(block, &materials, &textures, &named_shapes, directory)?;
+        named_shapes.insert(name.text, Arc::from(shape));
+    }
+
+    let mut camera = None;
+    let mut settings = None;
+    let mut shapes: Vec<Box<dyn Shape>> = Vec::new();
+    let mut lights: Vec<Box<dyn Light>> = Vec::new();
+    for block in &blocks {
+        let kind = block.kind.text;
+        if kind != "material" && kind != "texture" && !SHAPE_KINDS.contains(&kind) {
+            if let Some(name) = &block.name {
+                let message = "only materials, textures and shapes can be named";
+                return Err(name.error(block.line, message));
+            }
+        }
+        match kind {
+            "material" | "texture" => (),
+            _ if block.name.is_some() => (),
+            "camera" => {
+                if camera.is_some() {
+                    return Err(block.error("camera defined more than once"));
+                }
+                camera = Some(block);
+            }
+            "render" => {
+                if settings.is_some() {
+                    return Err(block.error("render settings defined more than once"));
+                }
+                settings = Some(parse_render_settings(block)?);
+            }
+            "point_light" => lights.push(Box::new(parse_point_light(block)?)),
+            "directional_light" => lights.push(Box::new(parse_directional_light(block)?)),
+            _ => shapes.push(parse_shape(
+                block,
+                &materials,
+                &textures,
+                &named_shapes,
+                directory,
+            )?),
+        }
+    }
+
+    let settings = settings.unwrap_or_default();
+    let camera = match camera {
+        Some(block) => parse_camera(block, &settings)?,
+        None => {
+            return Err(ParseError {
+                line: source.lines().count().max(1),
+                column: 1,
+                key: None,
+                message: "the scene has no camera".to_string(),
+            })
+        }
+    };
+    Ok(Scene::new(shapes, lights, camera, settings))
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Word<'a> {
+    pub column: usize,
+    pub text: &'a str,
+}
+
+impl<'a> Word<'a> {
+    pub fn error(&self, line: usize, message: &str) -> ParseError {
+        ParseError {
+            line,
+            column: self.column,
+            key: None,
+            message: message.to_string(),
+        }
+    }
+}
+
+struct Entry<'a> {
+    line: usize,
+    key: Word<'a>,
+    values: Vec<Word<'a>>,
+}
+
+impl<'a> Entry<'a> {
+    fn error_at(&self, word: &Word, message: &str) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: word.column,
+            key: Some(self.key.text.to_string()),
+            message: message.to_string(),
+        }
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        self.error_at(&self.key, message)
+    }
+
+    fn values(&self, count: usize, what: &str) -> Result<&[Word<'a>], ParseError> {
+        if self.values.len() == count {
+            Ok(&self.values)
+        } else {
+            let message = format!("expected {}, got {} value(s)", what, self.values.len());
+            Err(match self.values.get(count) {
+                Some(extra) => self.error_at(extra, &message),
+                None => self.error(&message),
+            })
+        }
+    }
+
+    fn number_at(&self, word: &Word) -> Result<f32, ParseError> {
+        match word.text.parse::<f32>() {
+            Ok(value) if value.is_finite() => Ok(value),
+            _ => Err(self.error_at(word, &format!("expected a number, got \"{}\"", word.text))),
+        }
+    }
+
+    fn number(&self) -> Result<f32, ParseError> {
+        let values = self.values(1, "a number")?;
+        self.number_at(&values[0])
+    }
+
+    fn positive_number(&self) -> Result<f32, ParseError> {
+        let value = self.number()?;
+        if value > 0.0 {
+            Ok(value)
+        } else {
+            Err(self.error_at(&self.values[0], "expected a positive number"))
+        }
+    }
+
+    fn non_negative_number(&self) -> Result<f32, ParseError> {
+        let value = self.number()?;
+        if value >= 0.0 {
+            Ok(value)
+        } else {
+            Err(self.error_at(&self.values[0], "expected a non-negative number"))
+        }
+    }
+
+    fn fraction(&self) -> Result<f32, ParseError> {
+        let value = self.number()?;
+        if (0.0..=1.0).contains(&value) {
+            Ok(value)
+        } else {
+            Err(self.error_at(&self.values[0], "expected a number between 0 and 1"))
+        }
+    }
+
+    fn integer(&self) -> Result<usize, ParseError> {
+        let values = self.values(1, "an integer")?;
+        values[0].text.parse::<usize>().map_err(|_| {
+            self.error_at(
+                &values[0],
+                &format!(
+                    "expected a non-negative integer, got \"{}\"",
+                    values[0].text
+                ),
+            )
+        })
+    }
+
+    fn numbers(&self) -> Result<[f32; 3], ParseError> {
+        let values = self.values(3, "3 numbers")?;
+        Ok([
+            self.number_at(&values[0])?,
+            self.number_at(&values[1])?,
+            self.number_at(&values[2])?,
+        ])
+    }
+
+    fn vector(&self) -> Result<Vector, ParseError> {
+        let [x, y, z] = self.numbers()?;
+        Ok(Vector { x, y, z })
+    }
+
+    // Keyframes are a time followed by an offset, repeated as many times as needed.
+    fn motion(&self) -> Result<Motion, ParseError> {
+        if self.values.is_empty() || !self.values.len().is_multiple_of(4) {
+            let message = format!(
+                "expected groups of a time and 3 numbers, got {} value(s)",
+                self.values.len()
+            );
+            return Err(self.error(&message));
+        }
+        let mut keyframes = Vec::new();
+        for keyframe in self.values.chunks(4) {
+            let time = self.number_at(&keyframe[0])?;
+            let offset = Vector {
+                x: self.number_at(&keyframe[1])?,
+                y: self.number_at(&keyframe[2])?,
+                z: self.number_at(&keyframe[3])?,
+            };
+            keyframes.push((time, offset));
+        }
+        Ok(Motion::new(keyframes))
+    }
+
+    // An axis followed by an angle in degrees.
+    fn rotation(&self) -> Result<Transform, ParseError> {
+        let values = self.values(4, "an axis and an angle in degrees")?;
+        let axis = Vector {
+            x: self.number_at(&values[0])?,
+            y: self.number_at(&values[1])?,
+            z: self.number_at(&values[2])?,
+        };
+        if axis.len() == 0.0 {
+            return Err(self.error_at(&values[0], "expected a non-zero axis"));
+        }
+        let angle = Radians(self.number_at(&values[3])?.to_radians());
+        Ok(Transform::rotation(axis.normalized(), angle))
+    }
+
+    fn unit_vector(&self) -> Result<UnitVector, ParseError> {
+        let vector = self.vector()?;
+        if vector.len() == 0.0 {
+            return Err(self.error_at(&self.values[0], "expected a non-zero vector"));
+        }
+        Ok(vector.normalized())
+    }
+
+    fn color(&self) -> Result<Color, ParseError> {
+        let [r, g, b] = self.numbers()?;
+        Ok(Color { r, g, b })
+    }
+
+    fn name(&self) -> Result<&'a str, ParseError> {
+        Ok(self.values(1, "a name")?[0].text)
+    }
+}
+
+struct Block<'a> {
+    line: usize,
+    kind: Word<'a>,
+    name: Option<Word<'a>>,
+    entries: Vec<Entry<'a>>,
+}
+
+impl<'a> Block<'a> {
+    fn error(&self, message: &str) -> ParseError {
+        self.kind.error(self.line, message)
+    }
+
+    fn missing(&self, key: &str) -> ParseError {
+        ParseError {
+            key: Some(key.to_string()),
+            ..self.error("required setting is missing")
+        }
+    }
+
+    // Makes sure every key in the block is one of the known ones and that none of them is
+    // repeated.
+    fn check_keys(&self, known: &[&str]) -> Result<(), ParseError> {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if !known.contains(&entry.key.text) {
+                return Err(entry.error(&format!("unknown setting for {}", self.kind.text)));
+            }
+            if self.entries[..i]
+                .iter()
+                .any(|e| e.key.text == entry.key.text)
+            {
+                return Err(entry.error("setting specified more than once"));
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Option<&Entry<'a>> {
+        self.entries.iter().find(|e| e.key.text == key)
+    }
+
+    fn require(&self, key: &str) -> Result<&Entry<'a>, ParseError> {
+        self.get(key).ok_or_else(|| self.missing(key))
+    }
+}
+
+// Splits a line into whitespace separated words, skipping comments.
+pub(crate) fn split_words(line: &str) -> Vec<Word<'_>> {
+    let mut words = Vec::new();
+    let mut start = None;
+    // Columns count characters, not bytes, so that they match what text editors display.
+    for (column, (index, c)) in line.char_indices().enumerate() {
+        if c == '#' || c.is_whitespace() {
+            if let Some((start_column, start_index)) = start.take() {
+                words.push(Word {
+                    column: start_column + 1,
+                    text: &line[start_index..index],
+                });
+            }
+            if c == '#' {
+                return words;
+            }
+        } else if start.is_none() {
+            start = Some((column, index));
+        }
+    }
+    if let Some((start_column, start_index)) = start {
+        words.push(Word {
+            column: start_column + 1,
+            text: &line[start_index..],
+        });
+    }
+    words
+}
+
+fn parse_blocks(source: &str) -> Result<Vec<Block<'_>>, ParseError> {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut current: Option<Block> = None;
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let words = split_words(line);
+        let (first, rest) = match words.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+        match current.take() {
+            Some(mut block) => {
+                if first.text == "}" {
+                    if let Some(extra) = rest.first() {
+                        return Err(extra.error(line_number, "unexpected text after \"}\""));
+                    }
+                    blocks.push(block);
+                    continue;
+                }
+                match rest.split_first() {
+                    Some((equals, values)) if equals.text == "=" => {
+                        if values.is_empty() {
+                            return Err(ParseError {
+                                key: Some(first.text.to_string()),
+                                ..equals.error(line_number, "missing value")
+                            });
+                        }
+                        block.entries.push(Entry {
+                            line: line_number,
+                            key: *first,
+                            values: values.to_vec(),
+                        });
+                    }
+                    _ if words.last().unwrap().text == "{" => {
+                        return Err(first.error(line_number, "blocks cannot be nested"));
+                    }
+                    _ => {
+                        return Err(first.error(line_number, "expected \"key = value\""));
+                    }
+                }
+                current = Some(block);
+            }
+            None => {
+                let (kind, name) = match words.as_slice() {
+                    [kind, open] if open.text == "{" => (kind, None),
+                    [kind, name, open] if open.text == "{" => (kind, Some(*name)),
+                    _ => {
+                        return Err(first.error(
+                            line_number,
+                            "expected the beginning of a block (\"<type> [name] {\")",
+                        ))
+                    }
+                };
+                current = Some(Block {
+                    line: line_number,
+                    kind: *kind,
+                    name,
+                    entries: Vec::new(),
+                });
+            }
+        }
+    }
+    match current {
+        Some(block) => Err(block.error(&format!("{} block is never closed", block.kind.text))),
+        None => Ok(blocks),
+    }
+}
+
+fn parse_render_settings(block: &Block) -> Result<RenderSettings, ParseError> {
+    block.check_keys(&[
+        "width",
+        "height",
+        "bounces",
+        "samples",
+        "seed",
+        "mode",
+        "occlusion_distance",
+    ])?;
+    let mut settings = RenderSettings::default();
+    for entry in &block.entries {
+        match entry.key.text {
+            "width" => settings.width = parse_image_size(entry)?,
+            "height" => settings.height = parse_image_size(entry)?,
+            "bounces" => settings.bounces = entry.integer()?,
+            "samples" => {
+                settings.samples = entry.integer()?;
+                if settings.samples == 0 {
+                    return Err(entry.error_at(&entry.values[0], "expected at least 1 sample"));
+                }
+            }
+            "seed" => settings.seed = entry.integer()? as u64,
+            // Depend on each other, see below.
+            "mode" | "occlusion_distance" => (),
+            _ => unreachable!(),
+        }
+    }
+    settings.mode = parse_render_mode(block)?;
+    Ok(settings)
+}
+
+// The ambient occlusion mode takes the distance to look for occluding geometry within from a
+// separate setting, geometry at any distance counts when it's not given.
+fn parse_render_mode(block: &Block) -> Result<RenderMode, ParseError> {
+    let mode = match block.get("mode") {
+        None => RenderMode::Classic,
+        Some(entry) => match entry.name()? {
+            "classic" => RenderMode::Classic,
+            "path" => RenderMode::PathTracing,
+            "ambient_occlusion" => RenderMode::AmbientOcclusion {
+                distance: match block.get("occlusion_distance") {
+                    Some(distance) => distance.positive_number()?,
+                    None => f32::INFINITY,
+                },
+            },
+            "normals" => RenderMode::Normals,
+            other => {
+                return Err(entry.error_at(
+                    &entry.values[0],
+                    &format!(
+                        "expected classic, path, ambient_occlusion or normals, got \"{}\"",
+                        other
+                    ),
+                ))
+            }
+        },
+    };
+    if let Some(entry) = block.get("occlusion_distance") {
+        if !matches!(mode, RenderMode::AmbientOcclusion { .. }) {
+            return Err(entry.error("only used by the ambient_occlusion mode"));
+        }
+    }
+    Ok(mode)
+}
+
+fn parse_image_size(entry: &Entry) -> Result<usize, ParseError> {
+    let size = entry.integer()?;
+    // The renderer divides by (size - 1) when computing screen coordinates.
+    if size < 2 {
+        return Err(entry.error_at(&entry.values[0], "expected at least 2 pixels"));
+    }
+    Ok(size)
+}
+
+// The field of view of perspective cameras is below 180 degrees, fisheyes can see all around.
+fn parse_fovx(block: &Block, fisheye: bool) -> Result<Radians, ParseError> {
+    let fovx = block.require("fovx")?;
+    let degrees = fovx.positive_number()?;
+    if fisheye && degrees > 360.0 {
+        return Err(fovx.error_at(&fovx.values[0], "expected an angle up to 360 degrees"));
+    }
+    if !fisheye && degrees >= 180.0 {
+        return Err(fovx.error_at(&fovx.values[0], "expected an angle below 180 degrees"));
+    }
+    Ok(Radians(degrees.to_radians()))
+}
+
+// The camera direction is given either as a forward vector or as a point to look at. Cameras with
+// an aperture focus on the point they look at unless given a focus distance. The shutter is open
+// for an instant at time 0 by default.
+fn parse_camera(block: &Block, settings: &RenderSettings) -> Result<Camera, ParseError> {
+    block.check_keys(&[
+        "position",
+        "forward",
+        "look_at",
+        "up",
+        "projection",
+        "fovx",
+        "width",
+        "aspect_ratio",
+        "aperture",
+        "focus_distance",
+        "blades",
+        "shutter",
+    ])?;
+    let aspect_ratio = match block.get("aspect_ratio") {
+        Some(entry) => entry.positive_number()?,
+        None => settings.width as f32 / settings.height as f32,
+    };
+    let projection = match block.get("projection") {
+        None => Projection::Perspective {
+            fovx: parse_fovx(block, false)?,
+        },
+        Some(entry) => match entry.name()? {
+            "perspective" => Projection::Perspective {
+                fovx: parse_fovx(block, false)?,
+            },
+            "orthographic" => Projection::Orthographic {
+                width: block.require("width")?.positive_number()?,
+            },
+            "equidistant_fisheye" => Projection::EquidistantFisheye {
+                fovx: parse_fovx(block, true)?,
+            },
+            "equisolid_fisheye" => Projection::EquisolidFisheye {
+                fovx: parse_fovx(block, true)?,
+            },
+            "equirectangular" => Projection::Equirectangular,
+            other => {
+                return Err(entry.error_at(
+                    &entry.values[0],
+                    &format!(
+                        "expected perspective, orthographic, equidistant_fisheye, \
+                         equisolid_fisheye or equirectangular, got \"{}\"",
+                        other
+                    ),
+                ))
+            }
+        },
+    };
+    let orthographic = matches!(projection, Projection::Orthographic { .. });
+    if let Some(entry) = block.get("fovx") {
+        if orthographic || matches!(projection, Projection::Equirectangular) {
+            return Err(entry.error("only used by perspective and fisheye projections"));
+        }
+    }
+    if let Some(entry) = block.get("width") {
+        if !orthographic {
+            return Err(entry.error("only used by the orthographic projection"));
+        }
+    }
+    let position = block.require("position")?.vector()?;
+    let (direction, forward) = match (block.get("forward"), block.get("look_at")) {
+        (Some(_), Some(look_at)) => {
+            return Err(look_at.error("cannot be used together with forward"))
+        }
+        (Some(entry), None) => (entry, entry.vector()?),
+        (None, Some(entry)) => (entry, entry.vector()? - position),
+        (None, None) => return Err(block.missing("forward")),
+    };
+    let up = block.require("up")?;
+    let aperture = match block.get("aperture") {
+        Some(entry) => entry.non_negative_number()?,
+        None => 0.0,
+    };
+    let focus_distance = match (block.get("focus_distance"), block.get("look_at")) {
+        (Some(entry), _) => entry.positive_number()?,
+        (None, Some(_)) => forward.len(),
+        (None, None) if aperture > 0.0 => return Err(block.missing("focus_distance")),
+        (None, None) => 1.0,
+    };
+    let blades = match block.get("blades") {
+        Some(entry) => u32::try_from(entry.integer()?)
+            .map_err(|_| entry.error_at(&entry.values[0], "too many blades"))?,
+        None => 0,
+    };
+    let (open, close) = match block.get("shutter") {
+        Some(entry) => {
+            let values = entry.values(2, "the opening and closing times")?;
+            (entry.number_at(&values[0])?, entry.number_at(&values[1])?)
+        }
+        None => (0.0, 0.0),
+    };
+    Camera::new(position, forward, up.vector()?, projection, aspect_ratio)
+        .and_then(|camera| camera.with_lens(aperture, focus_distance, blades))
+        .and_then(|camera| camera.with_shutter(open, close))
+        .map_err(|e| {
+            let entry = match e {
+                CameraError::NoDirection => Some(direction),
+                CameraError::UpParallelToForward => Some(up),
+                CameraError::InvalidFov => block.get("fovx"),
+                CameraError::InvalidWidth => block.get("width"),
+                CameraError::InvalidAspectRatio => block.get("aspect_ratio"),
+                CameraError::InvalidAperture => block.get("aperture"),
+                CameraError::InvalidFocusDistance => block.get("focus_distance"),
+                CameraError::TooFewBlades => block.get("blades"),
+                CameraError::InvalidShutter => block.get("shutter"),
+            };
+            entry.unwrap_or(direction).error(&e.to_string())
+        })
+}
+
+// Textures need a type and the colors they blend between (or an image file), the scale of their
+// pattern (how many repetitions per unit of texture coordinates or distance) is 1 unless given.
+fn parse_texture(block: &Block, directory: &Path) -> Result<Arc<dyn Texture>, ParseError> {
+    let entry = block.require("type")?;
+    let kind = entry.name()?;
+    let scale = || match block.get("scale") {
+        Some(entry) => entry.positive_number(),
+        None => Ok(1.0),
+    };
+    let turbulence = || match block.get("turbulence") {
+        Some(entry) => entry.non_negative_number(),
+        None => Ok(1.0),
+    };
+    Ok(match kind {
+        "checkerboard" | "stripes" => {
+            block.check_keys(&["type", "even", "odd", "scale"])?;
+            let even = block.require("even")?.color()?;
+            let odd = block.require("odd")?.color()?;
+            if kind == "checkerboard" {
+                Arc::new(Checkerboard {
+                    even,
+                    odd,
+                    scale: scale()?,
+                })
+            } else {
+                Arc::new(Stripes {
+                    even,
+                    odd,
+                    scale: scale()?,
+                })
+            }
+        }
+        "gradient" => {
+            block.check_keys(&["type", "start", "end"])?;
+            Arc::new(Gradient {
+                start: block.require("start")?.color()?,
+                end: block.require("end")?.color()?,
+            })
+        }
+        "noise" => {
+            block.check_keys(&["type", "low", "high", "scale"])?;
+            Arc::new(Noise {
+                low: block.require("low")?.color()?,
+                high: block.require("high")?.color()?,
+                scale: scale()?,
+            })
+        }
+        "turbulence" => {
+            block.check_keys(&["type", "low", "high", "scale", "octaves"])?;
+            let octaves = match block.get("octaves") {
+                Some(entry) => entry.integer()?,
+                None => 7,
+            };
+            Arc::new(Turbulence {
+                low: block.require("low")?.color()?,
+                high: block.require("high")?.color()?,
+                scale: scale()?,
+                octaves,
+            })
+        }
+        "marble" | "wood" => {
+            block.check_keys(&["type", "low", "high", "scale", "turbulence"])?;
+            let low = block.require("low")?.color()?;
+            let high = block.require("high")?.color()?;
+            if kind == "marble" {
+                Arc::new(Marble {
+                    low,
+                    high,
+                    scale: scale()?,
+                    turbulence: turbulence()?,
+                })
+            } else {
+                Arc::new(Wood {
+                    low,
+                    high,
+                    scale: scale()?,
+                    turbulence: turbulence()?,
+                })
+            }
+        }
+        "image" => Arc::new(parse_image_texture(block, directory)?),
+        other => {
+            return Err(entry.error_at(
+                &entry.values[0],
+                &format!(
+                    "expected checkerboard, stripes, gradient, noise, turbulence, marble, wood or \
+                     image, got \"{}\"",
+                    other
+                ),
+            ));
+        }
+    })
+}
+
+// Images are filtered bilinearly and repeat by default. Their pixels are taken to be sRGB encoded
+// like those of most image files, unless color_space says they're linear already.
+fn parse_image_texture(block: &Block, directory: &Path) -> Result<ImageTexture, ParseError> {
+    block.check_keys(&["type", "file", "filter", "wrap", "color_space"])?;
+    let filter = match block.get("filter") {
+        None => Filter::Bilinear,
+        Some(entry) => match entry.name()? {
+            "nearest" => Filter::Nearest,
+            "bilinear" => Filter::Bilinear,
+            other => {
+                return Err(entry.error_at(
+                    &entry.values[0],
+                    &format!("expected nearest or bilinear, got \"{}\"", other),
+                ))
+            }
+        },
+    };
+    let wrap = match block.get("wrap") {
+        None => Wrap::Repeat,
+        Some(entry) => match entry.name()? {
+            "repeat" => Wrap::Repeat,
+            "clamp" => Wrap::Clamp,
+            "mirror" => Wrap::Mirror,
+            other => {
+                return Err(entry.error_at(
+                    &entry.values[0],
+                    &format!("expected repeat, clamp or mirror, got \"{}\"", other),
+                ))
+            }
+        },
+    };
+    let srgb = match block.get("color_space") {
+        None => true,
+        Some(entry) => match entry.name()? {
+            "srgb" => true,
+            "linear" => false,
+            other => {
+                return Err(entry.error_at(
+                    &entry.values[0],
+                    &format!("expected srgb or linear, got \"{}\"", other),
+                ))
+            }
+        },
+    };
+
+    let entry = block.require("file")?;
+    let filename = entry.name()?;
+    let mut file = fs::File::open(directory.join(filename)).map_err(|e| {
+        entry.error_at(
+            &entry.values[0],
+            &format!("cannot read \"{}\": {}", filename, e),
+        )
+    })?;
+    let mut image = image_from_file(&mut file).map_err(|e| {
+        entry.error_at(
+            &entry.values[0],
+            &format!("invalid image \"{}\": {}", filename, e),
+        )
+    })?;
+    if srgb {
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                let color = image.get_color(x, y).srgb_to_linear();
+                image.set_color(x, y, color);
+            }
+        }
+    }
+    Ok(ImageTexture {
+        image,
+        filter,
+        wrap,
+    })
+}
+
+// The settings describing a material, given either in a material block or inline in a shape.
+const MATERIAL_KEYS: &[&str] = &[
+    "color",
+    "emission",
+    "type",
+    "ior",
+    "absorption",
+    "roughness",
+    "metallic",
+];
+
+// The material settings that only make sense for one type of material.
+const MATERIAL_TYPE_KEYS: &[(&str, &[&str])] = &[
+    ("dielectric", &["ior", "absorption"]),
+    ("microfacet", &["roughness", "metallic"]),
+];
+
+fn check_material_type_keys(block: &Block, kind: &str) -> Result<(), ParseError> {
+    for (owner, keys) in MATERIAL_TYPE_KEYS
+        .iter()
+        .filter(|(owner, _)| *owner != kind)
+    {
+        for key in keys.iter() {
+            if let Some(entry) = block.get(key) {
+                return Err(entry.error(&format!("only used by {} materials", owner)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_material(
+    block: &Block,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Material, ParseError> {
+    block.check_keys(MATERIAL_KEYS)?;
+    parse_material_settings(block, textures)
+}
+
+// Material colors are either given directly or name a texture.
+fn parse_material_color(
+    entry: &Entry,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Arc<dyn Texture>, ParseError> {
+    match entry.values.as_slice() {
+        [value] if value.text.parse::<f32>().is_err() => match textures.get(value.text) {
+            Some(texture) => Ok(texture.clone()),
+            None => Err(entry.error_at(value, &format!("unknown texture \"{}\"", value.text))),
+        },
+        _ => Ok(Arc::new(entry.color()?)),
+    }
+}
+
+// Materials are diffuse unless their type says otherwise. Dielectrics need an index of
+// refraction, they're clear and colorless unless given a color or absorption. Microfacet
+// materials need a roughness and are dielectric (like plastic) unless given a metallic value.
+fn parse_material_settings(
+    block: &Block,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Material, ParseError> {
+    let emission = match block.get("emission") {
+        Some(entry) => entry.color()?,
+        None => Color::new_black(),
+    };
+    let kind = match block.get("type") {
+        Some(entry) => entry.name()?,
+        None => "diffuse",
+    };
+    Ok(match kind {
+        "diffuse" => {
+            check_material_type_keys(block, kind)?;
+            Material {
+                color: parse_material_color(block.require("color")?, textures)?,
+                emission,
+                surface: Surface::Diffuse,
+            }
+        }
+        "dielectric" => {
+            check_material_type_keys(block, kind)?;
+            let absorption = match block.get("absorption") {
+                Some(entry) => {
+                    let absorption = entry.color()?;
+                    if absorption.r < 0.0 || absorption.g < 0.0 || absorption.b < 0.0 {
+                        return Err(entry.error("expected non-negative numbers"));
+                    }
+                    absorption
+                }
+                None => Color::new_black(),
+            };
+            Material {
+                color: match block.get("color") {
+                    Some(entry) => parse_material_color(entry, textures)?,
+                    None => Arc::new(Color::new_white()),
+                },
+                emission,
+                surface: Surface::Dielectric {
+                    ior: block.require("ior")?.positive_number()?,
+                    absorption,
+                },
+            }
+        }
+        "microfacet" => {
+            check_material_type_keys(block, kind)?;
+            let metallic = match block.get("metallic") {
+                Some(entry) => entry.fraction()?,
+                None => 0.0,
+            };
+            Material {
+                color: parse_material_color(block.require("color")?, textures)?,
+                emission,
+                surface: Surface::Microfacet {
+                    roughness: block.require("roughness")?.fraction()?,
+                    metallic,
+                },
+            }
+        }
+        other => {
+            let entry = block.get("type").unwrap();
+            return Err(entry.error_at(
+                &entry.values[0],
+                &format!(
+                    "expected diffuse, dielectric or microfacet, got \"{}\"",
+                    other
+                ),
+            ));
+        }
+    })
+}
+
+// Shapes either refer to a named material or have its settings specified inline.
+fn parse_shape_material(
+    block: &Block,
+    materials: &HashMap<&str, Material>,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Material, ParseError> {
+    let inline = MATERIAL_KEYS.iter().find_map(|key| block.get(key));
+    match (block.get("material"), inline) {
+        (Some(_), Some(entry)) => Err(entry.error("cannot be used together with a named material")),
+        (Some(entry), None) => {
+            let name = entry.name()?;
+            match materials.get(name) {
+                Some(material) => Ok(material.clone()),
+                None => {
+                    Err(entry.error_at(&entry.values[0], &format!("unknown material \"{}\"", name)))
+                }
+            }
+        }
+        (None, Some(_)) => parse_material_settings(block, textures),
+        (None, None) => Err(block.missing("material")),
+    }
+}
+
+fn parse_sphere(
+    block: &Block,
+    materials: &HashMap<&str, Material>,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Sphere, ParseError> {
+    block.check_keys(&[&["center", "radius", "motion", "material"], MATERIAL_KEYS].concat())?;
+    let center = block.require("center")?.vector()?;
+    let radius = block.require("radius")?.positive_number()?;
+    let motion = match block.get("motion") {
+        Some(entry) => entry.motion()?,
+        None => Motion::default(),
+    };
+    Ok(Sphere {
+        center,
+        radius,
+        material: parse_shape_material(block, materials, textures)?,
+        motion,
+    })
+}
+
+fn parse_plane(
+    block: &Block,
+    materials: &HashMap<&str, Material>,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Plane, ParseError> {
+    block.check_keys(&[&["point", "normal", "material"], MATERIAL_KEYS].concat())?;
+    let point = block.require("point")?.vector()?;
+    let normal = block.require("normal")?.unit_vector()?;
+    Ok(Plane {
+        point,
+        normal,
+        material: parse_shape_material(block, materials, textures)?,
+    })
+}
+
+fn parse_triangle(
+    block: &Block,
+    materials: &HashMap<&str, Material>,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Triangle, ParseError> {
+    block.check_keys(&[&["a", "b", "c", "material"], MATERIAL_KEYS].concat())?;
+    let a = block.require("a")?.vector()?;
+    let b = block.require("b")?.vector()?;
+    let c = block.require("c")?.vector()?;
+    Ok(Triangle {
+        a,
+        b,
+        c,
+        material: parse_shape_material(block, materials, textures)?,
+    })
+}
+
+// Boxes are given by their corners, rotate turns them around their center.
+fn parse_box(
+    block: &Block,
+    materials: &HashMap<&str, Material>,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Box<dyn Shape>, ParseError> {
+    block.check_keys(&[&["min", "max", "rotate", "material"], MATERIAL_KEYS].concat())?;
+    let min = block.require("min")?.vector()?;
+    let entry = block.require("max")?;
+    let max = entry.vector()?;
+    if max.x <= min.x || max.y <= min.y || max.z <= min.z {
+        return Err(entry.error("has to be larger than min in every coordinate"));
+    }
+    let cuboid = Cuboid {
+        min,
+        max,
+        material: parse_shape_material(block, materials, textures)?,
+    };
+    Ok(match block.get("rotate") {
+        Some(entry) => {
+            let center = (min + max) / 2.0;
+            Box::new(Instance::new(
+                Arc::new(cuboid),
+                Transform::translation(center)
+                    * entry.rotation()?
+                    * Transform::translation(-center),
+            ))
+        }
+        None => Box::new(cuboid),
+    })
+}
+
+fn parse_disk(
+    block: &Block,
+    materials: &HashMap<&str, Material>,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Disk, ParseError> {
+    block.check_keys(
+        &[
+            &["center", "normal", "radius", "inner_radius", "material"],
+            MATERIAL_KEYS,
+        ]
+        .concat(),
+    )?;
+    let center = block.require("center")?.vector()?;
+    let normal = block.require("normal")?.unit_vector()?;
+    let radius = block.require("radius")?.positive_number()?;
+    let inner_radius = match block.get("inner_radius") {
+        Some(entry) => {
+            let inner_radius = entry.non_negative_number()?;
+            if inner_radius >= radius {
+                return Err(entry.error_at(&entry.values[0], "has to be smaller than radius"));
+            }
+            inner_radius
+        }
+        None => 0.0,
+    };
+    Ok(Disk {
+        center,
+        normal,
+        radius,
+        inner_radius,
+        material: parse_shape_material(block, materials, textures)?,
+    })
+}
+
+// Cylinders and cones go from the center of their base to the one of their top (or tip), with
+// the returned axis and height.
+fn parse_axis(
+    block: &Block,
+    base: &str,
+    top: &str,
+) -> Result<(Vector, UnitVector, f32), ParseError> {
+    let base = block.require(base)?.vector()?;
+    let entry = block.require(top)?;
+    let along = entry.vector()? - base;
+    if along.len() == 0.0 {
+        return Err(entry.error("has to be away from the base"));
+    }
+    Ok((base, along.normalized(), along.len()))
+}
+
+fn parse_cylinder(
+    block: &Block,
+    materials: &HashMap<&str, Material>,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Cylinder, ParseError> {
+    block.check_keys(&[&["base", "top", "radius", "material"], MATERIAL_KEYS].concat())?;
+    let (base, axis, height) = parse_axis(block, "base", "top")?;
+    Ok(Cylinder {
+        base,
+        axis,
+        height,
+        radius: block.require("radius")?.positive_number()?,
+        material: parse_shape_material(block, materials, textures)?,
+    })
+}
+
+fn parse_cone(
+    block: &Block,
+    materials: &HashMap<&str, Material>,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Cone, ParseError> {
+    block.check_keys(&[&["base", "tip", "radius", "material"], MATERIAL_KEYS].concat())?;
+    let (base, axis, height) = parse_axis(block, "base", "tip")?;
+    Ok(Cone {
+        base,
+        axis,
+        height,
+        radius: block.require("radius")?.positive_number()?,
+        material: parse_shape_material(block, materials, textures)?,
+    })
+}
+
+fn parse_torus(
+    block: &Block,
+    materials: &HashMap<&str, Material>,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+) -> Result<Torus, ParseError> {
+    block.check_keys(
+        &[
+            &["center", "axis", "major_radius", "minor_radius", "material"],
+            MATERIAL_KEYS,
+        ]
+        .concat(),
+    )?;
+    let major_radius = block.require("major_radius")?.positive_number()?;
+    let entry = block.require("minor_radius")?;
+    let minor_radius = entry.positive_number()?;
+    if minor_radius >= major_radius {
+        return Err(entry.error_at(&entry.values[0], "has to be smaller than major_radius"));
+    }
+    Ok(Torus {
+        center: block.require("center")?.vector()?,
+        axis: block.require("axis")?.unit_vector()?,
+        major_radius,
+        minor_radius,
+        material: parse_shape_material(block, materials, textures)?,
+    })
+}
+
+fn parse_mesh(
+    block: &Block,
+    materials: &HashMap<&str, Material>,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+    directory: &Path,
+) -> Result<Mesh, ParseError> {
+    block.check_keys(&[&["file", "material"], MATERIAL_KEYS].concat())?;
+    let entry = block.require("file")?;
+    let filename = entry.name()?;
+    let material = parse_shape_material(block, materials, textures)?;
+    let source = fs::read_to_string(directory.join(filename)).map_err(|e| {
+        entry.error_at(
+            &entry.values[0],
+            &format!("cannot read \"{}\": {}", filename, e),
+        )
+    })?;
+    load_obj(&source, material).map_err(|e| {
+        entry.error_at(
+            &entry.values[0],
+            &format!("invalid mesh \"{}\": {}", filename, e),
+        )
+    })
+}
+
+// Instances place a named shape, named instances can only use the ones defined before them. The
+// shape is scaled first (by one factor or one per axis), then rotated around an axis by an angle
+// in degrees and moved last.
+fn parse_instance(
+    block: &Block,
+    named_shapes: &HashMap<&str, Arc<dyn Shape>>,
+) -> Result<Instance, ParseError> {
+    block.check_keys(&["shape", "scale", "rotate", "translate", "motion"])?;
+    let entry = block.require("shape")?;
+    let name = entry.name()?;
+    let shape = match named_shapes.get(name) {
+        Some(shape) => shape.clone(),
+        None => {
+            let message = format!("unknown shape \"{}\"", name);
+            return Err(entry.error_at(&entry.values[0], &message));
+        }
+    };
+    let mut transform = Transform::identity();
+    if let Some(entry) = block.get("scale") {
+        let factors = if entry.values.len() == 1 {
+            let factor = entry.number()?;
+            Vector {
+                x: factor,
+                y: factor,
+                z: factor,
+            }
+        } else {
+            entry.vector()?
+        };
+        if factors.x == 0.0 || factors.y == 0.0 || factors.z == 0.0 {
+            return Err(entry.error_at(&entry.values[0], "can't scale by 0"));
+        }
+        transform = Transform::scaling(factors);
+    }
+    if let Some(entry) = block.get("rotate") {
+        transform = entry.rotation()? * transform;
+    }
+    if let Some(entry) = block.get("translate") {
+        transform = Transform::translation(entry.vector()?) * transform;
+    }
+    let motion = match block.get("motion") {
+        Some(entry) => entry.motion()?,
+        None => Motion::default(),
+    };
+    Ok(Instance {
+        shape,
+        transform,
+        motion,
+    })
+}
+
+const SHAPE_KINDS: &[&str] = &[
+    "sphere", "plane", "triangle", "box", "disk", "cylinder", "cone", "torus", "mesh", "instance",
+];
+
+// Parses any of the SHAPE_KINDS blocks, the block types that aren't known at all end up here too.
+fn parse_shape(
+    block: &Block,
+    materials: &HashMap<&str, Material>,
+    textures: &HashMap<&str, Arc<dyn Texture>>,
+    named_shapes: &HashMap<&str, Arc<dyn Shape>>,
+    directory: &Path,
+) -> Result<Box<dyn Shape>, ParseError> {
+    Ok(match block.kind.text {
+        "sphere" => Box::new(parse_sphere(block, materials, textures)?),
+        "plane" => Box::new(parse_plane(block, materials, textures)?),
+        "triangle" => Box::new(parse_triangle(block, materials, textures)?),
+        "box" => parse_box(block, materials, textures)?,
+        "disk" => Box::new(parse_disk(block, materials, textures)?),
+        "cylinder" => Box::new(parse_cylinder(block, materials, textures)?),
+        "cone" => Box::new(parse_cone(block, materials, textures)?),
+        "torus" => Box::new(parse_torus(block, materials, textures)?),
+        "mesh" => Box::new(parse_mesh(block, materials, textures, directory)?),
+        "instance" => Box::new(parse_instance(block, named_shapes)?),
+        other => return Err(block.error(&format!("unknown block type \"{}\"", other))),
+    })
+}
+
+// Lights are white with an intensity of 1 unless specified otherwise.
+fn parse_light_emission(block: &Block) -> Result<(Color, f32), ParseError> {
+    let color = match block.get("color") {
+        Some(entry) => entry.color()?,
+        None => Color::new_white(),
+    };
+    let intensity = match block.get("intensity") {
+        Some(entry) => entry.positive_number()?,
+        None => 1.0,
+    };
+    Ok((color, intensity))
+}
+
+fn parse_point_light(block: &Block) -> Result<PointLight, ParseError> {
+    block.check_keys(&["position", "color", "intensity"])?;
+    let position = block.require("position")?.vector()?;
+    let (color, intensity) = parse_light_emission(block)?;
+    Ok(PointLight {
+        position,
+        color,
+        intensity,
+    })
+}
+
+fn parse_directional_light(block: &Block) -> Result<DirectionalLight, ParseError> {
+    block.check_keys(&["direction", "color", "intensity"])?;
+    let direction = block.require("direction")?.unit_vector()?;
+    let (color, intensity) = parse_light_emission(block)?;
+    Ok(DirectionalLight {
+        direction,
+        color,
+        intensity,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_almost_eq;
+    use crate::bvh::Aabb;
+    use crate::light::PointLight;
+    use crate::material::{Color, Material, Surface};
+    use crate::render::{RenderMode, RenderSettings};
+    use crate::sampler::Sampler;
+    use crate::scene::{
+        closest_intersection, load_scene, Camera, CameraError, Intersection, Motion, ParseError,
+        Plane, Projection, Radians, Ray, Scene, Shape, Sphere, UnitVector, Vector,
+    };
+    use crate::testing::{camera, scene_with, vector};
+    use crate::traits::AlmostEqual;
+    use std::f32;
+    use std::path::Path;
+    use std::ptr;
+
+    const CAMERA: &str =
+        "camera {\n position = 0 0 0\n forward = 0 0 -1\n up = 0 1 0\n fovx = 90\n}\n";
+
+    // Where the scene fails to load, files are looked up in the scenes directory.
+    fn error(source: &str) -> (usize, usize, Option<String>) {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let ParseError {
+            line, column, key, ..
+        } = load_scene(source, &directory).err().unwrap();
+        (line, column, key)
+    }
+
+    #[test]
+    fn test_vector_addition() {
+        assert_almost_eq!(
+            Vector {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0
+            } + Vector {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0
+            },
+            Vector {
+                x: 2.0,
+                y: 3.0,
+                z: 4.0
+            }
+        );
+    }
+
+    #[test]
+    fn test_vector_subtraction() {
+        assert_almost_eq!(
+            Vector {
+                x: 5.0,
+                y: 5.0,
+                z: 5.0
+            } - Vector {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0
+            },
+            Vector {
+                x: 4.0,
+                y: 3.0,
+                z: 2.0
+            }
+        );
+    }
+
+    #[test]
+    fn test_vector_scalar_multiplication() {
+        let initial_vector = Vector {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        };
+        let expected_vector = Vector {
+            x: 2.0,
+            y: 4.0,
+            z: 6.0,
+        };
+        assert_almost_eq!(initial_vector * 2.0, expected_vector);
+        assert_almost_eq!(2.0 * initial_vector, expected_vector);
+    }
+
+    #[test]
+    fn test_vector_scalar_division() {
+        assert_almost_eq!(
+            Vector {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0
+            } / 2.0,
+            Vector {
+                x: 0.5,
+                y: 1.0,
                 z: 1.5
             }
         );
@@ -520,9 +2619,9 @@ mod tests {
         assert_almost_eq!(
             original.normalized().0,
             Vector {
-                x: 0.2672612419124244,
-                y: 0.5345224838248488,
-                z: 0.8017837257372732,
+                x: 0.26726124,
+                y: 0.5345225,
+                z: 0.8017837,
             }
         );
     }
@@ -533,34 +2632,35 @@ mod tests {
             center: Vector::zero(),
             radius: 1.0,
             material: Material::dummy(),
+            motion: Motion::default(),
         };
 
-        let outside_pointing_away = Ray {
-            pos: Vector {
+        let outside_pointing_away = Ray::new(
+            Vector {
                 x: 0.0,
                 y: 0.0,
                 z: 10.0,
             },
-            dir: UnitVector(Vector {
+            UnitVector(Vector {
                 x: 0.0,
                 y: 0.0,
                 z: 1.0,
             }),
-        };
+        );
         assert_almost_eq!(sphere.intersect_ray(&outside_pointing_away), None);
 
-        let outside_pointing_towards = Ray {
-            pos: Vector {
+        let outside_pointing_towards = Ray::new(
+            Vector {
                 x: 0.0,
                 y: 0.0,
                 z: 10.0,
             },
-            dir: UnitVector(Vector {
+            UnitVector(Vector {
                 x: 0.0,
                 y: 0.0,
                 z: -1.0,
             }),
-        };
+        );
         assert_almost_eq!(
             sphere.intersect_ray(&outside_pointing_towards),
             Some(Intersection {
@@ -569,65 +2669,688 @@ mod tests {
                     y: 0.0,
                     z: 1.0
                 },
+                t: 9.0,
                 normal: Vector::unitz(),
-                sphere: &sphere,
+                geometric_normal: Vector::unitz(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.25, 0.5),
+                object: &sphere,
+                time: 0.0,
             })
         );
 
-        let inside = Ray {
-            pos: Vector::zero(),
-            dir: UnitVector(Vector {
+        // Rays starting inside hit the back face, the normal points inwards.
+        let inside = Ray::new(
+            Vector::zero(),
+            UnitVector(Vector {
                 x: 1.0,
                 y: 0.0,
                 z: 0.0,
             }),
+        );
+        assert_almost_eq!(
+            sphere.intersect_ray(&inside),
+            Some(Intersection {
+                position: Vector::unitx().0,
+                t: 1.0,
+                normal: -Vector::unitx(),
+                geometric_normal: -Vector::unitx(),
+                front_face: false,
+                position_error: 0.0,
+                uv: (0.5, 0.5),
+                object: &sphere,
+                time: 0.0,
+            })
+        );
+
+        let inside_center_behind = Ray::new(
+            Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 0.5,
+            },
+            Vector::unitz(),
+        );
+        assert_almost_eq!(
+            sphere.intersect_ray(&inside_center_behind),
+            Some(Intersection {
+                position: Vector::unitz().0,
+                t: 0.5,
+                normal: -Vector::unitz(),
+                geometric_normal: -Vector::unitz(),
+                front_face: false,
+                position_error: 0.0,
+                uv: (0.25, 0.5),
+                object: &sphere,
+                time: 0.0,
+            })
+        );
+
+        // Rays leaving the surface don't hit it again where they start.
+        let on_surface_pointing_in = Ray::new(Vector::unitz().0, -Vector::unitz());
+        assert_almost_eq!(
+            sphere.intersect_ray(&on_surface_pointing_in),
+            Some(Intersection {
+                position: -Vector::unitz().0,
+                t: 2.0,
+                normal: Vector::unitz(),
+                geometric_normal: Vector::unitz(),
+                front_face: false,
+                position_error: 0.0,
+                uv: (0.75, 0.5),
+                object: &sphere,
+                time: 0.0,
+            })
+        );
+
+        let on_surface_pointing_out = Ray::new(Vector::unitz().0, Vector::unitz());
+        assert_almost_eq!(sphere.intersect_ray(&on_surface_pointing_out), None);
+
+        let on_surface_tangent = Ray::new(Vector::unitz().0, Vector::unitx());
+        assert_almost_eq!(sphere.intersect_ray(&on_surface_tangent), None);
+    }
+
+    #[test]
+    fn test_sphere_motion() {
+        let motion = Motion::new(vec![
+            (1.0, Vector::unitx().0 * 2.0),
+            (0.0, Vector::zero()),
+            (2.0, Vector::unity().0),
+        ]);
+        assert_almost_eq!(motion.offset(-1.0), Vector::zero());
+        assert_almost_eq!(motion.offset(0.5), Vector::unitx().0);
+        assert_almost_eq!(
+            motion.offset(1.5),
+            Vector {
+                x: 1.0,
+                y: 0.5,
+                z: 0.0
+            }
+        );
+        assert_almost_eq!(motion.offset(3.0), Vector::unity().0);
+        assert_almost_eq!(Motion::default().offset(0.5), Vector::zero());
+
+        let sphere = Sphere {
+            center: Vector::zero(),
+            radius: 1.0,
+            material: Material::dummy(),
+            motion: Motion::linear(Vector::unitx().0 * 4.0),
+        };
+        let bounds = sphere.bounding_box().unwrap();
+        assert_almost_eq!(
+            bounds.min,
+            Vector {
+                x: -1.0,
+                y: -1.0,
+                z: -1.0
+            }
+        );
+        assert_almost_eq!(
+            bounds.max,
+            Vector {
+                x: 5.0,
+                y: 1.0,
+                z: 1.0
+            }
+        );
+
+        // A ray along the path of the sphere hits it wherever it is at the time of the ray.
+        let ray = Ray::new(Vector::unitx().0 * 10.0, -Vector::unitx());
+        for &time in &[0.0, 0.25, 1.0] {
+            let intersection = sphere.intersect_ray(&Ray { time, ..ray }).unwrap();
+            assert_almost_eq!(intersection.t, 9.0 - 4.0 * time);
+            assert_eq!(intersection.time, time);
+            // Rays bouncing off the sphere happen at the same time.
+            assert_eq!(intersection.spawn_ray(Vector::unitx()).time, time);
+        }
+        // One across its path only hits it while it's passing by.
+        let across = Ray::new(
+            Vector {
+                x: 4.0,
+                y: 0.0,
+                z: 10.0,
+            },
+            -Vector::unitz(),
+        );
+        assert!(sphere.intersect_ray(&across).is_none());
+        assert!(sphere
+            .intersect_ray(&Ray {
+                time: 1.0,
+                ..across
+            })
+            .is_some());
+    }
+
+    #[test]
+    fn test_texture_coordinates() {
+        let almost_equal = |(u, v): (f32, f32), (eu, ev): (f32, f32)| {
+            (u - eu).abs() < 1e-6 && (v - ev).abs() < 1e-6
+        };
+        // Spheres are mapped like a globe, u going around the equator and v from pole to pole.
+        let sphere = Sphere {
+            center: vector(1.0, 2.0, 3.0),
+            radius: 2.0,
+            material: Material::dummy(),
+            motion: Motion::default(),
+        };
+        let uv_towards = |x, y, z| {
+            let ray = Ray::new(sphere.center, vector(x, y, z).normalized());
+            sphere.intersect_ray(&ray).unwrap().uv
+        };
+        assert!(almost_equal(uv_towards(-1.0, 0.0, 0.0), (0.0, 0.5)));
+        assert!(almost_equal(uv_towards(0.0, 0.0, 1.0), (0.25, 0.5)));
+        assert!(almost_equal(uv_towards(1.0, 0.0, 0.0), (0.5, 0.5)));
+        assert!(almost_equal(uv_towards(0.0, 0.0, -1.0), (0.75, 0.5)));
+        assert!(almost_equal(uv_towards(1.0, 1.0, 0.0), (0.5, 0.75)));
+        assert!(uv_towards(0.0, -1.0, 0.0).1.abs() < 1e-6);
+        assert!((uv_towards(0.0, 1.0, 0.0).1 - 1.0).abs() < 1e-6);
+
+        // Planes measure distances from their point.
+        let plane = Plane {
+            point: vector(1.0, 2.0, 3.0),
+            normal: Vector::unitz(),
+            material: Material::dummy(),
+        };
+        let ray = Ray::new(vector(1.5, 4.0, 5.0), -Vector::unitz());
+        let uv = plane.intersect_ray(&ray).unwrap().uv;
+        assert!(almost_equal(uv, (0.5, 2.0)), "{:?}", uv);
+    }
+
+    #[test]
+    fn test_sphere_solid_angle_sampling() {
+        let sphere = Sphere {
+            center: vector(0.0, 0.0, -5.0),
+            radius: 3.0,
+            material: Material::dummy(),
+            motion: Motion::default(),
+        };
+        let point = Vector::zero();
+        // The sphere covers a cone with a half angle of asin(3 / 5), its solid angle is
+        // 2 * pi * (1 - cos), every direction within is equally likely.
+        let pdf = 1.0 / (2.0 * f32::consts::PI * (1.0 - 0.8));
+        let mut sampler = Sampler::new(0, 0);
+        for _ in 0..1000 {
+            let direction = sphere
+                .sample_solid_angle(&point, 0.0, sampler.next_f32(), sampler.next_f32())
+                .unwrap();
+            assert!(sphere.intersect_ray(&Ray::new(point, direction)).is_some());
+            assert!((sphere.solid_angle_pdf(&point, 0.0, &direction) - pdf).abs() < 1e-4 * pdf);
+        }
+        // The edge of the cone.
+        let direction = sphere.sample_solid_angle(&point, 0.0, 1.0, 0.0).unwrap();
+        assert!((direction.0.z + 0.8).abs() < 1e-6);
+        assert_eq!(sphere.solid_angle_pdf(&point, 0.0, &Vector::unitz()), 0.0);
+
+        // Tiny or distant spheres are sampled with the same precision.
+        let far = Sphere {
+            center: vector(0.0, 0.0, -1e4),
+            radius: 1.0,
+            ..sphere.clone()
+        };
+        let direction = far.sample_solid_angle(&point, 0.0, 1.0, 0.0).unwrap();
+        assert!(far.intersect_ray(&Ray::new(point, direction)).is_some());
+        assert!(far.solid_angle_pdf(&point, 0.0, &direction) > 1e7);
+
+        // There's nothing to aim at from inside the sphere.
+        let inside = vector(0.0, 0.0, -4.0);
+        assert!(sphere.sample_solid_angle(&inside, 0.0, 0.5, 0.5).is_none());
+        assert_eq!(sphere.solid_angle_pdf(&inside, 0.0, &Vector::unitz()), 0.0);
+    }
+
+    #[test]
+    fn test_scene_emitters() {
+        let lamp = Material {
+            emission: Color::new_white(),
+            ..Material::diffuse(Color::new_black())
+        };
+        let sphere = |x, material: &Material| {
+            Box::new(Sphere {
+                center: vector(x, 0.0, -5.0),
+                radius: 1.0,
+                material: material.clone(),
+                motion: Motion::default(),
+            })
+        };
+        let scene = scene_with(
+            vec![
+                Box::new(Plane {
+                    point: vector(0.0, -1.0, 0.0),
+                    normal: Vector::unity(),
+                    material: lamp.clone(),
+                }),
+                sphere(-2.0, &Material::dummy()),
+                sphere(0.0, &lamp),
+                sphere(2.0, &lamp),
+            ],
+            vec![],
+        );
+        // The plane glows too but can't be aimed at, so only the two lamps are picked.
+        let shapes = scene.shapes();
+        assert!(ptr::addr_eq(scene.pick_emitter(0.0).unwrap(), &*shapes[2]));
+        assert!(ptr::addr_eq(scene.pick_emitter(0.99).unwrap(), &*shapes[3]));
+        assert_eq!(scene.emitter_probability(&*shapes[0]), 0.0);
+        assert_eq!(scene.emitter_probability(&*shapes[1]), 0.0);
+        assert_eq!(scene.emitter_probability(&*shapes[2]), 0.5);
+        assert!(scene_with(vec![], vec![]).pick_emitter(0.5).is_none());
+    }
+
+    #[test]
+    fn test_ray_interval() {
+        let sphere = Sphere {
+            center: Vector::zero(),
+            radius: 1.0,
+            material: Material::dummy(),
+            motion: Motion::default(),
+        };
+        let plane = Plane {
+            point: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -3.0,
+            },
+            normal: Vector::unitz(),
+            material: Material::dummy(),
+        };
+        let ray = Ray::new(
+            Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 5.0,
+            },
+            -Vector::unitz(),
+        );
+        assert_almost_eq!(ray.at(2.0).z, 3.0);
+        assert_almost_eq!(sphere.intersect_ray(&ray).unwrap().t, 4.0);
+        assert_almost_eq!(plane.intersect_ray(&ray).unwrap().t, 8.0);
+
+        let short = Ray { t_max: 3.5, ..ray };
+        assert!(sphere.intersect_ray(&short).is_none());
+        assert!(plane.intersect_ray(&short).is_none());
+
+        // Starting past the front of the sphere the ray hits its back.
+        let late = Ray { t_min: 4.5, ..ray };
+        let hit = sphere.intersect_ray(&late).unwrap();
+        assert_almost_eq!(hit.t, 6.0);
+        assert!(!hit.front_face);
+        assert!(plane.intersect_ray(&Ray { t_min: 8.5, ..ray }).is_none());
+
+        // Hits right at the end of the ray count.
+        assert_almost_eq!(
+            plane.intersect_ray(&Ray { t_max: 8.0, ..ray }).unwrap().t,
+            8.0
+        );
+
+        let shapes: Vec<Box<dyn Shape>> = vec![Box::new(plane), Box::new(sphere)];
+        let hit = closest_intersection(&shapes, &ray).unwrap();
+        assert!(ptr::addr_eq(hit.object, &*shapes[1]));
+        assert_almost_eq!(hit.t, 4.0);
+        let hit = closest_intersection(&shapes, &Ray { t_min: 6.5, ..ray }).unwrap();
+        assert!(ptr::addr_eq(hit.object, &*shapes[0]));
+        assert!(closest_intersection(&shapes, &short).is_none());
+    }
+
+    #[test]
+    fn test_plane_ray_intersection() {
+        let plane = Plane {
+            point: Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            normal: Vector::unity(),
+            material: Material::dummy(),
         };
-        assert_almost_eq!(sphere.intersect_ray(&inside), None);
+
+        let above_pointing_down = Ray::new(
+            Vector {
+                x: 1.0,
+                y: 3.0,
+                z: 0.0,
+            },
+            -Vector::unity(),
+        );
+        assert_almost_eq!(
+            plane.intersect_ray(&above_pointing_down),
+            Some(Intersection {
+                position: Vector {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
+                t: 2.0,
+                normal: Vector::unity(),
+                geometric_normal: Vector::unity(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (1.0, 0.0),
+                object: &plane,
+                time: 0.0,
+            })
+        );
+
+        let below_pointing_up = Ray::new(
+            Vector {
+                x: 1.0,
+                y: -3.0,
+                z: 0.0,
+            },
+            Vector::unity(),
+        );
+        assert_almost_eq!(
+            plane.intersect_ray(&below_pointing_up),
+            Some(Intersection {
+                position: Vector {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
+                t: 4.0,
+                normal: -Vector::unity(),
+                geometric_normal: -Vector::unity(),
+                front_face: false,
+                position_error: 0.0,
+                uv: (1.0, 0.0),
+                object: &plane,
+                time: 0.0,
+            })
+        );
+
+        let parallel = Ray::new(Vector::zero(), Vector::unitx());
+        assert_almost_eq!(plane.intersect_ray(&parallel), None);
+
+        let pointing_away = Ray::new(Vector::zero(), -Vector::unity());
+        assert_almost_eq!(plane.intersect_ray(&pointing_away), None);
     }
 
     #[test]
     fn test_camera_screen_ray() {
-        let camera = Camera {
-            position: Vector::zero(),
-            forward: -Vector::unitz(),
-            up: Vector::unity(),
-            aspect_ratio: 2.0 / 1.0,
+        let camera = camera(2.0 / 1.0);
+
+        assert_almost_eq!(
+            camera.screen_ray(0.0, 0.0),
+            Ray::new(
+                Vector::zero(),
+                Vector {
+                    x: -1.0,
+                    y: 0.5,
+                    z: -1.0,
+                }
+                .normalized()
+            )
+        );
+
+        assert_almost_eq!(
+            camera.screen_ray(0.5, 0.5),
+            Ray::new(Vector::zero(), -Vector::unitz()),
+        );
+
+        assert_almost_eq!(
+            camera.screen_ray(0.25, 0.25),
+            Ray::new(
+                Vector::zero(),
+                Vector {
+                    x: -0.5,
+                    y: 0.25,
+                    z: -1.0,
+                }
+                .normalized()
+            )
+        );
+    }
+
+    #[test]
+    fn test_camera_constructors() {
+        let perspective = Projection::Perspective {
             fovx: Radians(90.0f32.to_radians()),
         };
+        let camera = Camera::look_at(
+            Vector {
+                x: 0.0,
+                y: 5.0,
+                z: 5.0,
+            },
+            Vector::zero(),
+            Vector {
+                x: 0.0,
+                y: 2.0,
+                z: 0.0,
+            },
+            perspective,
+            1.5,
+        )
+        .unwrap();
+        assert_almost_eq!(
+            camera.forward,
+            Vector {
+                x: 0.0,
+                y: -1.0,
+                z: -1.0,
+            }
+            .normalized()
+        );
+        assert_almost_eq!(
+            camera.up,
+            Vector {
+                x: 0.0,
+                y: 1.0,
+                z: -1.0,
+            }
+            .normalized()
+        );
+        assert_almost_eq!(camera.forward.0.dot(&camera.up.0), 0.0);
+        assert_almost_eq!(camera.aspect_ratio, 1.5);
+        assert_almost_eq!(camera.screen_ray(0.5, 0.5).dir, camera.forward);
+
+        let forward = -Vector::unitz().0;
+        let up = Vector::unity().0;
+        assert!(Camera::new(Vector::zero(), forward, up, perspective, 1.0).is_ok());
+        assert_eq!(
+            Camera::look_at(Vector::zero(), Vector::zero(), up, perspective, 1.0).err(),
+            Some(CameraError::NoDirection)
+        );
+        assert_eq!(
+            Camera::new(Vector::zero(), forward, forward * -2.0, perspective, 1.0).err(),
+            Some(CameraError::UpParallelToForward)
+        );
+        assert_eq!(
+            Camera::new(Vector::zero(), forward, Vector::zero(), perspective, 1.0).err(),
+            Some(CameraError::UpParallelToForward)
+        );
+        for &degrees in &[0.0f32, -10.0, 180.0, 270.0] {
+            assert_eq!(
+                Camera::new(
+                    Vector::zero(),
+                    forward,
+                    up,
+                    Projection::Perspective {
+                        fovx: Radians(degrees.to_radians())
+                    },
+                    1.0
+                )
+                .err(),
+                Some(CameraError::InvalidFov)
+            );
+        }
+        for &aspect_ratio in &[0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert_eq!(
+                Camera::new(Vector::zero(), forward, up, perspective, aspect_ratio).err(),
+                Some(CameraError::InvalidAspectRatio)
+            );
+        }
+        let camera = Camera::new(Vector::zero(), forward, up, perspective, 1.0).unwrap();
+        assert_eq!(camera.aperture, 0.0);
+        assert!(camera.with_lens(0.1, 5.0, 6).is_ok());
+        assert_eq!(
+            camera.with_lens(-0.1, 5.0, 0).err(),
+            Some(CameraError::InvalidAperture)
+        );
+        assert_eq!(
+            camera.with_lens(0.1, 0.0, 0).err(),
+            Some(CameraError::InvalidFocusDistance)
+        );
+        assert_eq!(
+            camera.with_lens(0.1, 5.0, 2).err(),
+            Some(CameraError::TooFewBlades)
+        );
+        assert_eq!((camera.shutter_open, camera.shutter_close), (0.0, 0.0));
+        let camera = camera.with_shutter(0.5, 1.5).unwrap();
+        assert_eq!(camera.shutter_time(0.0), 0.5);
+        assert_eq!(camera.shutter_time(0.25), 0.75);
+        for &(open, close) in &[(1.0, 0.5), (0.0, f32::NAN), (f32::NEG_INFINITY, 0.0)] {
+            assert_eq!(
+                camera.with_shutter(open, close).err(),
+                Some(CameraError::InvalidShutter)
+            );
+        }
+    }
+
+    #[test]
+    fn test_camera_projections() {
+        let camera = |projection| {
+            Camera::new(
+                Vector::zero(),
+                -Vector::unitz().0,
+                Vector::unity().0,
+                projection,
+                2.0,
+            )
+        };
+        let forward = -Vector::unitz();
+        let right = Vector::unitx();
 
+        let orthographic = camera(Projection::Orthographic { width: 4.0 }).unwrap();
+        let ray = orthographic.screen_ray(0.0, 0.25);
         assert_almost_eq!(
-            camera.screen_ray(0.0, 0.0),
-            Ray {
-                pos: Vector::zero(),
-                dir: Vector {
-                    x: -1.0,
-                    y: 0.5,
-                    z: -1.0,
-                }
-                .normalized(),
+            ray.pos,
+            Vector {
+                x: -2.0,
+                y: 0.5,
+                z: 0.0,
             }
         );
+        assert_almost_eq!(ray.dir, forward);
 
-        assert_almost_eq!(
-            camera.screen_ray(0.5, 0.5),
-            Ray {
-                pos: Vector::zero(),
-                dir: -Vector::unitz(),
-            },
+        let fovx = Radians(180.0f32.to_radians());
+        let equidistant = camera(Projection::EquidistantFisheye { fovx }).unwrap();
+        let equisolid = camera(Projection::EquisolidFisheye { fovx }).unwrap();
+        for fisheye in &[equidistant, equisolid] {
+            assert_almost_eq!(fisheye.screen_ray(0.5, 0.5).dir, forward);
+            assert_almost_eq!(fisheye.screen_ray(1.0, 0.5).dir, right);
+            // Pixels are square, with an aspect ratio of 2 the top edge is as far from the center
+            // as halfway to the side.
+            let top = fisheye.screen_ray(0.5, 0.0).dir.0;
+            let side = fisheye.screen_ray(0.75, 0.5).dir.0;
+            assert_almost_eq!(top.dot(&forward.0), side.dot(&forward.0));
+            assert!(top.y > 0.0);
+            assert!(fisheye.screen_ray(0.0, 0.0).dir.0.z > 0.0);
+        }
+        // Halfway to the edge an equidistant fisheye is halfway there in angle, an equisolid one
+        // a bit less.
+        let angle = |camera: &Camera| camera.screen_ray(0.75, 0.5).dir.0.dot(&forward.0).acos();
+        assert_almost_eq!(angle(&equidistant), f32::consts::FRAC_PI_4);
+        assert!(
+            (angle(&equisolid) - 2.0 * (0.5 * f32::consts::FRAC_PI_4.sin()).asin()).abs() < 1e-5
+        );
+        let all_around = camera(Projection::EquidistantFisheye {
+            fovx: Radians(360.0f32.to_radians()),
+        })
+        .unwrap();
+        assert!(all_around
+            .screen_ray(1.0, 0.5)
+            .dir
+            .0
+            .almost_equal_with_epsilon(&Vector::unitz().0, 1e-3));
+
+        let panorama = camera(Projection::Equirectangular).unwrap();
+        assert_almost_eq!(panorama.screen_ray(0.5, 0.5).dir, forward);
+        assert_almost_eq!(panorama.screen_ray(0.75, 0.5).dir, right);
+        assert_almost_eq!(panorama.screen_ray(0.25, 0.5).dir, -Vector::unitx());
+        assert!(panorama
+            .screen_ray(0.0, 0.5)
+            .dir
+            .0
+            .almost_equal_with_epsilon(&Vector::unitz().0, 1e-3));
+        assert!(panorama
+            .screen_ray(0.3, 0.0)
+            .dir
+            .0
+            .almost_equal_with_epsilon(&Vector::unity().0, 1e-3));
+
+        // Panoramas focus at the same distance all around.
+        let panorama = panorama.with_lens(0.1, 3.0, 0).unwrap();
+        let center = panorama.screen_ray(0.1, 0.4);
+        let in_focus = center.pos + center.dir.0 * 3.0;
+        for &(u, v) in &[(0.1, 0.2), (0.9, 0.5)] {
+            let ray = panorama.lens_ray(0.1, 0.4, u, v);
+            assert_almost_eq!((ray.pos - center.pos).dot(&center.dir.0), 0.0);
+            let t = (in_focus - ray.pos).len();
+            assert!((ray.pos + ray.dir.0 * t).almost_equal_with_epsilon(&in_focus, 1e-5));
+        }
+
+        assert_eq!(
+            camera(Projection::Orthographic { width: 0.0 }).err(),
+            Some(CameraError::InvalidWidth)
+        );
+        for &degrees in &[0.0f32, 400.0] {
+            let fovx = Radians(degrees.to_radians());
+            assert_eq!(
+                camera(Projection::EquisolidFisheye { fovx }).err(),
+                Some(CameraError::InvalidFov)
+            );
+        }
+        let fovx = Radians(270.0f32.to_radians());
+        assert!(camera(Projection::EquidistantFisheye { fovx }).is_ok());
+        assert_eq!(
+            camera(Projection::Perspective { fovx }).err(),
+            Some(CameraError::InvalidFov)
         );
+    }
 
+    #[test]
+    fn test_camera_lens_ray() {
+        let forward = Vector {
+            x: 1.0,
+            y: 0.0,
+            z: -1.0,
+        };
+        let pinhole = Camera::new(
+            Vector::unity().0,
+            forward,
+            Vector::unity().0,
+            Projection::Perspective {
+                fovx: Radians(60.0f32.to_radians()),
+            },
+            1.5,
+        )
+        .unwrap();
         assert_almost_eq!(
-            camera.screen_ray(0.25, 0.25),
-            Ray {
-                pos: Vector::zero(),
-                dir: Vector {
-                    x: -0.5,
-                    y: 0.25,
-                    z: -1.0,
-                }
-                .normalized(),
-            }
+            pinhole.lens_ray(0.2, 0.7, 0.9, 0.1),
+            pinhole.screen_ray(0.2, 0.7)
         );
+
+        let focus_distance = 4.0;
+        for &blades in &[0, 5] {
+            let camera = pinhole.with_lens(0.25, focus_distance, blades).unwrap();
+            // The center of the lens is where the pinhole was.
+            let ray = camera.lens_ray(0.5, 0.5, 0.5, 0.5);
+            if blades == 0 {
+                assert_almost_eq!(ray, pinhole.screen_ray(0.5, 0.5));
+            }
+            // Rays through the same screen point start at different points of the lens but meet
+            // again on the plane in focus.
+            let center = pinhole.screen_ray(0.3, 0.8);
+            let in_focus =
+                center.pos + center.dir.0 * (focus_distance / center.dir.0.dot(&camera.forward.0));
+            for &(u, v) in &[(0.1, 0.2), (0.9, 0.5), (0.45, 0.95)] {
+                let ray = camera.lens_ray(0.3, 0.8, u, v);
+                let offset = ray.pos - camera.position;
+                assert!(offset.len() <= 0.25 + 1e-6 && offset.len() > 0.01);
+                assert_almost_eq!(offset.dot(&camera.forward.0), 0.0);
+                let t = (in_focus - ray.pos).len();
+                assert!((ray.pos + ray.dir.0 * t).almost_equal_with_epsilon(&in_focus, 1e-5));
+            }
+        }
     }
 
     #[test]
@@ -656,6 +3379,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_unitvector_refraction() {
+        let normal = Vector::unity();
+        // Light going straight through isn't bent.
+        let down = -Vector::unity();
+        assert_almost_eq!(down.refracted(&normal, 1.0 / 1.5).unwrap(), down);
+        // Nor is it between media with the same index of refraction.
+        let slanted = vector(1.0, -1.0, 0.5).normalized();
+        assert_almost_eq!(slanted.refracted(&normal, 1.0).unwrap(), slanted);
+
+        // Snell's law: sin(incident) * n1 = sin(transmitted) * n2.
+        let incident = 60.0f32.to_radians();
+        let direction = vector(incident.sin(), -incident.cos(), 0.0).normalized();
+        let refracted = direction.refracted(&normal, 1.0 / 1.5).unwrap();
+        assert!((refracted.0.len() - 1.0).abs() < 1e-6);
+        assert!((refracted.0.x - incident.sin() / 1.5).abs() < 1e-6);
+        assert!(refracted.0.y < 0.0 && refracted.0.z == 0.0);
+        // Leaving through the parallel bottom of a glass slab restores the direction.
+        let back = refracted.refracted(&normal, 1.5).unwrap();
+        assert!(back.0.almost_equal_with_epsilon(&direction.0, 1e-6));
+
+        // From inside glass light can't leave past the critical angle of about 41.8 degrees.
+        let critical = (1.0f32 / 1.5).asin();
+        let leaving = |angle: f32| vector(angle.sin(), angle.cos(), 0.0).normalized();
+        assert!(leaving(critical - 0.01).refracted(&-normal, 1.5).is_some());
+        assert!(leaving(critical + 0.01).refracted(&-normal, 1.5).is_none());
+    }
+
     #[test]
     fn test_closest_intersection() {
         let spheres = [
@@ -663,6 +3414,7 @@ mod tests {
                 center: Vector::zero(),
                 radius: 1.0,
                 material: Material::dummy(),
+                motion: Motion::default(),
             },
             Sphere {
                 center: Vector {
@@ -672,19 +3424,20 @@ mod tests {
                 },
                 radius: 1.0,
                 material: Material::dummy(),
+                motion: Motion::default(),
             },
         ];
         assert_almost_eq!(
             closest_intersection(
                 &spheres,
-                &Ray {
-                    pos: Vector {
+                &Ray::new(
+                    Vector {
                         x: -100.0,
                         y: 0.0,
                         z: 0.0,
                     },
-                    dir: Vector::unitx(),
-                }
+                    Vector::unitx()
+                )
             ),
             Some(Intersection {
                 position: Vector {
@@ -692,22 +3445,28 @@ mod tests {
                     y: 0.0,
                     z: 0.0,
                 },
+                t: 99.0,
                 normal: -Vector::unitx(),
-                sphere: &spheres[0],
+                geometric_normal: -Vector::unitx(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.0, 0.5),
+                object: &spheres[0],
+                time: 0.0,
             }),
         );
 
         assert_almost_eq!(
             closest_intersection(
                 &spheres,
-                &Ray {
-                    pos: Vector {
+                &Ray::new(
+                    Vector {
                         x: 100.0,
                         y: 0.0,
                         z: 0.0,
                     },
-                    dir: -Vector::unitx(),
-                },
+                    -Vector::unitx()
+                ),
             ),
             Some(Intersection {
                 position: Vector {
@@ -715,73 +3474,1061 @@ mod tests {
                     y: 0.0,
                     z: 0.0,
                 },
+                t: 89.0,
                 normal: Vector::unitx(),
-                sphere: &spheres[1],
+                geometric_normal: Vector::unitx(),
+                front_face: true,
+                position_error: 0.0,
+                uv: (0.5, 0.5),
+                object: &spheres[1],
+                time: 0.0,
             }),
         );
 
         assert_almost_eq!(
             closest_intersection(
                 &spheres,
-                &Ray {
-                    pos: Vector {
+                &Ray::new(
+                    Vector {
                         x: 100.0,
                         y: 0.0,
                         z: 0.0
                     },
-                    dir: Vector::unitx()
-                },
+                    Vector::unitx()
+                ),
             ),
             None,
         );
     }
 
+    // The color of materials that aren't textured.
+    fn color(material: &Material) -> Color {
+        material.color.value(&Vector::zero(), (0.0, 0.0))
+    }
+
     #[test]
-    fn test_trace_ray() {
-        let spheres = [
-            Sphere {
-                center: Vector {
-                    x: 2.0,
-                    y: 1.0,
-                    z: 1.0,
-                },
-                radius: 1.0,
-                material: Material {
-                    color: Color::new_red(),
-                },
-            },
-            Sphere {
-                center: Vector {
-                    x: 4.0,
-                    y: 4.0,
-                    z: 1.0,
+    fn test_shadows() {
+        let floor = Plane {
+            point: Vector::zero(),
+            normal: Vector::unity(),
+            material: Material::diffuse(Color::new_white()),
+        };
+        let blocker = Sphere {
+            center: vector(0.0, 2.0, -5.0),
+            radius: 1.0,
+            material: Material::diffuse(Color::new_white()),
+            motion: Motion::default(),
+        };
+        let scene = scene_with(
+            vec![Box::new(floor), Box::new(blocker)],
+            vec![Box::new(PointLight {
+                position: vector(0.0, 5.0, -5.0),
+                color: Color::new_white(),
+                intensity: 25.0,
+            })],
+        );
+        let down_at = |x| Ray::new(vector(x, 0.5, -5.0), -Vector::unity());
+        let light_at = |ray: Ray| {
+            let intersection = scene.closest_intersection(&ray).unwrap();
+            scene.direct_lighting(&intersection, &-ray.dir)
+        };
+        assert_almost_eq!(light_at(down_at(0.0)), Color::new_black());
+        // The irradiance times the Lambertian BRDF (1 / pi for white).
+        let lit = 25.0 * 5.0 / 34.0f32.powf(1.5) / f32::consts::PI;
+        assert!(light_at(down_at(3.0)).almost_equal_with_epsilon(&Color::new(lit, lit, lit), 1e-5));
+        let lights_at = |ray: Ray| {
+            let intersection = scene.closest_intersection(&ray).unwrap();
+            scene.visible_lights(&intersection).count()
+        };
+        assert_eq!(lights_at(down_at(0.0)), 0);
+        assert_eq!(lights_at(down_at(3.0)), 1);
+        // The blocker can't cast a shadow on itself.
+        let above_blocker = Ray::new(vector(0.0, 4.5, -5.0), -Vector::unity());
+        assert_eq!(lights_at(above_blocker), 1);
+    }
+
+    #[test]
+    fn test_secondary_rays_leave_the_surface() {
+        // The walls of the original example scene, huge spheres that barely curve. Rays bouncing
+        // off of them at grazing angles would easily hit them again if they started right at the
+        // computed intersection point, especially far away from the origin.
+        for &offset in &[0.0, 1000.0, -20000.0] {
+            let origin = vector(offset, offset, offset);
+            let wall = |x, y, z| {
+                Box::new(Sphere {
+                    center: origin + vector(x, y, z),
+                    radius: 10000.0,
+                    material: Material::dummy(),
+                    motion: Motion::default(),
+                }) as Box<dyn Shape>
+            };
+            let walls = vec![
+                wall(0.0, -10005.0, 0.0),
+                wall(0.0, 10005.0, 0.0),
+                wall(-10010.0, 0.0, 0.0),
+                wall(10010.0, 0.0, 0.0),
+                wall(0.0, 0.0, -10015.0),
+                wall(0.0, 0.0, 10005.0),
+            ];
+            // Both the eye and the light are close to the walls so plenty of rays graze them.
+            let light = PointLight {
+                position: origin + vector(0.0, 4.9, -14.9),
+                color: Color::new_white(),
+                intensity: 1.0,
+            };
+            let scene = scene_with(walls, vec![Box::new(light)]);
+            let eye = origin + vector(0.0, -4.9, 0.0);
+            let steps = 64;
+            for i in 0..steps {
+                for j in 0..steps {
+                    let theta = (i as f32 + 0.5) / steps as f32 * f32::consts::PI;
+                    let phi = j as f32 / steps as f32 * 2.0 * f32::consts::PI;
+                    let ray = Ray::new(
+                        eye,
+                        vector(
+                            theta.sin() * phi.cos(),
+                            theta.cos(),
+                            theta.sin() * phi.sin(),
+                        )
+                        .normalized(),
+                    );
+                    let hit = scene.closest_intersection(&ray).unwrap();
+                    // The walls are convex so light bouncing off of one can't hit it again.
+                    let bounce = scene.closest_intersection(&ray.reflected(&hit)).unwrap();
+                    assert!(
+                        !ptr::addr_eq(hit.object, bounce.object),
+                        "{:?} hits its own surface again",
+                        ray
+                    );
+                    let towards_light = (light.position - hit.position).normalized();
+                    if hit.normal.0.dot(&towards_light.0) > 0.0 {
+                        assert_eq!(scene.visible_lights(&hit).count(), 1, "{:?}", ray);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_scene() {
+        let scene = load_scene(
+            "
+            # A comment
+            render {
+                width = 40   # Another comment
+                height = 20
+                bounces = 1
+                samples = 16
+                seed = 7
+                mode = path
+            }
+
+            camera {
+                position = 1 2 3
+                look_at = 1 2 -7
+                up = 0 1 0
+                fovx = 90
+            }
+
+            material red {
+                color = 1 0 0
+            }
+
+            sphere {
+                center = 0 0 -5
+                radius = 1.5
+                material = red
+            }
+
+            sphere {
+                center = 0 1 -5
+                radius = 2
+                color = 0 0.5 0
+                emission = 2 2 2
+            }
+
+            plane {
+                point = 0 -1 0
+                normal = 0 2 0
+                material = red
+            }
+
+            point_light {
+                position = 0 4 0
+                color = 1 0.5 0
+                intensity = 8
+            }
+
+            directional_light {
+                direction = 0 -3 0
+            }
+            ",
+            Path::new(""),
+        )
+        .unwrap();
+        assert_eq!(
+            scene.settings,
+            RenderSettings {
+                width: 40,
+                height: 20,
+                bounces: 1,
+                samples: 16,
+                seed: 7,
+                mode: RenderMode::PathTracing,
+                ..RenderSettings::default()
+            }
+        );
+        assert_almost_eq!(
+            scene.camera.position,
+            Vector {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0,
+            }
+        );
+        assert_almost_eq!(scene.camera.forward, -Vector::unitz());
+        assert_almost_eq!(scene.camera.up, Vector::unity());
+        assert_almost_eq!(scene.camera.aspect_ratio, 2.0);
+        match scene.camera.projection {
+            Projection::Perspective { fovx } => assert_almost_eq!(fovx.0, 90.0f32.to_radians()),
+            projection => panic!("{:?}", projection),
+        }
+        assert_eq!(scene.camera.aperture, 0.0);
+        let shapes = scene.shapes();
+        assert_eq!(shapes.len(), 3);
+        assert_almost_eq!(
+            shapes[0].bounding_box(),
+            Some(Aabb {
+                min: Vector {
+                    x: -1.5,
+                    y: -1.5,
+                    z: -6.5,
                 },
-                radius: 1.0,
-                material: Material {
-                    color: Color::new_green(),
+                max: Vector {
+                    x: 1.5,
+                    y: 1.5,
+                    z: -3.5,
                 },
-            },
-        ];
-        let ray = Ray {
-            pos: Vector {
-                x: 1.0,
-                y: 3.0,
-                z: 1.0,
-            },
-            dir: Vector {
-                x: 1.0,
+            })
+        );
+        assert_almost_eq!(color(shapes[0].material()), Color::new_red());
+        assert_almost_eq!(shapes[0].material().emission, Color::new_black());
+        assert_almost_eq!(color(shapes[1].material()), Color::new(0.0, 0.5, 0.0));
+        assert_almost_eq!(shapes[1].material().emission, Color::new(2.0, 2.0, 2.0));
+        assert!(shapes[2].bounding_box().is_none());
+        assert_almost_eq!(color(shapes[2].material()), Color::new_red());
+        let ray = Ray::new(Vector::zero(), -Vector::unity());
+        assert_almost_eq!(
+            shapes[2].intersect_ray(&ray).unwrap().position,
+            Vector {
+                x: 0.0,
                 y: -1.0,
                 z: 0.0,
             }
-            .normalized(),
+        );
+        let lights = scene.lights();
+        assert_eq!(lights.len(), 2);
+        let sample = lights[0].illuminate(&Vector::zero());
+        assert_almost_eq!(sample.direction, Vector::unity());
+        assert_almost_eq!(sample.distance, 4.0);
+        assert_almost_eq!(sample.radiance, Color::new(0.5, 0.25, 0.0));
+        let sample = lights[1].illuminate(&Vector::zero());
+        assert_almost_eq!(sample.direction, Vector::unity());
+        assert_almost_eq!(sample.radiance, Color::new_white());
+    }
+
+    #[test]
+    fn test_load_example_scene() {
+        let scene = load_scene(include_str!("../scenes/example.scene"), Path::new("")).unwrap();
+        assert_eq!(scene.shapes().len(), 9);
+        assert_eq!(scene.lights().len(), 2);
+        let scene =
+            load_scene(include_str!("../scenes/sphere_walls.scene"), Path::new("")).unwrap();
+        assert_eq!(scene.shapes().len(), 9);
+        assert_eq!(scene.bvh_stats().unbounded, 0);
+        assert_eq!(scene.settings, RenderSettings::default());
+        assert_almost_eq!(scene.camera.aspect_ratio, 4.0 / 3.0);
+        let scene = load_scene(include_str!("../scenes/cornell.scene"), Path::new("")).unwrap();
+        assert_eq!(scene.settings.mode, RenderMode::PathTracing);
+        assert!(scene.lights().is_empty());
+        assert!(!scene.shapes()[0].material().emission.is_black());
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let scene = load_scene(include_str!("../scenes/textures.scene"), &directory).unwrap();
+        assert_eq!(scene.shapes().len(), 8);
+        let scene = load_scene(
+            include_str!("../scenes/depth_of_field.scene"),
+            Path::new(""),
+        )
+        .unwrap();
+        assert_eq!(scene.camera.blades, 6);
+        let scene = load_scene(include_str!("../scenes/motion_blur.scene"), Path::new("")).unwrap();
+        assert_eq!(scene.camera.shutter_close, 1.0);
+        assert_eq!(scene.shapes().len(), 6);
+        let scene = load_scene(include_str!("../scenes/primitives.scene"), &directory).unwrap();
+        assert_eq!(scene.shapes().len(), 11);
+        assert_eq!(scene.bvh_stats().unbounded, 1);
+    }
+
+    #[test]
+    fn test_load_camera_lens() {
+        let load = |camera: &str| {
+            load_scene(
+                &format!(
+                    "camera {{\n position = 0 0 0\n up = 0 1 0\n fovx = 90\n{}\n}}",
+                    camera
+                ),
+                Path::new(""),
+            )
+            .unwrap()
+            .camera
         };
-        assert_almost_eq!(
-            trace_ray(&spheres, &ray, 0),
-            45.0f32.to_radians().cos() * Color::new_red(),
+        // Cameras focus on what they look at unless told otherwise.
+        let camera = load("look_at = 0 3 -4\naperture = 0.05");
+        assert_eq!(camera.aperture, 0.05);
+        assert_almost_eq!(camera.focus_distance, 5.0);
+        assert_eq!(camera.blades, 0);
+        let camera = load("look_at = 0 3 -4\naperture = 0.05\nfocus_distance = 2\nblades = 6");
+        assert_almost_eq!(camera.focus_distance, 2.0);
+        assert_eq!(camera.blades, 6);
+        let camera = load("forward = 0 0 -1\naperture = 0.1\nfocus_distance = 3");
+        assert_almost_eq!(camera.focus_distance, 3.0);
+    }
+
+    #[test]
+    fn test_load_motion_blur() {
+        let scene = load_scene(
+            "camera {\n position = 0 0 0\n forward = 0 0 -1\n up = 0 1 0\n fovx = 90\n shutter = 0 0.5\n}\n\
+             sphere {\n center = 0 0 -5\n radius = 1\n color = 1 1 1\n motion = 1 0 1 0  0 0 0 0\n}\n\
+             sphere {\n center = 0 0 -5\n radius = 1\n color = 1 1 1\n}",
+            Path::new(""),
+        )
+        .unwrap();
+        assert_eq!(scene.camera.shutter_open, 0.0);
+        assert_eq!(scene.camera.shutter_close, 0.5);
+        let moving = scene.shapes()[0].intersect_ray(&Ray {
+            time: 0.5,
+            ..Ray::new(
+                Vector {
+                    x: 0.0,
+                    y: 0.5,
+                    z: 0.0,
+                },
+                -Vector::unitz(),
+            )
+        });
+        assert_almost_eq!(moving.unwrap().t, 4.0);
+        let still = scene.shapes()[1].intersect_ray(&Ray {
+            time: 0.5,
+            ..Ray::new(Vector::zero(), -Vector::unitz())
+        });
+        assert_almost_eq!(still.unwrap().t, 4.0);
+    }
+
+    #[test]
+    fn test_load_projections() {
+        let source = |camera: &str| {
+            format!(
+                "camera {{\n position = 0 0 0\n forward = 0 0 -1\n up = 0 1 0\n{}\n}}",
+                camera
+            )
+        };
+        let projection = |camera: &str| {
+            load_scene(&source(camera), Path::new(""))
+                .unwrap()
+                .camera
+                .projection
+        };
+        assert!(matches!(
+            projection("fovx = 90"),
+            Projection::Perspective { .. }
+        ));
+        assert!(matches!(
+            projection("projection = orthographic\nwidth = 5"),
+            Projection::Orthographic { width } if width == 5.0
+        ));
+        match projection("projection = equisolid_fisheye\nfovx = 360") {
+            Projection::EquisolidFisheye { fovx } => {
+                assert_almost_eq!(fovx.0, 2.0 * f32::consts::PI)
+            }
+            projection => panic!("{:?}", projection),
+        }
+        assert!(matches!(
+            projection("projection = equidistant_fisheye\nfovx = 200"),
+            Projection::EquidistantFisheye { .. }
+        ));
+        assert!(matches!(
+            projection("projection = equirectangular"),
+            Projection::Equirectangular
+        ));
+
+        let error = |camera: &str| error(&source(camera));
+        assert_eq!(
+            error("projection = cylindrical"),
+            (5, 14, Some("projection".to_string()))
+        );
+        assert_eq!(
+            error("projection = orthographic"),
+            (1, 1, Some("width".to_string()))
+        );
+        assert_eq!(
+            error("projection = orthographic\nwidth = 5\nfovx = 90"),
+            (7, 1, Some("fovx".to_string()))
+        );
+        assert_eq!(
+            error("fovx = 90\nwidth = 5"),
+            (6, 1, Some("width".to_string()))
+        );
+        assert_eq!(error("fovx = 200"), (5, 8, Some("fovx".to_string())));
+        assert_eq!(
+            error("projection = equidistant_fisheye\nfovx = 400"),
+            (6, 8, Some("fovx".to_string()))
+        );
+        assert_eq!(
+            error("projection = equirectangular\nfovx = 90"),
+            (6, 1, Some("fovx".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_load_render_modes() {
+        let mode = |render: &str| {
+            let source = format!("render {{\n{}\n}}\n{}", render, CAMERA);
+            load_scene(&source, Path::new("")).unwrap().settings.mode
+        };
+        assert_eq!(mode(""), RenderMode::Classic);
+        assert_eq!(mode("mode = classic"), RenderMode::Classic);
+        assert_eq!(mode("mode = path"), RenderMode::PathTracing);
+        assert_eq!(mode("mode = normals"), RenderMode::Normals);
+        assert_eq!(
+            mode("mode = ambient_occlusion"),
+            RenderMode::AmbientOcclusion {
+                distance: f32::INFINITY
+            }
+        );
+        assert_eq!(
+            mode("occlusion_distance = 2.5\nmode = ambient_occlusion"),
+            RenderMode::AmbientOcclusion { distance: 2.5 }
+        );
+    }
+
+    #[test]
+    fn test_load_materials() {
+        let scene = load_scene(
+            &format!(
+                "{}
+                material glass {{
+                    type = dielectric
+                    ior = 1.5
+                }}
+                material tinted {{
+                    type = dielectric
+                    ior = 1.33
+                    color = 0.9 1 1
+                    absorption = 0 0.1 0.2
+                }}
+                sphere {{
+                    center = 0 0 -5
+                    radius = 1
+                    material = glass
+                }}
+                sphere {{
+                    center = 0 0 -5
+                    radius = 1
+                    material = tinted
+                }}
+                sphere {{
+                    center = 0 0 -5
+                    radius = 1
+                    type = dielectric
+                    ior = 2.4
+                    emission = 1 1 1
+                }}
+                sphere {{
+                    center = 0 0 -5
+                    radius = 1
+                    type = diffuse
+                    color = 1 0 0
+                }}
+                sphere {{
+                    center = 0 0 -5
+                    radius = 1
+                    type = microfacet
+                    color = 1 0.8 0.3
+                    roughness = 0.2
+                    metallic = 1
+                }}
+                sphere {{
+                    center = 0 0 -5
+                    radius = 1
+                    type = microfacet
+                    color = 0 0 1
+                    roughness = 0
+                }}",
+                CAMERA
+            ),
+            Path::new(""),
+        )
+        .unwrap();
+        let materials: Vec<_> = scene.shapes().iter().map(|s| s.material()).collect();
+        assert_almost_eq!(color(materials[0]), Color::new_white());
+        assert_eq!(
+            materials[0].surface,
+            Surface::Dielectric {
+                ior: 1.5,
+                absorption: Color::new_black()
+            }
+        );
+        assert_almost_eq!(color(materials[1]), Color::new(0.9, 1.0, 1.0));
+        assert_eq!(
+            materials[1].surface,
+            Surface::Dielectric {
+                ior: 1.33,
+                absorption: Color::new(0.0, 0.1, 0.2)
+            }
+        );
+        assert_eq!(
+            materials[2].surface,
+            Surface::Dielectric {
+                ior: 2.4,
+                absorption: Color::new_black()
+            }
+        );
+        assert_almost_eq!(materials[2].emission, Color::new_white());
+        assert_eq!(materials[3].surface, Surface::Diffuse);
+        assert_almost_eq!(color(materials[3]), Color::new_red());
+        assert_eq!(
+            materials[4].surface,
+            Surface::Microfacet {
+                roughness: 0.2,
+                metallic: 1.0
+            }
+        );
+        assert_almost_eq!(color(materials[4]), Color::new(1.0, 0.8, 0.3));
+        assert_eq!(
+            materials[5].surface,
+            Surface::Microfacet {
+                roughness: 0.0,
+                metallic: 0.0
+            }
+        );
+
+        let error = |material: &str| error(&format!("{}material m {{\n{}\n}}", CAMERA, material));
+        assert_eq!(error("type = metal"), (8, 8, Some("type".to_string())));
+        assert_eq!(error("type = dielectric"), (7, 1, Some("ior".to_string())));
+        assert_eq!(
+            error("color = 1 1 1\nior = 1.5"),
+            (9, 1, Some("ior".to_string()))
+        );
+        assert_eq!(
+            error("type = dielectric\nior = 1.5\nabsorption = 0 -1 0"),
+            (10, 1, Some("absorption".to_string()))
+        );
+        assert_eq!(
+            error("type = microfacet\ncolor = 1 1 1"),
+            (7, 1, Some("roughness".to_string()))
+        );
+        assert_eq!(
+            error("type = microfacet\ncolor = 1 1 1\nroughness = 1.5"),
+            (10, 13, Some("roughness".to_string()))
+        );
+        assert_eq!(
+            error("type = dielectric\nior = 1.5\nmetallic = 1"),
+            (10, 1, Some("metallic".to_string()))
+        );
+        assert_eq!(
+            error("type = microfacet\ncolor = 1 1 1\nroughness = 0.5\nior = 1.5"),
+            (11, 1, Some("ior".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_load_textures() {
+        let scene = load_scene(
+            &format!(
+                "{}
+                texture checks {{
+                    type = checkerboard
+                    even = 1 1 1
+                    odd = 0 0 0
+                    scale = 2
+                }}
+                texture sky {{
+                    type = gradient
+                    start = 0 0 0
+                    end = 0 0 1
+                }}
+                material floor {{
+                    color = checks
+                }}
+                plane {{
+                    point = 0 0 0
+                    normal = 0 0 1
+                    material = floor
+                }}
+                sphere {{
+                    center = 0 0 -5
+                    radius = 1
+                    type = microfacet
+                    color = sky
+                    roughness = 0.5
+                }}
+                sphere {{
+                    center = 0 0 -5
+                    radius = 1
+                    color = 0 1 0
+                }}",
+                CAMERA
+            ),
+            Path::new(""),
+        )
+        .unwrap();
+        let at = |i: usize, uv| {
+            scene.shapes()[i]
+                .material()
+                .color
+                .value(&Vector::zero(), uv)
+        };
+        assert_almost_eq!(at(0, (0.25, 0.25)), Color::new_white());
+        assert_almost_eq!(at(0, (0.75, 0.25)), Color::new_black());
+        assert_almost_eq!(at(1, (0.0, 0.5)), Color::new(0.0, 0.0, 0.5));
+        assert_almost_eq!(at(2, (0.3, 0.7)), Color::new_green());
+
+        let error = |texture: &str| {
+            error(&format!(
+                "{}texture t {{\n{}\n}}\nmaterial m {{\ncolor = t\n}}",
+                CAMERA, texture
+            ))
+        };
+        assert_eq!(error("type = plaid"), (8, 8, Some("type".to_string())));
+        assert_eq!(error("even = 1 1 1"), (7, 1, Some("type".to_string())));
+        assert_eq!(
+            error("type = stripes\neven = 1 1 1"),
+            (7, 1, Some("odd".to_string()))
+        );
+        assert_eq!(
+            error("type = noise\nlow = 0 0 0\nhigh = 1 1 1\nscale = 0"),
+            (11, 9, Some("scale".to_string()))
+        );
+        assert_eq!(
+            error("type = gradient\nstart = 0 0 0\nend = 1 1 1\nscale = 2"),
+            (11, 1, Some("scale".to_string()))
+        );
+        assert_eq!(
+            error("type = marble\nlow = 0 0 0\nhigh = 1 1 1\nturbulence = -1"),
+            (11, 14, Some("turbulence".to_string()))
+        );
+        let source = format!("{}material m {{\ncolor = marble\n}}", CAMERA);
+        let error = load_scene(&source, Path::new("")).err().unwrap();
+        assert_eq!(error.line, 8);
+        assert_eq!(
+            error.to_string(),
+            "line 8, column 9: color: unknown texture \"marble\""
+        );
+        let source = format!("{}texture {{\ntype = wood\n}}", CAMERA);
+        let error = load_scene(&source, Path::new("")).err().unwrap();
+        assert_eq!((error.line, error.column), (7, 1));
+    }
+
+    #[test]
+    fn test_load_image_texture() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let source = |texture: &str| {
+            format!(
+                "{}texture t {{\ntype = image\nfile = images/bricks.png\n{}\n}}\n\
+                 sphere {{\ncenter = 0 0 -5\nradius = 1\ncolor = t\n}}",
+                CAMERA, texture
+            )
+        };
+        let load = |texture: &str| load_scene(&source(texture), &directory).unwrap();
+        let at = |scene: &Scene, uv| {
+            scene.shapes()[0]
+                .material()
+                .color
+                .value(&Vector::zero(), uv)
+        };
+        // The top left pixel of the image is mortar, at its center bilinear filtering gives just
+        // that pixel.
+        let corner = (0.5 / 64.0, 1.0 - 0.5 / 64.0);
+        let srgb = load("");
+        let linear = load("color_space = linear\nfilter = nearest\nwrap = clamp");
+        let mortar = Color::new(200.0, 196.0, 186.0) / 255.0;
+        assert_almost_eq!(at(&linear, corner), mortar);
+        assert!(at(&srgb, corner).almost_equal_with_epsilon(&mortar.srgb_to_linear(), 1e-6));
+        // Further in is a brick.
+        assert!(at(&linear, (0.25, 0.5)).r > 0.5);
+
+        let error = |texture: &str| error(&source(texture));
+        assert_eq!(
+            error("filter = cubic"),
+            (10, 10, Some("filter".to_string()))
+        );
+        assert_eq!(error("wrap = around"), (10, 8, Some("wrap".to_string())));
+        assert_eq!(
+            error("color_space = xyz"),
+            (10, 15, Some("color_space".to_string()))
+        );
+        assert_eq!(error("scale = 2"), (10, 1, Some("scale".to_string())));
+        let error = load_scene(
+            &format!(
+                "{}texture t {{\ntype = image\nfile = missing.png\n}}",
+                CAMERA
+            ),
+            &directory,
+        )
+        .err()
+        .unwrap();
+        assert_eq!((error.line, error.column), (9, 8));
+        assert!(error.to_string().contains("cannot read \"missing.png\""));
+        let error = load_scene(
+            &format!(
+                "{}texture t {{\ntype = image\nfile = mesh.scene\n}}",
+                CAMERA
+            ),
+            &directory,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "line 9, column 8: file: invalid image \"mesh.scene\": expected a PPM or PNG image"
         );
+    }
+
+    #[test]
+    fn test_load_mesh_scene() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let scene = load_scene(include_str!("../scenes/mesh.scene"), &directory).unwrap();
+        assert_eq!(scene.shapes().len(), 4);
         assert_almost_eq!(
-            trace_ray(&spheres, &ray, 1),
-            45.0f32.to_radians().cos() * (Color::new_red() + Color::new_green()),
+            scene.shapes()[0].bounding_box(),
+            Some(Aabb {
+                min: Vector {
+                    x: -1.0,
+                    y: 0.0,
+                    z: -1.0,
+                },
+                max: Vector {
+                    x: 1.0,
+                    y: 1.5,
+                    z: 1.0,
+                },
+            })
+        );
+
+        let error = load_scene(
+            include_str!("../scenes/mesh.scene"),
+            Path::new("/nonexistent"),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            (error.line, error.column, error.key),
+            (21, 12, Some("file".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_load_primitives() {
+        let scene = load_scene(
+            "camera {\n position = 0 0 0\n forward = 0 0 -1\n up = 0 1 0\n fovx = 90\n}\n\
+             box {\n min = -1 -1 -6\n max = 1 1 -4\n color = 1 1 1\n}\n\
+             box {\n min = -1 -1 -6\n max = 1 1 -4\n rotate = 0 1 0 45\n color = 1 1 1\n}\n\
+             disk {\n center = 0 -2 0\n normal = 0 1 0\n radius = 3\n inner_radius = 1\n color = 1 1 1\n}\n\
+             cylinder {\n base = 5 0 0\n top = 5 4 0\n radius = 0.5\n color = 1 1 1\n}\n\
+             cone {\n base = 0 0 5\n tip = 0 0 8\n radius = 1\n color = 1 1 1\n}\n\
+             torus {\n center = 0 10 0\n axis = 0 0 2\n major_radius = 2\n minor_radius = 0.5\n color = 1 1 1\n}",
+            Path::new(""),
+        )
+        .unwrap();
+        let bounds: Vec<_> = scene
+            .shapes()
+            .iter()
+            .map(|shape| shape.bounding_box().unwrap())
+            .collect();
+        assert_almost_eq!(bounds[0].min, vector(-1.0, -1.0, -6.0));
+        // Rotated around its center the box gets wider.
+        let half_diagonal = 2.0f32.sqrt();
+        assert!(bounds[1]
+            .min
+            .almost_equal_with_epsilon(&vector(-half_diagonal, -1.0, -5.0 - half_diagonal), 1e-5));
+        assert_almost_eq!(bounds[2].max, vector(3.0, -2.0, 3.0));
+        assert_almost_eq!(bounds[3].max, vector(5.5, 4.0, 0.5));
+        assert_almost_eq!(bounds[4].max, vector(1.0, 1.0, 8.0));
+        assert_almost_eq!(bounds[5].min, vector(-2.5, 7.5, -0.5));
+        let hit = scene
+            .closest_intersection(&Ray::new(Vector::zero(), -Vector::unitz()))
+            .unwrap();
+        assert_almost_eq!(hit.t, 5.0 - half_diagonal);
+    }
+
+    #[test]
+    fn test_load_instances() {
+        let scene = load_scene(
+            "camera {\n position = 0 0 0\n forward = 0 0 -1\n up = 0 1 0\n fovx = 90\n}\n\
+             sphere ball {\n center = 0 0 0\n radius = 1\n color = 0 0 0\n emission = 1 0 0\n}\n\
+             instance egg {\n shape = ball\n scale = 1 2 1\n}\n\
+             instance {\n shape = ball\n translate = 0 0 -5\n}\n\
+             instance {\n shape = egg\n scale = 0.5\n rotate = 0 0 1 90\n translate = 3 0 0\n}\n\
+             instance {\n shape = ball\n translate = 0 10 0\n motion = 0 0 0 0  1 0 -4 0\n}",
+            Path::new(""),
+        )
+        .unwrap();
+        // Named shapes are only drawn where they're placed.
+        assert_eq!(scene.shapes().len(), 3);
+        let hit = scene
+            .closest_intersection(&Ray::new(Vector::zero(), -Vector::unitz()))
+            .unwrap();
+        assert_almost_eq!(hit.t, 4.0);
+        assert_eq!(hit.object.material().emission, Color::new(1.0, 0.0, 0.0));
+        // Scaled first, then rotated and moved.
+        let bounds = scene.shapes()[1].bounding_box().unwrap();
+        assert!(bounds.min.almost_equal_with_epsilon(
+            &Vector {
+                x: 2.0,
+                y: -0.5,
+                z: -0.5
+            },
+            1e-6
+        ));
+        assert!(bounds.max.almost_equal_with_epsilon(
+            &Vector {
+                x: 4.0,
+                y: 0.5,
+                z: 0.5
+            },
+            1e-6
+        ));
+        // Instances move like spheres do.
+        let up = Ray::new(Vector::zero(), Vector::unity());
+        assert_almost_eq!(scene.closest_intersection(&up).unwrap().t, 9.0);
+        let later = Ray { time: 1.0, ..up };
+        assert_almost_eq!(scene.closest_intersection(&later).unwrap().t, 5.0);
+        let bounds = scene.shapes()[2].bounding_box().unwrap();
+        assert_almost_eq!(bounds.min.y, 5.0);
+        assert_almost_eq!(bounds.max.y, 11.0);
+
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let scene = load_scene(include_str!("../scenes/instances.scene"), &directory).unwrap();
+        assert_eq!(scene.shapes().len(), 66);
+    }
+
+    #[test]
+    fn test_load_scene_errors() {
+        assert_eq!(
+            error(&format!(
+                "{}sphere {{\n  radius = abc\n  center = 0 0 0\n}}",
+                CAMERA
+            )),
+            (8, 12, Some("radius".to_string()))
+        );
+        assert_eq!(
+            error(&format!("{}sphere {{\n  center = 1 2\n}}", CAMERA)),
+            (8, 3, Some("center".to_string()))
+        );
+        assert_eq!(
+            error(&format!("{}sphere {{\n  center = 1 2 3 4\n}}", CAMERA)),
+            (8, 18, Some("center".to_string()))
+        );
+        assert_eq!(
+            error(&format!("{}sphere {{\n  colour = 1 1 1\n}}", CAMERA)),
+            (8, 3, Some("colour".to_string()))
+        );
+        assert_eq!(
+            error(&format!("{}point_light {{\n  intensity = 1\n}}", CAMERA)),
+            (7, 1, Some("position".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}directional_light {{\n  direction = 0 -1 0\n  intensity = -2\n}}",
+                CAMERA
+            )),
+            (9, 15, Some("intensity".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}sphere {{\n  center = 0 0 0\n  radius = 1\n  material = wood\n}}",
+                CAMERA
+            )),
+            (10, 14, Some("material".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}sphere {{\n  center = 0 0 0\n  radius = 1\n}}",
+                CAMERA
+            )),
+            (7, 1, Some("material".to_string()))
+        );
+        assert_eq!(error(&format!("{}cube {{\n}}", CAMERA)), (7, 1, None));
+        assert_eq!(error(&format!("{}sphere {{\n", CAMERA)), (7, 1, None));
+        assert_eq!(
+            error("camera {\n  position = 0 0 0\n  fovx = 90 # no forward\n}"),
+            (1, 1, Some("forward".to_string()))
+        );
+        assert_eq!(
+            error(
+                "camera {\n  position = 0 0 0\n  forward = 0 0 -1\n  look_at = 0 0 -5\n  fovx = 90\n}"
+            ),
+            (4, 3, Some("look_at".to_string()))
+        );
+        assert_eq!(
+            error("camera {\n  position = 0 1 0\n  look_at = 0 1 0\n  up = 0 1 0\n  fovx = 90\n}"),
+            (3, 3, Some("look_at".to_string()))
+        );
+        assert_eq!(
+            error("camera {\n  position = 0 0 0\n  look_at = 0 -5 0\n  up = 0 1 0\n  fovx = 90\n}"),
+            (4, 3, Some("up".to_string()))
+        );
+        assert_eq!(
+            error("camera {\n  position = 0 0 0\n  forward = 0 0 -1\n  up = 0 1 0\n  fovx = 90\n  aperture = 0.1\n}"),
+            (1, 1, Some("focus_distance".to_string()))
+        );
+        assert_eq!(
+            error("camera {\n  position = 0 0 0\n  look_at = 0 0 -5\n  up = 0 1 0\n  fovx = 90\n  aperture = 0.1\n  blades = 2\n}"),
+            (7, 3, Some("blades".to_string()))
+        );
+        assert_eq!(
+            error("camera {\n  position = 0 0 0\n  look_at = 0 0 -5\n  up = 0 1 0\n  fovx = 90\n  aperture = 0.1\n  blades = 4294967296\n}"),
+            (7, 12, Some("blades".to_string()))
+        );
+        assert_eq!(
+            error("camera {\n  position = 0 0 0\n  forward = 0 0 -1\n  up = 0 1 0\n  fovx = 90\n  shutter = 1 0\n}"),
+            (6, 3, Some("shutter".to_string()))
+        );
+        assert_eq!(
+            error("camera {\n  position = 0 0 0\n  forward = 0 0 -1\n  up = 0 1 0\n  fovx = 90\n  shutter = 1\n}"),
+            (6, 3, Some("shutter".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}sphere {{\n  center = 0 0 0\n  radius = 1\n  motion = 0 0 0 0 1 1 0\n}}",
+                CAMERA
+            )),
+            (10, 3, Some("motion".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}sphere {{\n  center = 0 0 0\n  radius = 1\n  motion = 0 0 0 0 x 1 0 0\n}}",
+                CAMERA
+            )),
+            (10, 20, Some("motion".to_string()))
+        );
+        let ball = "sphere ball {\n  center = 0 0 0\n  radius = 1\n  color = 1 1 1\n}\n";
+        assert_eq!(
+            error(&format!(
+                "{}{}instance {{\n  shape = cube\n}}",
+                CAMERA, ball
+            )),
+            (13, 11, Some("shape".to_string()))
+        );
+        assert_eq!(
+            error(&format!("{}{}instance {{\n  scale = 2\n}}", CAMERA, ball)),
+            (12, 1, Some("shape".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}{}instance {{\n  shape = ball\n  scale = 1 0 1\n}}",
+                CAMERA, ball
+            )),
+            (14, 11, Some("scale".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}{}instance {{\n  shape = ball\n  rotate = 0 0 0 90\n}}",
+                CAMERA, ball
+            )),
+            (14, 12, Some("rotate".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}{}instance {{\n  shape = ball\n  rotate = 0 1 0\n}}",
+                CAMERA, ball
+            )),
+            (14, 3, Some("rotate".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}{}instance {{\n  shape = ball\n  motion = 0 0 1\n}}",
+                CAMERA, ball
+            )),
+            (14, 3, Some("motion".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}box {{\n  min = 0 0 0\n  max = 1 0 1\n  color = 1 1 1\n}}",
+                CAMERA
+            )),
+            (9, 3, Some("max".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}disk {{\n  center = 0 0 0\n  normal = 0 1 0\n  radius = 1\n  inner_radius = 2\n}}",
+                CAMERA
+            )),
+            (11, 18, Some("inner_radius".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}cylinder {{\n  base = 0 1 0\n  top = 0 1 0\n  radius = 1\n}}",
+                CAMERA
+            )),
+            (9, 3, Some("top".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}cone {{\n  base = 0 1 0\n  radius = 1\n}}",
+                CAMERA
+            )),
+            (7, 1, Some("tip".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}torus {{\n  center = 0 0 0\n  axis = 0 1 0\n  major_radius = 1\n  minor_radius = 1\n}}",
+                CAMERA
+            )),
+            (11, 18, Some("minor_radius".to_string()))
+        );
+        // Named instances can only use shapes defined before them.
+        assert_eq!(
+            error(&format!(
+                "{}instance egg {{\n  shape = ball\n}}\n{}",
+                CAMERA, ball
+            )),
+            (8, 11, Some("shape".to_string()))
+        );
+        assert_eq!(error(&format!("{}{}{}", CAMERA, ball, ball)), (12, 8, None));
+        assert_eq!(
+            error(&format!(
+                "{}point_light lamp {{\n  position = 0 1 0\n}}",
+                CAMERA
+            )),
+            (7, 13, None)
+        );
+        assert_eq!(error("render {\n}"), (2, 1, None));
+        assert_eq!(
+            error("render {\n  mode = fast\n}"),
+            (2, 10, Some("mode".to_string()))
+        );
+        assert_eq!(
+            error("render {\n  mode = path\n  occlusion_distance = 2\n}"),
+            (3, 3, Some("occlusion_distance".to_string()))
+        );
+        assert_eq!(
+            error(&format!(
+                "{}material lamp {{\n  color = 0 0 0\n  emission = 1 1 1\n}}\nsphere {{\n  center = 0 0 0\n  radius = 1\n  material = lamp\n  emission = 1 1 1\n}}",
+                CAMERA
+            )),
+            (15, 3, Some("emission".to_string()))
+        );
+
+        assert_eq!(
+            load_scene(
+                &format!("{}sphere {{\n  radius = abc\n  center = 0 0 0\n}}", CAMERA),
+                Path::new("")
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            "line 8, column 12: radius: expected a number, got \"abc\""
         );
     }
 }
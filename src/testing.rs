@@ -0,0 +1,26 @@
+// Helpers shared by the tests of several modules.
+use crate::light::Light;
+use crate::render::RenderSettings;
+use crate::scene::{Camera, Projection, Radians, Scene, Shape, Vector};
+
+pub fn vector(x: f32, y: f32, z: f32) -> Vector {
+    Vector { x, y, z }
+}
+
+// A camera at the origin looking down -z, with a 90 degree field of view.
+pub fn camera(aspect_ratio: f32) -> Camera {
+    Camera::new(
+        Vector::zero(),
+        -Vector::unitz().0,
+        Vector::unity().0,
+        Projection::Perspective {
+            fovx: Radians(90.0f32.to_radians()),
+        },
+        aspect_ratio,
+    )
+    .unwrap()
+}
+
+pub fn scene_with(shapes: Vec<Box<dyn Shape>>, lights: Vec<Box<dyn Light>>) -> Scene {
+    Scene::new(shapes, lights, camera(1.0), RenderSettings::default())
+}
@@ -0,0 +1,453 @@
+use crate::image::Image;
+use crate::material::Color;
+use crate::scene::Vector;
+use std::f32;
+use std::fmt;
+
+// Where on a surface a texture is looked up: the position of the point in the scene and its
+// texture coordinates, which shapes map to the [0, 1] range where they can (see Intersection).
+// Flat patterns are laid out over the texture coordinates, solid ones (the noise based textures)
+// over the position, as if the shape was carved out of a block of the material.
+pub trait Texture: fmt::Debug + Send + Sync {
+    fn value(&self, position: &Vector, uv: (f32, f32)) -> Color;
+}
+
+// A plain color is a texture that's the same everywhere.
+impl Texture for Color {
+    fn value(&self, _position: &Vector, _uv: (f32, f32)) -> Color {
+        *self
+    }
+}
+
+fn mix(a: &Color, b: &Color, t: f32) -> Color {
+    *a * (1.0 - t) + *b * t
+}
+
+// Squares of two alternating colors, scale of them along each texture coordinate.
+#[derive(Copy, Clone, Debug)]
+pub struct Checkerboard {
+    pub even: Color,
+    pub odd: Color,
+    pub scale: f32,
+}
+
+impl Texture for Checkerboard {
+    fn value(&self, _position: &Vector, (u, v): (f32, f32)) -> Color {
+        let cell = (u * self.scale).floor() + (v * self.scale).floor();
+        if cell.rem_euclid(2.0) < 1.0 {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+}
+
+// Stripes of two alternating colors running along the v texture coordinate, scale pairs of them
+// across u.
+#[derive(Copy, Clone, Debug)]
+pub struct Stripes {
+    pub even: Color,
+    pub odd: Color,
+    pub scale: f32,
+}
+
+impl Texture for Stripes {
+    fn value(&self, _position: &Vector, (u, _v): (f32, f32)) -> Color {
+        if (u * self.scale).rem_euclid(1.0) < 0.5 {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+}
+
+// Blends from start at v = 0 to end at v = 1, on spheres that's from the bottom to the top.
+#[derive(Copy, Clone, Debug)]
+pub struct Gradient {
+    pub start: Color,
+    pub end: Color,
+}
+
+impl Texture for Gradient {
+    fn value(&self, _position: &Vector, (_u, v): (f32, f32)) -> Color {
+        mix(&self.start, &self.end, v.clamp(0.0, 1.0))
+    }
+}
+
+// How an image texture blends between the pixels around a point.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Filter {
+    // The pixel the point is in, blocky up close.
+    Nearest,
+    // Weighs the four pixels with the closest centers by how near they are.
+    Bilinear,
+}
+
+// What an image texture shows outside of the [0, 1] texture coordinate range.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Wrap {
+    // Tiles the image.
+    Repeat,
+    // Extends the edge pixels.
+    Clamp,
+    // Tiles the image, flipping every other copy so the edges line up.
+    Mirror,
+}
+
+impl Wrap {
+    // Maps a pixel index, possibly outside of the image, to one inside.
+    fn address(self, index: i64, size: usize) -> usize {
+        let size = size as i64;
+        let index = match self {
+            Wrap::Repeat => index.rem_euclid(size),
+            Wrap::Clamp => index.clamp(0, size - 1),
+            Wrap::Mirror => {
+                let index = index.rem_euclid(2 * size);
+                if index < size {
+                    index
+                } else {
+                    2 * size - 1 - index
+                }
+            }
+        };
+        index as usize
+    }
+}
+
+// An image stretched over the texture coordinates, u going left to right and v from the bottom
+// row to the top one.
+#[derive(Clone, Debug)]
+pub struct ImageTexture {
+    pub image: Image,
+    pub filter: Filter,
+    pub wrap: Wrap,
+}
+
+impl ImageTexture {
+    fn pixel(&self, x: i64, y: i64) -> Color {
+        self.image.get_color(
+            self.wrap.address(x, self.image.width()),
+            self.wrap.address(y, self.image.height()),
+        )
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, _position: &Vector, (u, v): (f32, f32)) -> Color {
+        let x = u * self.image.width() as f32;
+        let y = (1.0 - v) * self.image.height() as f32;
+        match self.filter {
+            Filter::Nearest => self.pixel(x.floor() as i64, y.floor() as i64),
+            Filter::Bilinear => {
+                // Pixel centers are at half integer coordinates.
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (left, top) = (x.floor(), y.floor());
+                let (tx, ty) = (x - left, y - top);
+                let (left, top) = (left as i64, top as i64);
+                let upper = mix(&self.pixel(left, top), &self.pixel(left + 1, top), tx);
+                let lower = mix(
+                    &self.pixel(left, top + 1),
+                    &self.pixel(left + 1, top + 1),
+                    tx,
+                );
+                mix(&upper, &lower, ty)
+            }
+        }
+    }
+}
+
+// Smooth random blotches blending between two colors, scale sets how many there are per unit of
+// distance.
+#[derive(Copy, Clone, Debug)]
+pub struct Noise {
+    pub low: Color,
+    pub high: Color,
+    pub scale: f32,
+}
+
+impl Texture for Noise {
+    fn value(&self, position: &Vector, _uv: (f32, f32)) -> Color {
+        let noise = perlin(&(*position * self.scale));
+        mix(&self.low, &self.high, (noise + 1.0) / 2.0)
+    }
+}
+
+// Noise with finer and finer detail layered on top, see turbulence.
+#[derive(Copy, Clone, Debug)]
+pub struct Turbulence {
+    pub low: Color,
+    pub high: Color,
+    pub scale: f32,
+    pub octaves: usize,
+}
+
+impl Texture for Turbulence {
+    fn value(&self, position: &Vector, _uv: (f32, f32)) -> Color {
+        let value = turbulence(&(*position * self.scale), self.octaves);
+        mix(&self.low, &self.high, value.min(1.0))
+    }
+}
+
+// Veins running across the x axis, scale of them per unit of distance, bent by turbulence of the
+// given strength.
+#[derive(Copy, Clone, Debug)]
+pub struct Marble {
+    pub low: Color,
+    pub high: Color,
+    pub scale: f32,
+    pub turbulence: f32,
+}
+
+impl Texture for Marble {
+    fn value(&self, position: &Vector, _uv: (f32, f32)) -> Color {
+        let point = *position * self.scale;
+        let phase = 2.0 * f32::consts::PI * point.x + self.turbulence * turbulence(&point, 7);
+        mix(&self.low, &self.high, (phase.sin() + 1.0) / 2.0)
+    }
+}
+
+// Growth rings around the y axis, scale of them per unit of distance, made irregular by
+// turbulence of the given strength.
+#[derive(Copy, Clone, Debug)]
+pub struct Wood {
+    pub low: Color,
+    pub high: Color,
+    pub scale: f32,
+    pub turbulence: f32,
+}
+
+impl Texture for Wood {
+    fn value(&self, position: &Vector, _uv: (f32, f32)) -> Color {
+        let point = *position * self.scale;
+        let distance = (point.x * point.x + point.z * point.z).sqrt();
+        let rings = distance + self.turbulence * turbulence(&point, 4);
+        // Sharpening the rings a little makes them look more like wood grain.
+        mix(&self.low, &self.high, rings.fract().abs().powi(3))
+    }
+}
+
+// The permutation from Ken Perlin's reference implementation of improved noise, hash looks it up
+// with indices wrapping around.
+const PERMUTATION: [u8; 256] = [
+    151, 160, 137, 91, 90, 15, 131, 13, 201, 95, 96, 53, 194, 233, 7, 225, 140, 36, 103, 30, 69,
+    142, 8, 99, 37, 240, 21, 10, 23, 190, 6, 148, 247, 120, 234, 75, 0, 26, 197, 62, 94, 252, 219,
+    203, 117, 35, 11, 32, 57, 177, 33, 88, 237, 149, 56, 87, 174, 20, 125, 136, 171, 168, 68, 175,
+    74, 165, 71, 134, 139, 48, 27, 166, 77, 146, 158, 231, 83, 111, 229, 122, 60, 211, 133, 230,
+    220, 105, 92, 41, 55, 46, 245, 40, 244, 102, 143, 54, 65, 25, 63, 161, 1, 216, 80, 73, 209, 76,
+    132, 187, 208, 89, 18, 169, 200, 196, 135, 130, 116, 188, 159, 86, 164, 100, 109, 198, 173,
+    186, 3, 64, 52, 217, 226, 250, 124, 123, 5, 202, 38, 147, 118, 126, 255, 82, 85, 212, 207, 206,
+    59, 227, 47, 16, 58, 17, 182, 189, 28, 42, 223, 183, 170, 213, 119, 248, 152, 2, 44, 154, 163,
+    70, 221, 153, 101, 155, 167, 43, 172, 9, 129, 22, 39, 253, 19, 98, 108, 110, 79, 113, 224, 232,
+    178, 185, 112, 104, 218, 246, 97, 228, 251, 34, 242, 193, 238, 210, 144, 12, 191, 179, 162,
+    241, 81, 51, 145, 235, 249, 14, 239, 107, 49, 192, 214, 31, 181, 199, 106, 157, 184, 84, 204,
+    176, 115, 121, 50, 45, 127, 4, 150, 254, 138, 236, 205, 93, 222, 114, 67, 29, 24, 72, 243, 141,
+    128, 195, 78, 66, 215, 61, 156, 180,
+];
+
+fn hash(i: usize) -> usize {
+    PERMUTATION[i % 256] as usize
+}
+
+// The dot product of the offset (x, y, z) with one of 12 gradients pointing to the middles of
+// the edges of a cube, picked by the hash.
+fn gradient(hash: usize, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+// Gradient noise varying smoothly between about -1 and 1, repeating every 256 units and 0 at
+// points with integer coordinates. See "Improving Noise" by Ken Perlin.
+pub fn perlin(point: &Vector) -> f32 {
+    let (fx, fy, fz) = (point.x.floor(), point.y.floor(), point.z.floor());
+    let (x, y, z) = (point.x - fx, point.y - fy, point.z - fz);
+    // The corners of the unit cube containing the point.
+    let (xi, yi, zi) = (
+        (fx as i64).rem_euclid(256) as usize,
+        (fy as i64).rem_euclid(256) as usize,
+        (fz as i64).rem_euclid(256) as usize,
+    );
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+    let a = hash(xi) + yi;
+    let (aa, ab) = (hash(a) + zi, hash(a + 1) + zi);
+    let b = hash(xi + 1) + yi;
+    let (ba, bb) = (hash(b) + zi, hash(b + 1) + zi);
+    lerp(
+        w,
+        lerp(
+            v,
+            lerp(
+                u,
+                gradient(hash(aa), x, y, z),
+                gradient(hash(ba), x - 1.0, y, z),
+            ),
+            lerp(
+                u,
+                gradient(hash(ab), x, y - 1.0, z),
+                gradient(hash(bb), x - 1.0, y - 1.0, z),
+            ),
+        ),
+        lerp(
+            v,
+            lerp(
+                u,
+                gradient(hash(aa + 1), x, y, z - 1.0),
+                gradient(hash(ba + 1), x - 1.0, y, z - 1.0),
+            ),
+            lerp(
+                u,
+                gradient(hash(ab + 1), x, y - 1.0, z - 1.0),
+                gradient(hash(bb + 1), x - 1.0, y - 1.0, z - 1.0),
+            ),
+        ),
+    )
+}
+
+// The sum of the absolute values of noise at doubling frequencies and halving amplitudes, it's
+// between 0 and about 1.
+pub fn turbulence(point: &Vector, octaves: usize) -> f32 {
+    let mut sum = 0.0;
+    let mut point = *point;
+    let mut amplitude = 0.5;
+    for _ in 0..octaves {
+        sum += amplitude * perlin(&point).abs();
+        point = point * 2.0;
+        amplitude /= 2.0;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_almost_eq;
+    use crate::image::Image;
+    use crate::material::Color;
+    use crate::scene::Vector;
+    use crate::texture::{
+        perlin, turbulence, Checkerboard, Filter, Gradient, ImageTexture, Stripes, Texture, Wrap,
+    };
+    use crate::traits::AlmostEqual;
+
+    #[test]
+    fn test_patterns() {
+        let checkerboard = Checkerboard {
+            even: Color::new_white(),
+            odd: Color::new_black(),
+            scale: 4.0,
+        };
+        let at = |texture: &dyn Texture, u: f32, v: f32| texture.value(&Vector::zero(), (u, v));
+        assert_almost_eq!(at(&checkerboard, 0.1, 0.1), Color::new_white());
+        assert_almost_eq!(at(&checkerboard, 0.3, 0.1), Color::new_black());
+        assert_almost_eq!(at(&checkerboard, 0.3, 0.3), Color::new_white());
+        // The pattern continues outside of the [0, 1] range.
+        assert_almost_eq!(at(&checkerboard, -0.1, 0.1), Color::new_black());
+
+        let stripes = Stripes {
+            even: Color::new_white(),
+            odd: Color::new_black(),
+            scale: 2.0,
+        };
+        assert_almost_eq!(at(&stripes, 0.1, 0.9), Color::new_white());
+        assert_almost_eq!(at(&stripes, 0.3, 0.1), Color::new_black());
+        assert_almost_eq!(at(&stripes, 0.6, 0.5), Color::new_white());
+        // Every stripe is as wide as the others, including the ones around u = 0.
+        assert_almost_eq!(at(&stripes, -0.1, 0.5), Color::new_black());
+        assert_almost_eq!(at(&stripes, -0.3, 0.5), Color::new_white());
+
+        let gradient = Gradient {
+            start: Color::new_black(),
+            end: Color::new_white(),
+        };
+        assert_almost_eq!(at(&gradient, 0.7, 0.0), Color::new_black());
+        assert_almost_eq!(at(&gradient, 0.2, 0.25), Color::new(0.25, 0.25, 0.25));
+        assert_almost_eq!(at(&gradient, 0.2, 1.5), Color::new_white());
+    }
+
+    #[test]
+    fn test_image_texture() {
+        // Black and white in the top row, red and blue in the bottom one.
+        let mut image = Image::new(2, 2);
+        image.set_color(1, 0, Color::new_white());
+        image.set_color(0, 1, Color::new_red());
+        image.set_color(1, 1, Color::new_blue());
+        let mut texture = ImageTexture {
+            image,
+            filter: Filter::Nearest,
+            wrap: Wrap::Repeat,
+        };
+        let at = |texture: &ImageTexture, u: f32, v: f32| texture.value(&Vector::zero(), (u, v));
+        assert_almost_eq!(at(&texture, 0.2, 0.2), Color::new_red());
+        assert_almost_eq!(at(&texture, 0.7, 0.9), Color::new_white());
+        assert_almost_eq!(at(&texture, 1.2, -0.8), Color::new_red());
+        assert_almost_eq!(at(&texture, -0.2, 0.2), Color::new_blue());
+        texture.wrap = Wrap::Clamp;
+        assert_almost_eq!(at(&texture, -0.2, 0.2), Color::new_red());
+        assert_almost_eq!(at(&texture, 3.0, 5.0), Color::new_white());
+        texture.wrap = Wrap::Mirror;
+        assert_almost_eq!(at(&texture, -0.2, 0.2), Color::new_red());
+        assert_almost_eq!(at(&texture, 1.7, 0.2), Color::new_red());
+        assert_almost_eq!(at(&texture, 2.2, 0.2), Color::new_red());
+
+        texture.filter = Filter::Bilinear;
+        // At pixel centers only the pixel itself counts.
+        assert_almost_eq!(at(&texture, 0.75, 0.25), Color::new_blue());
+        // Between them it's their average.
+        assert_almost_eq!(at(&texture, 0.5, 0.25), Color::new(0.5, 0.0, 0.5));
+        assert_almost_eq!(at(&texture, 0.5, 0.5), Color::new(0.5, 0.25, 0.5));
+        // At the edges only repeating blends with the other side of the image.
+        assert_almost_eq!(at(&texture, 0.0, 0.25), Color::new_red());
+        texture.wrap = Wrap::Clamp;
+        assert_almost_eq!(at(&texture, 0.0, 0.25), Color::new_red());
+        texture.wrap = Wrap::Repeat;
+        assert_almost_eq!(at(&texture, 0.0, 0.25), Color::new(0.5, 0.0, 0.5));
+    }
+
+    #[test]
+    fn test_noise() {
+        // The noise is 0 on the integer lattice and continuous everywhere else, including across
+        // the cells of the lattice.
+        assert_eq!(perlin(&Vector::zero()), 0.0);
+        let lattice = Vector {
+            x: 3.0,
+            y: -7.0,
+            z: 300.0,
+        };
+        assert_eq!(perlin(&lattice), 0.0);
+        let mut min = f32::INFINITY;
+        let mut max = f32::NEG_INFINITY;
+        for i in 0..1000 {
+            let point = Vector {
+                x: i as f32 * 0.137,
+                y: i as f32 * -0.071,
+                z: (i % 17) as f32 * 0.31,
+            };
+            let value = perlin(&point);
+            min = min.min(value);
+            max = max.max(value);
+            let nearby = Vector {
+                x: point.x + 1e-3,
+                ..point
+            };
+            assert!((perlin(&nearby) - value).abs() < 1e-2);
+            let sum = turbulence(&point, 5);
+            assert!((0.0..1.0).contains(&sum), "{}", sum);
+        }
+        assert!(min > -1.1 && min < -0.3, "{}", min);
+        assert!(max < 1.1 && max > 0.3, "{}", max);
+    }
+}
@@ -0,0 +1,509 @@
+use crate::bvh::Aabb;
+use crate::material::Material;
+use crate::scene::{rounding_error, Intersection, Motion, Radians, Ray, Shape, UnitVector, Vector};
+use std::ops::Mul;
+use std::sync::Arc;
+
+type Matrix = [[f32; 4]; 4];
+
+const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut product = [[0.0; 4]; 4];
+    for (i, row) in product.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    product
+}
+
+// Inverts an affine matrix, None if it's singular. The upper left 3x3 part is inverted by dividing
+// its adjugate by its determinant, the translation is then undone by the inverted part.
+fn invert_affine(matrix: &Matrix) -> Option<Matrix> {
+    let mut inverse = IDENTITY;
+    // With the rows and columns taken cyclically the cofactors come out with the right signs.
+    for (j, row) in inverse.iter_mut().take(3).enumerate() {
+        for (i, value) in row.iter_mut().take(3).enumerate() {
+            let (r0, r1, c0, c1) = ((i + 1) % 3, (i + 2) % 3, (j + 1) % 3, (j + 2) % 3);
+            *value = matrix[r0][c0] * matrix[r1][c1] - matrix[r0][c1] * matrix[r1][c0];
+        }
+    }
+    let determinant: f32 = (0..3).map(|j| matrix[0][j] * inverse[j][0]).sum();
+    if determinant == 0.0 || !determinant.is_finite() {
+        return None;
+    }
+    for row in inverse.iter_mut().take(3) {
+        for value in row.iter_mut().take(3) {
+            *value /= determinant;
+        }
+        row[3] = -(0..3).map(|k| row[k] * matrix[k][3]).sum::<f32>();
+    }
+    Some(inverse)
+}
+
+// An affine transform stored as a 4x4 matrix (with a bottom row of 0 0 0 1) along with its
+// inverse, so that transforming either way is cheap. Points are column vectors multiplied from
+// the left, which makes a * b the transform applying b first and a second.
+#[derive(Copy, Clone, Debug)]
+pub struct Transform {
+    matrix: Matrix,
+    inverse: Matrix,
+}
+
+impl Transform {
+    // Returns None for matrices that aren't affine or can't be inverted.
+    pub fn new(matrix: [[f32; 4]; 4]) -> Option<Transform> {
+        if matrix[3] != IDENTITY[3] {
+            return None;
+        }
+        let inverse = invert_affine(&matrix)?;
+        Some(Transform { matrix, inverse })
+    }
+
+    pub fn identity() -> Transform {
+        Transform {
+            matrix: IDENTITY,
+            inverse: IDENTITY,
+        }
+    }
+
+    pub fn translation(offset: Vector) -> Transform {
+        let mut matrix = IDENTITY;
+        let mut inverse = IDENTITY;
+        for (i, value) in offset.components().iter().enumerate() {
+            matrix[i][3] = *value;
+            inverse[i][3] = -*value;
+        }
+        Transform { matrix, inverse }
+    }
+
+    // Scales by a different factor along every axis. Panics if any of them is 0.
+    pub fn scaling(factors: Vector) -> Transform {
+        let mut matrix = IDENTITY;
+        let mut inverse = IDENTITY;
+        for (i, factor) in factors.components().iter().enumerate() {
+            assert!(*factor != 0.0, "Can't scale by 0");
+            matrix[i][i] = *factor;
+            inverse[i][i] = 1.0 / *factor;
+        }
+        Transform { matrix, inverse }
+    }
+
+    // Rotates counter-clockwise around the axis when looking against it, the inverse of a
+    // rotation is its transpose.
+    pub fn rotation(axis: UnitVector, angle: Radians) -> Transform {
+        let Vector { x, y, z } = axis.0;
+        let (sin, cos) = angle.0.sin_cos();
+        let t = 1.0 - cos;
+        let matrix = [
+            [
+                t * x * x + cos,
+                t * x * y - sin * z,
+                t * x * z + sin * y,
+                0.0,
+            ],
+            [
+                t * x * y + sin * z,
+                t * y * y + cos,
+                t * y * z - sin * x,
+                0.0,
+            ],
+            [
+                t * x * z - sin * y,
+                t * y * z + sin * x,
+                t * z * z + cos,
+                0.0,
+            ],
+            IDENTITY[3],
+        ];
+        let mut inverse = IDENTITY;
+        for (i, row) in inverse.iter_mut().take(3).enumerate() {
+            for (j, value) in row.iter_mut().take(3).enumerate() {
+                *value = matrix[j][i];
+            }
+        }
+        Transform { matrix, inverse }
+    }
+
+    pub fn matrix(&self) -> &[[f32; 4]; 4] {
+        &self.matrix
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
+    pub fn point(&self, point: &Vector) -> Vector {
+        let m = &self.matrix;
+        Vector {
+            x: m[0][0] * point.x + m[0][1] * point.y + m[0][2] * point.z + m[0][3],
+            y: m[1][0] * point.x + m[1][1] * point.y + m[1][2] * point.z + m[1][3],
+            z: m[2][0] * point.x + m[2][1] * point.y + m[2][2] * point.z + m[2][3],
+        }
+    }
+
+    // Directions and offsets between points aren't affected by the translation.
+    pub fn vector(&self, vector: &Vector) -> Vector {
+        let m = &self.matrix;
+        Vector {
+            x: m[0][0] * vector.x + m[0][1] * vector.y + m[0][2] * vector.z,
+            y: m[1][0] * vector.x + m[1][1] * vector.y + m[1][2] * vector.z,
+            z: m[2][0] * vector.x + m[2][1] * vector.y + m[2][2] * vector.z,
+        }
+    }
+
+    // Normals have to stay perpendicular to the surface, which under non-uniform scaling takes
+    // the transpose of the inverse rather than the matrix itself.
+    pub fn normal(&self, normal: &UnitVector) -> UnitVector {
+        let m = &self.inverse;
+        let n = &normal.0;
+        Vector {
+            x: m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            y: m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            z: m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        }
+        .normalized()
+    }
+
+    // Transforms a ray, keeping its direction normalized. Distances along the new ray are the
+    // original ones times the returned factor, its interval is stretched accordingly.
+    pub fn ray(&self, ray: &Ray) -> (Ray, f32) {
+        let dir = self.vector(&ray.dir.0);
+        let stretch = dir.len();
+        let transformed = Ray {
+            pos: self.point(&ray.pos),
+            dir: UnitVector(dir / stretch),
+            t_min: ray.t_min * stretch,
+            t_max: ray.t_max * stretch,
+            time: ray.time,
+        };
+        (transformed, stretch)
+    }
+
+    // The box containing the transformed bounds, see "Transforming Axis-Aligned Bounding Boxes"
+    // by Jim Arvo.
+    pub fn bounds(&self, bounds: &Aabb) -> Aabb {
+        let (min, max) = (bounds.min.components(), bounds.max.components());
+        let mut transformed = [[0.0; 3]; 2];
+        for (i, row) in self.matrix.iter().take(3).enumerate() {
+            let (mut low, mut high) = (row[3], row[3]);
+            for j in 0..3 {
+                let (a, b) = (row[j] * min[j], row[j] * max[j]);
+                low += a.min(b);
+                high += a.max(b);
+            }
+            transformed[0][i] = low;
+            transformed[1][i] = high;
+        }
+        Aabb {
+            min: Vector::from_components(transformed[0]),
+            max: Vector::from_components(transformed[1]),
+        }
+    }
+
+    // A bound on the rounding error of every coordinate of self.point(point), point itself being
+    // off by up to error in every coordinate.
+    pub fn point_error(&self, point: &Vector, error: f32) -> f32 {
+        let point = point.components();
+        self.matrix
+            .iter()
+            .take(3)
+            .map(|row| {
+                let scale: f32 = row[..3].iter().map(|value| value.abs()).sum();
+                let magnitude: f32 = (0..3).map(|j| (row[j] * point[j]).abs()).sum();
+                scale * error + rounding_error(magnitude + row[3].abs() + scale * error)
+            })
+            .fold(0.0, f32::max)
+    }
+}
+
+impl Mul for Transform {
+    type Output = Transform;
+
+    fn mul(self, other: Transform) -> Transform {
+        Transform {
+            matrix: multiply(&self.matrix, &other.matrix),
+            inverse: multiply(&other.inverse, &self.inverse),
+        }
+    }
+}
+
+// A shape placed in the scene by a transform. The shape itself is shared, so a big mesh can be
+// placed any number of times while being stored once. Instances report themselves as the object
+// hit. Emissive ones aren't sampled as lights, they only light the scene when hit by chance.
+// The motion moves the transformed shape over time, which makes any shape motion blurred.
+#[derive(Clone, Debug)]
+pub struct Instance {
+    pub shape: Arc<dyn Shape>,
+    pub transform: Transform,
+    pub motion: Motion,
+}
+
+impl Instance {
+    // An instance that stays in place.
+    pub fn new(shape: Arc<dyn Shape>, transform: Transform) -> Instance {
+        Instance {
+            shape,
+            transform,
+            motion: Motion::default(),
+        }
+    }
+
+    pub fn transform_at(&self, time: f32) -> Transform {
+        if self.motion.is_static() {
+            self.transform
+        } else {
+            Transform::translation(self.motion.offset(time)) * self.transform
+        }
+    }
+}
+
+impl Shape for Instance {
+    fn intersect_ray(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let transform = self.transform_at(ray.time);
+        let (local_ray, stretch) = transform.inverse().ray(ray);
+        let hit = self.shape.intersect_ray(&local_ray)?;
+        Some(Intersection {
+            position: transform.point(&hit.position),
+            t: hit.t / stretch,
+            normal: transform.normal(&hit.normal),
+            geometric_normal: transform.normal(&hit.geometric_normal),
+            position_error: transform.point_error(&hit.position, hit.position_error),
+            object: self,
+            ..hit
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let bounds = self.transform.bounds(&self.shape.bounding_box()?);
+        Some(self.motion.bounds(&bounds))
+    }
+
+    fn material(&self) -> &Material {
+        self.shape.material()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_almost_eq;
+    use crate::bvh::Aabb;
+    use crate::material::Material;
+    use crate::scene::{Motion, Plane, Radians, Ray, Shape, Sphere, Vector};
+    use crate::testing::vector;
+    use crate::traits::AlmostEqual;
+    use crate::transform::{Instance, Transform};
+    use std::f32;
+    use std::ptr;
+    use std::sync::Arc;
+
+    fn assert_close(a: Vector, b: Vector) {
+        assert!(a.almost_equal_with_epsilon(&b, 1e-5), "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_transform_points() {
+        let point = vector(1.0, 2.0, 3.0);
+        let translation = Transform::translation(vector(1.0, -1.0, 0.5));
+        assert_almost_eq!(translation.point(&point), vector(2.0, 1.0, 3.5));
+        assert_almost_eq!(translation.vector(&point), point);
+        let scaling = Transform::scaling(vector(2.0, -1.0, 0.5));
+        assert_almost_eq!(scaling.point(&point), vector(2.0, -2.0, 1.5));
+        let rotation = Transform::rotation(Vector::unitz(), Radians(f32::consts::FRAC_PI_2));
+        assert_close(rotation.point(&point), vector(-2.0, 1.0, 3.0));
+        let diagonal = vector(1.0, 1.0, 1.0).normalized();
+        let third = Transform::rotation(diagonal, Radians(f32::consts::PI * 2.0 / 3.0));
+        assert_close(third.point(&point), vector(3.0, 1.0, 2.0));
+
+        // The transform on the right goes first.
+        let combined = translation * rotation * scaling;
+        assert_close(combined.point(&point), vector(3.0, 1.0, 2.0));
+        assert_close(combined.inverse().point(&vector(3.0, 1.0, 2.0)), point);
+        assert_close((combined * combined.inverse()).point(&point), point);
+
+        let matrix = [
+            [0.0, 2.0, 0.0, 1.0],
+            [1.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 3.0, -2.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+        let general = Transform::new(matrix).unwrap();
+        assert_eq!(general.matrix(), &matrix);
+        assert_almost_eq!(general.point(&point), vector(5.0, 4.0, 7.0));
+        assert_close(general.inverse().point(&vector(5.0, 4.0, 7.0)), point);
+        let mut singular = matrix;
+        singular[2] = [1.0, 2.0, 1.0, 0.0];
+        assert!(Transform::new(singular).is_none());
+        let mut projective = matrix;
+        projective[3] = [0.0, 0.0, 1.0, 1.0];
+        assert!(Transform::new(projective).is_none());
+    }
+
+    #[test]
+    fn test_transform_normals_and_bounds() {
+        // A slanted plane squashed along x gets steeper, its normal has to lean the other way
+        // than the plane itself to stay perpendicular to it.
+        let scaling = Transform::scaling(vector(0.5, 1.0, 1.0));
+        let normal = vector(1.0, 1.0, 0.0).normalized();
+        let along_plane = vector(1.0, -1.0, 0.0);
+        let transformed = scaling.normal(&normal);
+        assert!(transformed.0.is_normalized());
+        assert_almost_eq!(transformed.0.dot(&scaling.vector(&along_plane)), 0.0);
+        assert_close(transformed.0, vector(2.0, 1.0, 0.0).normalized().0);
+
+        let bounds = Aabb {
+            min: vector(-1.0, -1.0, -1.0),
+            max: vector(1.0, 1.0, 1.0),
+        };
+        let rotation = Transform::rotation(Vector::unity(), Radians(f32::consts::FRAC_PI_4));
+        let transformed =
+            (Transform::translation(vector(0.0, 5.0, 0.0)) * rotation).bounds(&bounds);
+        let half_diagonal = 2.0f32.sqrt();
+        assert_close(transformed.min, vector(-half_diagonal, 4.0, -half_diagonal));
+        assert_close(transformed.max, vector(half_diagonal, 6.0, half_diagonal));
+    }
+
+    #[test]
+    fn test_instance_ray_intersection() {
+        let sphere: Arc<dyn Shape> = Arc::new(Sphere {
+            center: Vector::zero(),
+            radius: 1.0,
+            material: Material::dummy(),
+            motion: Motion::default(),
+        });
+        // An ellipsoid 4 units wide, stretched along x and moved 10 units away.
+        let ellipsoid = Instance::new(
+            sphere.clone(),
+            Transform::translation(vector(10.0, 0.0, 0.0))
+                * Transform::scaling(vector(2.0, 1.0, 1.0)),
+        );
+        let ray = Ray::new(Vector::zero(), Vector::unitx());
+        let hit = ellipsoid.intersect_ray(&ray).unwrap();
+        assert_almost_eq!(hit.t, 8.0);
+        assert_almost_eq!(hit.position, vector(8.0, 0.0, 0.0));
+        assert_almost_eq!(hit.normal.0, -Vector::unitx().0);
+        assert!(hit.front_face);
+        assert!(ptr::addr_eq(hit.object, &ellipsoid));
+        assert!(ptr::eq(hit.object.material(), sphere.material()));
+        // Distances along the ray stay the same even though it's shorter in the sphere's space.
+        let short = Ray { t_max: 7.5, ..ray };
+        assert!(ellipsoid.intersect_ray(&short).is_none());
+        let inside = Ray::new(vector(10.0, 0.0, 0.0), vector(1.0, 1.0, 0.0).normalized());
+        let hit = ellipsoid.intersect_ray(&inside).unwrap();
+        assert!(!hit.front_face);
+        assert_close(hit.normal.0, vector(-1.0, -4.0, 0.0).normalized().0);
+        assert!((hit.t - 1.6f32.sqrt()).abs() < 1e-6);
+
+        let bounds = ellipsoid.bounding_box().unwrap();
+        assert_almost_eq!(bounds.min, vector(8.0, -1.0, -1.0));
+        assert_almost_eq!(bounds.max, vector(12.0, 1.0, 1.0));
+        let plane = Instance::new(
+            Arc::new(Plane {
+                point: Vector::zero(),
+                normal: Vector::unity(),
+                material: Material::dummy(),
+            }),
+            Transform::identity(),
+        );
+        assert!(plane.bounding_box().is_none());
+
+        // Placing the same shape again doesn't copy it.
+        let copies: Vec<_> = (0..10)
+            .map(|i| {
+                Instance::new(
+                    sphere.clone(),
+                    Transform::translation(vector(0.0, i as f32 * 3.0, 0.0)),
+                )
+            })
+            .collect();
+        assert_eq!(Arc::strong_count(&sphere), copies.len() + 2);
+        let down = Ray::new(vector(0.0, 100.0, 0.0), -Vector::unity());
+        assert_almost_eq!(copies[9].intersect_ray(&down).unwrap().t, 72.0);
+    }
+
+    #[test]
+    fn test_instance_motion() {
+        let sphere: Arc<dyn Shape> = Arc::new(Sphere {
+            center: Vector::zero(),
+            radius: 1.0,
+            material: Material::dummy(),
+            motion: Motion::default(),
+        });
+        // Rotated and stretched along y, then moving 4 units along x between times 0 and 1.
+        let moving = Instance {
+            motion: Motion::linear(vector(4.0, 0.0, 0.0)),
+            ..Instance::new(
+                sphere,
+                Transform::translation(vector(0.0, 0.0, -5.0))
+                    * Transform::rotation(Vector::unitz(), Radians(f32::consts::FRAC_PI_2))
+                    * Transform::scaling(vector(2.0, 1.0, 1.0)),
+            )
+        };
+        let ray = Ray::new(Vector::zero(), -Vector::unitz());
+        let at = |time| Ray { time, ..ray };
+        let hit = moving.intersect_ray(&at(0.0)).unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-5);
+        assert_eq!(hit.time, 0.0);
+        assert!(moving.intersect_ray(&at(1.0)).is_none());
+        let side = Ray::new(vector(2.0, 0.0, 0.0), -Vector::unitz());
+        let hit = moving.intersect_ray(&Ray { time: 0.5, ..side }).unwrap();
+        assert_close(hit.position, vector(2.0, 0.0, -4.0));
+        assert_close(hit.normal.0, Vector::unitz().0);
+        assert!(moving.intersect_ray(&side).is_none());
+
+        // The bounds cover the whole way.
+        let bounds = moving.bounding_box().unwrap();
+        assert_close(bounds.min, vector(-1.0, -2.0, -6.0));
+        assert_close(bounds.max, vector(5.0, 2.0, -4.0));
+    }
+
+    #[test]
+    fn test_instance_secondary_rays_leave_the_surface() {
+        let sphere: Arc<dyn Shape> = Arc::new(Sphere {
+            center: Vector::zero(),
+            radius: 1.0,
+            material: Material::dummy(),
+            motion: Motion::default(),
+        });
+        for &offset in &[0.0, 1000.0, -20000.0] {
+            let ellipsoid = Instance::new(
+                sphere.clone(),
+                Transform::translation(vector(offset, offset, offset))
+                    * Transform::rotation(vector(1.0, 2.0, 3.0).normalized(), Radians(1.0))
+                    * Transform::scaling(vector(50.0, 0.5, 5.0)),
+            );
+            let eye = vector(offset, offset + 60.0, offset + 60.0);
+            let steps = 64;
+            for i in 0..steps {
+                for j in 0..steps {
+                    let target = vector(
+                        offset + (i as f32 / steps as f32 - 0.5) * 100.0,
+                        offset,
+                        offset + (j as f32 / steps as f32 - 0.5) * 10.0,
+                    );
+                    let ray = Ray::new(eye, (target - eye).normalized());
+                    let hit = match ellipsoid.intersect_ray(&ray) {
+                        Some(hit) => hit,
+                        None => continue,
+                    };
+                    // Ellipsoids are convex so light bouncing off of one can't hit it again.
+                    let bounce = ray.reflected(&hit);
+                    assert!(
+                        ellipsoid.intersect_ray(&bounce).is_none(),
+                        "{:?} hits its own surface again",
+                        ray
+                    );
+                }
+            }
+        }
+    }
+}